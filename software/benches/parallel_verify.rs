@@ -0,0 +1,62 @@
+//! Measures verification of device memory with and without --parallel-verify, i.e. by
+//! [verify_region] and by [verify_region_parallel].
+//!
+//! Run with `cargo bench --bench parallel_verify`.
+//! The simulated device answers instantly, so transfers of a real link are simulated by delaying each
+//! read from the port as well.
+
+use std::num::{NonZeroU8, NonZeroUsize};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use romectl::data_ops::{verify_region, verify_region_parallel, write_data, DataChunk, DataWriteRequest, DEFAULT_READ_BUFFER_SIZE, DEFAULT_WRITE_BUFFER_SIZE};
+use romectl::device::{Device, DeviceSettings};
+use romectl::mock::{MockPort, MOCK_PORT_NAME};
+
+const ROUNDS: u32 = 5;
+
+type Verify = fn(&mut Device, &DataChunk<Vec<u8>>, NonZeroU8) -> Result<()>;
+
+fn mock_device(read_delay: Duration) -> Result<Device> {
+    let mut port = MockPort::new();
+    port.set_read_delay(read_delay);
+    let mut device = Device::with_port(MOCK_PORT_NAME, Box::new(port), &DeviceSettings::default());
+    device.check()?;
+
+    Ok(device)
+}
+
+/// Writes `image` and returns average duration of its verification by `verify`.
+fn measure(read_delay: Duration, image: &DataChunk<Vec<u8>>, verify: Verify) -> Result<Duration> {
+    let mut device = mock_device(read_delay)?;
+    let buffer_size = NonZeroU8::new(DEFAULT_READ_BUFFER_SIZE).unwrap();
+
+    write_data(&mut device, DataWriteRequest {
+        data: image,
+        buffer_size: NonZeroU8::new(DEFAULT_WRITE_BUFFER_SIZE).unwrap(),
+        page_size: None,
+    })?;
+
+    let start_time = Instant::now();
+    for _ in 0..ROUNDS {
+        verify(&mut device, image, buffer_size)?;
+    }
+
+    Ok(start_time.elapsed() / ROUNDS)
+}
+
+fn main() -> Result<()> {
+    for (size, read_delay) in [(0x10000, Duration::ZERO), (0x1000, Duration::from_micros(10))] {
+        let image = DataChunk {
+            offset: 0,
+            data: (0..size).map(|i| (i ^ (i >> 8)) as u8).collect::<Vec<u8>>(),
+        };
+        let sequential = measure(read_delay, &image, verify_region)?;
+        let parallel = measure(read_delay, &image, verify_region_parallel)?;
+
+        println!("Verifying 0x{:X} bytes, {:?} delay per port read:", NonZeroUsize::new(size).unwrap(), read_delay);
+        println!("  sequential: {:?}", sequential);
+        println!("  parallel:   {:?} ({:.2}x)", parallel, sequential.as_secs_f64() / parallel.as_secs_f64());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! Compares allocations made while reading the whole memory of a simulated device by [read_data],
+//! which allocates a buffer for each chunk, and by [read_data_into], which reuses a single one.
+//!
+//! Run with `cargo bench --bench read_allocations`.
+//! Allocations made by the simulated device itself are the same in both cases.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::num::{NonZeroU8, NonZeroUsize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use romectl::data_ops::{read_data, read_data_into, DataReadRequest, DEFAULT_READ_BUFFER_SIZE};
+use romectl::device::{Device, DeviceSettings};
+use romectl::mock::open_mock_device;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const ROUNDS: usize = 5;
+
+fn request() -> DataReadRequest {
+    DataReadRequest {
+        offset: 0,
+        size: NonZeroUsize::new(0x10000).unwrap(),
+        buffer_size: NonZeroU8::new(DEFAULT_READ_BUFFER_SIZE).unwrap(),
+        allow_short_read: false,
+        reverse: false,
+    }
+}
+
+/// Runs `read` several times, returns allocations and duration of a single run.
+fn measure(device: &mut Device, read: fn(&mut Device) -> Result<()>) -> Result<(usize, Duration)> {
+    let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start_time = Instant::now();
+
+    for _ in 0..ROUNDS {
+        read(device)?;
+    }
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+
+    Ok((allocations / ROUNDS, start_time.elapsed() / ROUNDS as u32))
+}
+
+fn read_allocating(device: &mut Device) -> Result<()> {
+    for chunk in read_data(device, request())? {
+        black_box(chunk?.data);
+    }
+
+    Ok(())
+}
+
+fn read_reusing(device: &mut Device) -> Result<()> {
+    read_data_into(device, request(), |_, _| {}, |chunk| {
+        black_box(chunk.data);
+        Ok(())
+    })
+}
+
+fn main() -> Result<()> {
+    let mut device = open_mock_device(&DeviceSettings::default())?;
+    device.check()?;
+    let chunks = 0x10000usize.div_ceil(DEFAULT_READ_BUFFER_SIZE as usize);
+
+    let (allocating, allocating_time) = measure(&mut device, read_allocating)?;
+    let (reusing, reusing_time) = measure(&mut device, read_reusing)?;
+
+    println!("Reading 64KiB in {} chunks:", chunks);
+    println!("  read_data:      {:>7} allocations, {:?}", allocating, allocating_time);
+    println!("  read_data_into: {:>7} allocations, {:?}", reusing, reusing_time);
+    println!("  {:.2} fewer allocations per chunk", (allocating as f64 - reusing as f64) / chunks as f64);
+
+    Ok(())
+}
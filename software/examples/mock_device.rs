@@ -0,0 +1,56 @@
+//! Writes a test pattern to a simulated device, reads it back and verifies it.
+//!
+//! Run with `cargo run --example mock_device`.
+
+use std::num::{NonZeroU8, NonZeroUsize};
+use anyhow::Result;
+use romectl::data_ops::{read_data, verify_region, write_data, DataChunk, DataReadRequest, DataWriteRequest, DEFAULT_READ_BUFFER_SIZE, DEFAULT_WRITE_BUFFER_SIZE};
+use romectl::device::DeviceSettings;
+use romectl::mock::open_mock_device;
+
+fn main() -> Result<()> {
+    let mut device = open_mock_device(&DeviceSettings::default())?;
+    device.check()?;
+
+    let version = device.version()?;
+    println!("Connected to {}, firmware {}", device.name(), version);
+
+    let pattern = DataChunk {
+        offset: 0x100,
+        data: (0..=255u8).collect::<Vec<_>>(),
+    };
+    let read_buffer_size = NonZeroU8::new(DEFAULT_READ_BUFFER_SIZE).expect("default buffer size is not zero");
+    let write_buffer_size = NonZeroU8::new(DEFAULT_WRITE_BUFFER_SIZE).expect("default buffer size is not zero");
+
+    write_data(&mut device, DataWriteRequest {
+        data: &pattern,
+        buffer_size: write_buffer_size,
+        page_size: None,
+    })?;
+
+    let mut read_back = vec![];
+    for chunk in read_data(&mut device, DataReadRequest {
+        offset: pattern.offset,
+        size: NonZeroUsize::new(pattern.data.len()).expect("pattern is not empty"),
+        buffer_size: read_buffer_size,
+        allow_short_read: false,
+        reverse: false,
+    })? {
+        read_back.extend_from_slice(chunk?.data.as_slice());
+    }
+    assert_eq!(read_back, pattern.data);
+
+    verify_region(&mut device, &pattern, read_buffer_size)?;
+
+    println!("{} written, read back and verified", pattern);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn example_runs() {
+        super::main().unwrap();
+    }
+}
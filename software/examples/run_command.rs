@@ -0,0 +1,38 @@
+//! Executes `romectl device info` against a simulated device without parsing command line.
+//!
+//! Run with `cargo run --example run_command`.
+
+use std::time::Duration;
+use anyhow::Result;
+use romectl::cli::{run, Command, Config, DeviceCommand, OutputFormat};
+use romectl::device::DeviceSettings;
+use romectl::device_detector::DeviceDetectorSettings;
+use romectl::protocol::HexCase;
+
+fn main() -> Result<()> {
+    run(Config {
+        output_format: OutputFormat::Json,
+        trace: false,
+        summary: false,
+        no_summary: false,
+        output_hex_case: HexCase::Upper,
+        command: Command::Device(DeviceCommand::Info {
+            detector_settings: DeviceDetectorSettings {
+                port: None,
+                interactive: false,
+                mock_device: true,
+                device_settings: DeviceSettings::default(),
+            },
+            all: true,
+            test_timeout: Duration::from_secs(10),
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn example_runs() {
+        super::main().unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+use std::num::{NonZeroUsize, ParseIntError};
+use std::time::Duration;
+use crate::protocol::ADDRESS_SPACE_SIZE;
+
+/// Parses a human-readable duration passed as command line argument, e.g. `500ms`, `2s` or `1s500ms`.
+///
+/// All duration-typed arguments should use this parser (`value_parser = parse_duration`) instead of
+/// accepting bare numbers, so the units are always explicit and consistent across commands.
+pub fn parse_duration(value: &str) -> Result<Duration, humantime::DurationError> {
+    humantime::parse_duration(value)
+}
+
+/// Parses a positive finite multiplier, e.g. `2` or `1.5`.
+pub fn parse_factor(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(factor) if factor.is_finite() && factor > 0.0 => Ok(factor),
+        Ok(_) => Err("must be a positive number".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parses a byte value given either as decimal number or as hexadecimal number with `0x` prefix.
+pub fn parse_byte(value: &str) -> Result<u8, ParseIntError> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex_digits) => u8::from_str_radix(hex_digits, 16),
+        None => value.parse(),
+    }
+}
+
+/// Parses a signed address difference given either as decimal number or as hexadecimal number with
+/// `0x` prefix, e.g. `-0x8000`.
+pub fn parse_address_bias(value: &str) -> Result<i64, ParseIntError> {
+    let (negative, magnitude) = match value.strip_prefix('-') {
+        Some(magnitude) => (true, magnitude),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let magnitude = match magnitude.strip_prefix("0x").or_else(|| magnitude.strip_prefix("0X")) {
+        Some(hex_digits) => i64::from_str_radix(hex_digits, 16)?,
+        None => magnitude.parse()?,
+    };
+
+    Ok(if negative { -magnitude } else { magnitude })
+}
+
+/// Region of device memory given by a single command line argument.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AddressRange {
+    pub offset: u16,
+    pub size: NonZeroUsize,
+}
+
+/// Parses a number given either as decimal number or as hexadecimal number with `0x` prefix.
+pub fn parse_number(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+    let result = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex_digits) => usize::from_str_radix(hex_digits, 16),
+        None => value.parse(),
+    };
+
+    result.map_err(|e| format!("invalid number '{}': {}", value, e))
+}
+
+/// Parses a region given as `<first>-<last>` with inclusive last address (e.g. `0x100-0x1FF`) or as
+/// `<first>:<size>` (e.g. `0x100:256`).
+pub fn parse_address_range(value: &str) -> Result<AddressRange, String> {
+    let (first, size) = if let Some((first, last)) = value.split_once('-') {
+        let (first, last) = (parse_number(first)?, parse_number(last)?);
+
+        if last < first {
+            return Err(format!("last address 0x{:04X} is below first address 0x{:04X}", last, first));
+        }
+
+        // Overflows only for ranges far outside of the address space, which are rejected below
+        (first, (last - first).saturating_add(1))
+    } else if let Some((first, size)) = value.split_once(':') {
+        (parse_number(first)?, parse_number(size)?)
+    } else {
+        return Err("expected <first>-<last> or <first>:<size>".to_string());
+    };
+
+    let offset = u16::try_from(first)
+        .map_err(|_| format!("first address 0x{:X} is outside of 16-bit address space", first))?;
+    let size = NonZeroUsize::new(size).ok_or("size must not be zero")?;
+
+    if (offset as usize).checked_add(size.get()).is_none_or(|end| end > ADDRESS_SPACE_SIZE) {
+        return Err(format!(
+            "region 0x{:04X}+0x{:X} ends past the end of 16-bit address space at 0x{:X}",
+            offset,
+            size,
+            ADDRESS_SPACE_SIZE - 1,
+        ));
+    }
+
+    Ok(AddressRange { offset, size })
+}
+
+/// Buffer size given either as a number of bytes or as `auto`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BufferSize {
+    /// Largest size safe to use with the device.
+    Auto,
+    Bytes(u8),
+}
+
+impl BufferSize {
+    /// Returns size in bytes, using `auto_size` for [BufferSize::Auto].
+    pub fn resolve(self, auto_size: u8) -> u8 {
+        match self {
+            BufferSize::Auto => auto_size,
+            BufferSize::Bytes(size) => size,
+        }
+    }
+}
+
+/// Parses a buffer size given as decimal number of bytes or `auto`.
+pub fn parse_buffer_size(value: &str) -> Result<BufferSize, ParseIntError> {
+    match value {
+        "auto" => Ok(BufferSize::Auto),
+        _ => value.parse().map(BufferSize::Bytes),
+    }
+}
+
+/// Returns whether a boolean environment variable is set.
+///
+/// Empty values and `0`, `false`, `no` or `off` (in any case) mean that the variable is not set.
+pub fn env_flag(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|value| {
+        !matches!(value.trim().to_lowercase().as_str(), "" | "0" | "false" | "no" | "off")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(offset: u16, size: usize) -> AddressRange {
+        AddressRange { offset, size: NonZeroUsize::new(size).unwrap() }
+    }
+
+    #[test]
+    fn inclusive_ranges() {
+        assert_eq!(parse_address_range("0x100-0x1FF"), Ok(range(0x100, 0x100)));
+        assert_eq!(parse_address_range("256-256"), Ok(range(0x100, 1)));
+        assert_eq!(parse_address_range("0-0xFFFF"), Ok(range(0, 0x10000)));
+        assert_eq!(parse_address_range(" 0X10 - 0x1f "), Ok(range(0x10, 0x10)));
+    }
+
+    #[test]
+    fn sized_ranges() {
+        assert_eq!(parse_address_range("0x100:256"), Ok(range(0x100, 0x100)));
+        assert_eq!(parse_address_range("0xFFFF:1"), Ok(range(0xFFFF, 1)));
+        assert_eq!(parse_address_range("0:0x10000"), Ok(range(0, 0x10000)));
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        for value in ["", "0x100", "0x1FF-0x100", "0x100:0", "x-0x10", "0x10:y", "0x10-", ":16"] {
+            assert!(parse_address_range(value).is_err(), "{} was accepted", value);
+        }
+    }
+
+    #[test]
+    fn ranges_outside_of_address_space() {
+        for value in ["0x10000-0x10000", "0xFFFF-0x10000", "0xFFFF:2", "0x10000:1", "0:0x10001", "0-0xFFFFFFFFFFFFFFFF", "1:0xFFFFFFFFFFFFFFFF"] {
+            assert!(parse_address_range(value).is_err(), "{} was accepted", value);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt::Write;
+use clap::ValueEnum;
+use crc::{Crc, Digest, CRC_16_ARC, CRC_32_ISO_HDLC, CRC_8_SMBUS};
+use sha2::{Digest as _, Sha256};
+
+static CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_ARC);
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+static CRC8: Crc<u8> = Crc::<u8>::new(&CRC_8_SMBUS);
+
+/// Computes CRC-8/SMBUS (polynomial 0x07) used to protect responses to checked read commands.
+pub fn crc8(data: &[u8]) -> u8 {
+    CRC8.checksum(data)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ChecksumAlgorithm {
+    /// CRC-16/ARC
+    Crc16,
+    /// CRC-32 (as used by zip, gzip, etc.)
+    Crc32,
+    /// SHA-256
+    Sha256,
+}
+
+/// Checksum stored in a firmware image by the `sign` command.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SignatureAlgorithm {
+    /// CRC-16/ARC, 2 bytes
+    Crc16,
+    /// Sum of all bytes modulo 256, 1 byte
+    Sum8,
+}
+
+/// Order of bytes of multibyte numbers stored in device memory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ByteOrder {
+    /// Least significant byte first
+    Little,
+    /// Most significant byte first
+    Big,
+}
+
+impl SignatureAlgorithm {
+    /// Number of bytes occupied by the signature.
+    pub fn size(self) -> usize {
+        match self {
+            SignatureAlgorithm::Crc16 => 2,
+            SignatureAlgorithm::Sum8 => 1,
+        }
+    }
+
+    /// Computes signature of `data` as bytes to store in memory.
+    pub fn sign(self, data: &[u8], byte_order: ByteOrder) -> Vec<u8> {
+        match self {
+            SignatureAlgorithm::Crc16 => {
+                let crc = CRC16.checksum(data);
+
+                match byte_order {
+                    ByteOrder::Little => crc.to_le_bytes().to_vec(),
+                    ByteOrder::Big => crc.to_be_bytes().to_vec(),
+                }
+            }
+            SignatureAlgorithm::Sum8 => vec![data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))],
+        }
+    }
+}
+
+/// Incrementally computed checksum of a data stream.
+pub enum Checksum {
+    Crc16(Digest<'static, u16>),
+    Crc32(Digest<'static, u32>),
+    Sha256(Box<Sha256>),
+}
+
+impl Checksum {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Crc16 => Checksum::Crc16(CRC16.digest()),
+            ChecksumAlgorithm::Crc32 => Checksum::Crc32(CRC32.digest()),
+            ChecksumAlgorithm::Sha256 => Checksum::Sha256(Box::new(Sha256::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Checksum::Crc16(digest) => digest.update(data),
+            Checksum::Crc32(digest) => digest.update(data),
+            Checksum::Sha256(digest) => digest.update(data),
+        }
+    }
+
+    /// Returns the checksum as a hexadecimal number.
+    pub fn finish(self) -> String {
+        match self {
+            Checksum::Crc16(digest) => format!("{:04X}", digest.finalize()),
+            Checksum::Crc32(digest) => format!("{:08X}", digest.finalize()),
+            Checksum::Sha256(digest) => digest.finalize().iter().fold(String::with_capacity(64), |mut hex, b| {
+                let _ = write!(hex, "{:02x}", b);
+                hex
+            }),
+        }
+    }
+}
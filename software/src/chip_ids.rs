@@ -0,0 +1,43 @@
+/// Manufacturer codes as assigned by JEDEC.
+const MANUFACTURERS: [(u8, &str); 7] = [
+    (0x01, "AMD"),
+    (0x1F, "Atmel"),
+    (0x20, "STMicroelectronics"),
+    (0x37, "AMIC"),
+    (0xBF, "SST"),
+    (0xC2, "Macronix"),
+    (0xDA, "Winbond"),
+];
+
+/// Known chips: manufacturer code, device code and part number.
+const CHIPS: [(u8, u8, &str); 12] = [
+    (0x01, 0x20, "Am29F010"),
+    (0x01, 0xA4, "Am29F040"),
+    (0x1F, 0xDC, "AT29C256"),
+    (0x1F, 0x5D, "AT29C512"),
+    (0x1F, 0xD5, "AT29C010A"),
+    (0x1F, 0xDA, "AT29C020"),
+    (0x1F, 0xA4, "AT29C040A"),
+    (0xBF, 0xB5, "SST39SF010A"),
+    (0xBF, 0xB6, "SST39SF020A"),
+    (0xBF, 0xB7, "SST39SF040"),
+    (0xDA, 0x45, "W29C020"),
+    (0xDA, 0xC1, "W29EE011"),
+];
+
+/// Returns name of manufacturer with given code.
+pub fn manufacturer_name(code: u8) -> Option<&'static str> {
+    MANUFACTURERS.iter()
+        .find(|(known_code, _)| *known_code == code)
+        .map(|(_, name)| *name)
+}
+
+/// Returns part number of a chip with given ID (manufacturer code followed by device code).
+pub fn chip_name(id: &[u8]) -> Option<&'static str> {
+    match id {
+        [manufacturer, device, ..] => CHIPS.iter()
+            .find(|(known_manufacturer, known_device, _)| known_manufacturer == manufacturer && known_device == device)
+            .map(|(_, _, name)| *name),
+        _ => None,
+    }
+}
@@ -0,0 +1,2529 @@
+use std::cell::Cell;
+use std::fmt::{Display, Formatter};
+use std::cmp::min;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{IsTerminal, Read, stderr, stdin, stdout, Write};
+use std::num::{NonZeroU8, NonZeroUsize};
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use crate::args::{env_flag, parse_address_bias, parse_address_range, parse_buffer_size, parse_byte, parse_duration, AddressRange, BufferSize};
+use crate::checksum::{ByteOrder, Checksum, ChecksumAlgorithm, SignatureAlgorithm};
+use crate::chip_ids::{chip_name, manufacturer_name};
+use crate::data_ops::{DataChunk, check_write_bounds, changed_regions, coalesce_addresses, find_mismatches, DataReadRequest, DataWriteRequest, read_buffer_size_for_frame, read_commands, read_data_into, read_data_into_tolerant, FailedSegment, march_operations, march_test, modify_byte, MarchOperation, self_test, verify_region, verify_region_parallel, verify_region_sample, verify_stream, write_buffer_size_for_frame, write_commands, write_data, write_stream, write_data_healing, write_data_with_progress};
+use crate::device::{Capability, Device, DeviceSettings, DEFAULT_MEMORY_SIZE};
+use crate::device_detector::{detect_device, list_potential_devices, safe_detect_device, DeviceDetectorSettings};
+use crate::repl::run_repl;
+use crate::signals;
+use crate::file_io::{open_input_stream, open_mmap_output_stream, open_output_stream, open_concatenated_input_stream, read_concatenated_input_data, read_input_data, resolve_input_format, OutputFileMode, ADDRESS_BIAS_RAW_ERROR, write_skipped_regions_index, MultiWriter, RotatingFileWriter, Rotation, SplitFileWriter};
+use crate::protocol::{HexCase, TestResponse, TransferMode};
+use crate::formats::{AsmHexStyle, AsmHexWriter, CArrayStyle, CArrayWriter, DataFormat, DenseWriter, SparseWrite, HexDumpWriter, IntelHexWriter, SrecWriter, json_string, parse_address_list, write_mismatches_csv, write_patch, write_mismatches_json};
+
+/// Command line of `romectl`.
+///
+/// Can be parsed from command line arguments or constructed directly to execute a command using
+/// [run].
+#[derive(Parser)]
+#[command(about = "Control ROME, a ROM/RAM chip emulator, over a serial port", long_about = None)]
+pub struct Config {
+    /// Format of command results printed to standard output.
+    #[arg(long, value_enum, global = true, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+
+    /// Print trace of performed operations with their durations to standard error.
+    #[arg(long, global = true)]
+    pub trace: bool,
+
+    /// Print a `key=value` summary line to standard error after the command completes.
+    ///
+    /// The line looks like `rome: command=data.read bytes=65536 duration_ms=4210 retries=2 result=ok`.
+    /// Can be made the default by setting ROME_SUMMARY environment variable to `true`.
+    #[arg(long, global = true)]
+    pub summary: bool,
+
+    /// Do not print the summary line even if ROME_SUMMARY environment variable is set.
+    #[arg(long, global = true, conflicts_with = "summary")]
+    pub no_summary: bool,
+
+    /// Case of hexadecimal digits in data printed in `hexdump`, `ihex`, `srec`, `asm-hex` and
+    /// `c-array` formats, including output of `data stream` and REPL `read` command.
+    ///
+    /// Does not affect commands sent to the device, see --command-hex-case.
+    #[arg(long, value_enum, global = true, default_value_t = HexCase::Upper)]
+    pub output_hex_case: HexCase,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+/// Environment variable that makes --summary the default.
+const SUMMARY_ENV: &str = "ROME_SUMMARY";
+
+impl Config {
+    /// Checks whether the summary line should be printed after the command.
+    pub fn summary_enabled(&self) -> bool {
+        self.summary || (!self.no_summary && env_flag(SUMMARY_ENV))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text
+    Text,
+    /// JSON
+    Json,
+    /// Newline-delimited JSON events
+    ///
+    /// Data commands print `{"event":"progress",...}` lines while transferring data and a final
+    /// `{"event":"done",...}` line. Data read from the device must be written to --output.
+    Ndjson,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Serial port operations
+    #[command(subcommand)]
+    Port(PortCommand),
+
+    /// Device management operations
+    #[command(subcommand)]
+    Device(DeviceCommand),
+
+    /// Data read/write operations
+    Data {
+        #[command(flatten)]
+        detector_settings: DeviceDetectorSettings,
+
+        /// Show progress of data transfer.
+        #[arg(long)]
+        progress: bool,
+
+        /// Print read and write commands that would be sent to the device instead of executing them.
+        ///
+        /// No port is opened. Device memory is assumed to be 64KiB.
+        /// Commands of sample verification are not printed as they are chosen at random.
+        /// Write commands of `set-bits` are not printed as they depend on data read from the device.
+        #[arg(long)]
+        dump_protocol: bool,
+
+        #[command(flatten)]
+        endianness_settings: EndiannessSettings,
+
+        #[command(subcommand)]
+        command: Box<DataCommand>,
+    },
+
+    /// List data formats, checksum algorithms, transfer modes and protocol features supported by
+    /// this program
+    Capabilities {
+        /// Also connect to the device and list protocol features supported by its firmware.
+        #[arg(long)]
+        device: bool,
+
+        #[command(flatten)]
+        detector_settings: DeviceDetectorSettings,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum PortCommand {
+    /// List ports that may be occupied by ROME
+    List,
+    /// Detect port occupied by ROME
+    Detect(Box<DeviceSettings>),
+    /// Watch for ports that may be occupied by ROME being connected or disconnected
+    ///
+    /// Prints `+ <port>` when a port appears (including ports present at start) and `- <port>` when
+    /// it disappears. Runs until interrupted.
+    Watch {
+        /// Interval between checks of available ports.
+        #[arg(long, value_parser = parse_duration, default_value = "1s")]
+        interval: Duration,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DeviceCommand {
+    Version {
+        #[command(flatten)]
+        detector_settings: DeviceDetectorSettings,
+    },
+    Test {
+        #[command(flatten)]
+        detector_settings: DeviceDetectorSettings,
+
+        /// Maximal duration of device test. It usually takes about 3 seconds.
+        #[arg(long, value_parser = parse_duration, default_value = "10s")]
+        test_timeout: Duration,
+
+        #[command(flatten)]
+        external_control_settings: ExternalControlSettings,
+    },
+    EnableExternalControl {
+        #[command(flatten)]
+        detector_settings: DeviceDetectorSettings,
+    },
+    /// Read manufacturer and device ID of the memory chip
+    ///
+    /// Known IDs are shown with manufacturer name and part number.
+    /// Requires firmware supporting `id` feature.
+    Id {
+        #[command(flatten)]
+        detector_settings: DeviceDetectorSettings,
+    },
+    /// Repeatedly synchronize with the device to check link stability
+    ///
+    /// Reports how many ping exchanges succeeded and their round-trip times.
+    /// Fails if any of them failed.
+    Sync {
+        #[command(flatten)]
+        detector_settings: DeviceDetectorSettings,
+
+        /// Number of synchronization attempts.
+        #[arg(long, default_value = "10")]
+        count: NonZeroUsize,
+
+        /// Interval between starts of consecutive attempts.
+        #[arg(long, value_parser = parse_duration, default_value = "100ms")]
+        interval: Duration,
+    },
+    /// Show information about connected device
+    ///
+    /// Information that cannot be retrieved is reported as unavailable.
+    Info {
+        #[command(flatten)]
+        detector_settings: DeviceDetectorSettings,
+
+        /// Also run device test and report its result.
+        #[arg(long)]
+        all: bool,
+
+        /// Maximal duration of device test.
+        #[arg(long, value_parser = parse_duration, default_value = "10s")]
+        test_timeout: Duration,
+    },
+    /// Execute simple commands (`read`, `write`, `version`, ...) on the device
+    ///
+    /// When standard input is a terminal, commands are entered interactively. Otherwise, they are
+    /// read from standard input line by line until its end and execution stops at the first
+    /// failed command, e.g. `echo "read 0 16" | romectl device repl`.
+    Repl {
+        #[command(flatten)]
+        detector_settings: DeviceDetectorSettings,
+    },
+    /// Check the whole data path by writing a test pattern to a scratch region and reading it back.
+    ///
+    /// Unlike the `test` command, which runs firmware's built-in test, this command checks that data
+    /// can be correctly transferred between computer and device memory.
+    /// Original content of the scratch region is saved before the test and restored after it.
+    SelfTest {
+        #[command(flatten)]
+        detector_settings: DeviceDetectorSettings,
+
+        /// Address of the first byte of scratch region.
+        #[arg(long, default_value_t = 0u16)]
+        offset: u16,
+
+        /// Size of scratch region.
+        #[arg(long, default_value = "256")]
+        size: NonZeroUsize,
+
+        #[command(flatten)]
+        external_control_settings: ExternalControlSettings,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DataCommand {
+    /// Read data from device
+    Read {
+        /// Address of the first byte to read.
+        #[arg(long, default_value_t = 0u16)]
+        offset: u16,
+
+        /// Number of bytes to read from device memory.
+        ///
+        /// By default, all data from --offset to the end of device address space will be read.
+        #[arg(long)]
+        size: Option<usize>,
+
+        /// Region given as `<first>-<last>` with inclusive last address or as `<first>:<size>`, e.g.
+        /// `0x100-0x1FF` or `0x100:256`, instead of --offset and --size.
+        #[arg(long, value_parser = parse_address_range, conflicts_with_all = ["offset", "size"])]
+        range: Option<AddressRange>,
+
+        /// Size of read buffer.
+        ///
+        /// Defaults to the largest size safe to use with given --serial-frame-size
+        /// (31 bytes for Arduino's default serial receive buffer).
+        #[arg(long)]
+        buffer_size: Option<u8>,
+
+        /// A file to write the data to.
+        ///
+        /// If not defined, the result will be printed to standard output.
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        /// Format of the output.
+        ///
+        /// By default, the format is chosen by --output extension: `.hex` for Intel HEX,
+        /// `.srec`/`.s19` for S-records, `.txt` for hexdump, `.asm` for asm-hex, `.h`/`.c` for C array
+        /// and raw binary data otherwise.
+        #[arg(long, value_enum, default_value_t = DataFormat::Auto)]
+        format: DataFormat,
+
+        /// Number of bytes on each line of `asm-hex` and `c-array` output.
+        #[arg(long, default_value = "8")]
+        bytes_per_line: NonZeroUsize,
+
+        /// Name of the array defined by `c-array` output.
+        ///
+        /// Length of the array is defined as `<name>_len` constant.
+        #[arg(long, default_value = "data")]
+        array_name: String,
+
+        /// Type of elements of the array defined by `c-array` output.
+        #[arg(long, default_value = "uint8_t")]
+        array_type: String,
+
+        /// Assembler directive preceding bytes on each line of `asm-hex` output, e.g. `db` or `.byte`.
+        ///
+        /// With a directive, bytes are written as `0xNN` numbers separated by commas.
+        /// Without it, bytes are written as bare hexadecimal numbers separated by spaces.
+        #[arg(long)]
+        asm_directive: Option<String>,
+
+        /// Text preceding the address on each line of `asm-hex` output, e.g. `L_` to make addresses
+        /// valid labels.
+        #[arg(long, default_value = "")]
+        asm_label_prefix: String,
+
+        /// Split the output into files of given size.
+        ///
+        /// Files are named after --output with a numeric suffix appended: `<output>.000`,
+        /// `<output>.001`, etc. Can be used with raw output format only.
+        #[arg(long, requires = "output")]
+        split: Option<NonZeroUsize>,
+
+        /// Write output through a memory-mapped file.
+        ///
+        /// Lets the OS manage flushing of written data, which may be faster for very large dumps.
+        /// Falls back to regular file output if memory mapping is not available.
+        #[arg(long, requires = "output", conflicts_with = "split")]
+        mmap: bool,
+
+        /// Fail if --output file (or any part file with --split) already exists.
+        ///
+        /// By default, existing files are overwritten.
+        #[arg(long, requires = "output")]
+        no_clobber: bool,
+
+        /// Append the data to --output file if it already exists, e.g. to continue an interrupted
+        /// dump of raw data.
+        #[arg(long, requires = "output", conflicts_with_all = ["no_clobber", "split", "mmap"])]
+        append: bool,
+
+        /// Also print hexdump of the data to standard output.
+        ///
+        /// The data is still written to --output in the selected --format.
+        #[arg(long, requires = "output")]
+        tee: bool,
+
+        /// Omit read chunks consisting entirely of --fill-byte from the output.
+        ///
+        /// Offsets and lengths of omitted regions are written to an index file named after --output
+        /// with `.skipped` suffix appended.
+        #[arg(long, requires = "output", conflicts_with_all = ["mmap", "tee"])]
+        skip_fill: bool,
+
+        /// Value of bytes in regions omitted by --skip-fill.
+        #[arg(long, value_parser = parse_byte, default_value = "0xFF")]
+        fill_byte: u8,
+
+        /// What to do when a chunk cannot be read.
+        ///
+        /// Failed chunks are listed when the read completes.
+        #[arg(long, value_enum, default_value_t = ReadErrorAction::Abort, conflicts_with = "reverse")]
+        on_error: ReadErrorAction,
+
+        /// Value of bytes written in place of chunks that could not be read with `--on-error fill`.
+        #[arg(long, value_parser = parse_byte, default_value = "0xFF")]
+        error_fill_byte: u8,
+
+        /// Accept less data than requested in response to the last read command.
+        ///
+        /// Some firmware returns less data than requested when reading at the end of addressable
+        /// memory.
+        /// With this option, such a short read ends the read operation instead of failing it.
+        #[arg(long)]
+        allow_short_read: bool,
+
+        /// Read chunks starting from the highest address.
+        ///
+        /// The output is still written in address order, so it's the same as for a normal read,
+        /// but the whole data is kept in memory until the read completes.
+        #[arg(long)]
+        reverse: bool,
+
+        /// Fail instead of writing more than given number of bytes of data to the output.
+        ///
+        /// Protects terminals and consumers of standard output from accidentally requested huge
+        /// dumps. Data already written before the limit was reached is kept.
+        #[arg(long)]
+        max_bytes: Option<usize>,
+
+        /// Write only data that differs from given previous dump instead of all data.
+        ///
+        /// Changed regions are written to --output (or standard output) as a patch, one region per
+        /// line: offset and length as hexadecimal numbers followed by hexadecimal data, e.g.
+        /// `0x0010 0x2 BEEF`. A summary is printed to standard error.
+        /// Raw baseline files are assumed to start at --offset, Intel HEX and S-record files use
+        /// their record addresses. The baseline must cover the whole read region.
+        #[arg(long, conflicts_with_all = ["split", "mmap", "append", "tee", "skip_fill", "reverse"])]
+        baseline: Option<PathBuf>,
+
+        /// Exit with non-zero code if there is nothing to read, e.g. when --offset points to the end
+        /// of device memory.
+        ///
+        /// By default, an empty region is reported but not considered an error.
+        #[arg(long)]
+        fail_on_empty: bool,
+
+        #[command(flatten)]
+        external_control_settings: ExternalControlSettings,
+    },
+    /// Write data to device
+    Write {
+        /// Address of first byte to write.
+        #[arg(long, default_value_t = 0u16)]
+        offset: u16,
+
+        /// Size of buffer used during write operation.
+        ///
+        /// Defaults to a value safe to use with given --serial-frame-size
+        /// (29 bytes for Arduino's default serial receive buffer).
+        #[arg(long)]
+        buffer_size: Option<u8>,
+
+        /// Size of memory pages.
+        ///
+        /// If set, no write command crosses a page boundary: data is split at page boundaries in
+        /// addition to splitting by --buffer-size.
+        #[arg(long)]
+        page_size: Option<NonZeroUsize>,
+
+        /// Path to input file.
+        ///
+        /// If not specified, the standard input will be used.
+        /// May be repeated to write raw files concatenated in given order, e.g.
+        /// `--input bootloader.bin --input app.bin`.
+        #[arg(long)]
+        input: Vec<PathBuf>,
+
+        /// Skip given number of bytes at the start of the input data.
+        ///
+        /// The remaining data is still written starting at --offset.
+        #[arg(long, default_value_t = 0)]
+        input_offset: usize,
+
+        /// Write at most given number of bytes of the input data (after --input-offset).
+        #[arg(long)]
+        input_length: Option<usize>,
+
+        /// Format of the input.
+        ///
+        /// By default, the format is chosen by --input extension: `.hex` for Intel HEX,
+        /// `.srec`/`.s19` for S-records and raw binary data otherwise.
+        /// Addresses of Intel HEX and S-record files are added to --offset, gaps between records are
+        /// filled with 0xFF.
+        #[arg(long, value_enum, default_value_t = DataFormat::Auto)]
+        format: DataFormat,
+
+        /// Value added to addresses of Intel HEX and S-record records before they are added to
+        /// --offset, e.g. `-0x8000` for an image linked at 0x8000 that must be placed at the
+        /// beginning of device memory.
+        #[arg(long, value_parser = parse_address_bias, allow_hyphen_values = true, default_value_t = 0)]
+        address_bias: i64,
+
+        /// Verify written data after writing.
+        ///
+        /// If set, the program will read written data back from the device and compare it with
+        /// the data that should have been written.
+        /// If the data received from device differs, the program will exit with a non-zero code.
+        ///
+        /// `--verify` (same as `--verify=full`) reads back all written data.
+        /// `--verify=sample` reads back only a pseudo-randomly chosen part of written chunks.
+        /// It is faster but much weaker than full verification.
+        /// `--verify=heal` reads back each chunk as soon as it is written and rewrites chunks that
+        /// differ, up to --max-rewrites times, which helps with marginal chips.
+        #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "full")]
+        verify: Option<VerifyMode>,
+
+        /// Compare data read during full verification in a separate thread.
+        ///
+        /// The serial port still transfers one chunk at a time, so there is no measurable speedup:
+        /// reading takes much more time than comparison.
+        #[arg(long, requires = "verify", conflicts_with = "diff_output")]
+        parallel_verify: bool,
+
+        /// Verify only bytes described by records of Intel HEX and S-record input.
+        ///
+        /// Gaps between records are filled with 0xFF when written. With this option they are
+        /// treated as don't-care and not read back during verification.
+        #[arg(long, requires = "verify")]
+        verify_only_written: bool,
+
+        /// Percentage of written chunks to read back when `--verify=sample` is used.
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u8).range(1..=100))]
+        verify_sample_percent: u8,
+
+        /// Seed used to choose chunks to read back when `--verify=sample` is used.
+        ///
+        /// If not specified, a random seed is used. The seed is printed, so the same chunks can be
+        /// checked again later.
+        #[arg(long)]
+        verify_sample_seed: Option<u64>,
+
+        /// Size of buffer used for read operations during write result validation, or `auto`.
+        ///
+        /// `auto` (the default) picks the largest read buffer that fits into --serial-frame-size,
+        /// the same as default --buffer-size of read command, regardless of write --buffer-size.
+        #[arg(long, value_parser = parse_buffer_size, value_name = "SIZE")]
+        verification_read_buffer_size: Option<BufferSize>,
+
+        /// Delay between writing the data and reading it back for verification.
+        ///
+        /// Needed when device memory is backed by a parallel EEPROM (e.g. AT28C64 or AT28C256)
+        /// instead of RAM: such chips keep programming the last written bytes internally for up
+        /// to 10ms and return invalid data until they finish.
+        #[arg(long, value_parser = parse_duration, default_value = "0s")]
+        verify_delay: Duration,
+
+        /// Maximal number of times a chunk is written again when `--verify=heal` finds a mismatch.
+        #[arg(long, default_value_t = 3)]
+        max_rewrites: usize,
+
+        /// Write list of all mismatching bytes found by --verify=full to given file.
+        ///
+        /// The list is written as JSON if the file name ends with `.json` and as CSV otherwise.
+        /// The file is written even if no bytes differ, so it never holds results of an earlier run.
+        /// Without this option verification stops at the first mismatch.
+        #[arg(long, requires = "verify")]
+        diff_output: Option<PathBuf>,
+
+        /// Number of times to repeat writing (and verification, if enabled).
+        ///
+        /// Useful for endurance testing.
+        /// Repetition stops at the first failed iteration unless --keep-going is set.
+        #[arg(long, default_value = "1")]
+        repeat: NonZeroUsize,
+
+        /// Continue repeating after a failed iteration.
+        #[arg(long)]
+        keep_going: bool,
+
+        /// Do not ask for confirmation before overwriting a large region.
+        ///
+        /// Confirmation is asked only when running in a terminal.
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Write the input as it arrives instead of reading all of it first.
+        ///
+        /// Useful when the input is produced by another program, e.g. through a named pipe.
+        /// Each chunk of --buffer-size bytes is written as soon as it is available, waiting for slow
+        /// producer as long as necessary. Raw input format only, no confirmation is asked.
+        #[arg(long, conflicts_with_all = ["verify", "diff_output", "repeat"])]
+        stream: bool,
+
+        /// Exit with non-zero code if the input is empty.
+        ///
+        /// By default, empty input is reported but not considered an error.
+        #[arg(long)]
+        fail_on_empty: bool,
+
+        #[command(flatten)]
+        external_control_settings: ExternalControlSettings,
+    },
+    /// Compare data in device memory with a file without writing anything
+    ///
+    /// Unlike --verify option of write command, this command can be used to check data written
+    /// earlier, e.g. after a power cycle.
+    /// Exits with non-zero code if the data differs.
+    Verify {
+        /// Address of the first byte to compare.
+        #[arg(long, default_value_t = 0u16)]
+        offset: u16,
+
+        /// Number of bytes to compare.
+        ///
+        /// The input must contain at least that many bytes.
+        /// If not specified, the whole input is compared.
+        #[arg(long)]
+        size: Option<NonZeroUsize>,
+
+        /// Region given as `<first>-<last>` with inclusive last address or as `<first>:<size>`, e.g.
+        /// `0x100-0x1FF` or `0x100:256`, instead of --offset and --size.
+        #[arg(long, value_parser = parse_address_range, conflicts_with_all = ["offset", "size"])]
+        range: Option<AddressRange>,
+
+        /// Path to file containing expected data.
+        ///
+        /// If not specified, the standard input will be used.
+        /// Files with .gz extension are decompressed on the fly.
+        #[arg(long)]
+        input: Option<PathBuf>,
+
+        /// Format of the input.
+        ///
+        /// By default, the format is chosen by --input extension: `.hex` for Intel HEX,
+        /// `.srec`/`.s19` for S-records and raw binary data otherwise.
+        /// Addresses of Intel HEX and S-record files are added to --offset, gaps between records are
+        /// filled with 0xFF.
+        #[arg(long, value_enum, default_value_t = DataFormat::Auto)]
+        format: DataFormat,
+
+        /// Value added to addresses of Intel HEX and S-record records before they are added to
+        /// --offset, e.g. `-0x8000` for an image linked at 0x8000 that must be placed at the
+        /// beginning of device memory.
+        #[arg(long, value_parser = parse_address_bias, allow_hyphen_values = true, default_value_t = 0)]
+        address_bias: i64,
+
+        /// Size of read buffer.
+        ///
+        /// Defaults to the largest size safe to use with given --serial-frame-size.
+        #[arg(long)]
+        buffer_size: Option<u8>,
+
+        #[command(flatten)]
+        external_control_settings: ExternalControlSettings,
+    },
+    /// Read a region of device memory and print its checksum only
+    Checksum {
+        /// Address of the first byte of the region.
+        #[arg(long, default_value_t = 0u16)]
+        offset: u16,
+
+        /// Size of the region.
+        ///
+        /// By default, all data from --offset to the end of device address space is used.
+        #[arg(long)]
+        size: Option<usize>,
+
+        /// Region given as `<first>-<last>` with inclusive last address or as `<first>:<size>`, e.g.
+        /// `0x100-0x1FF` or `0x100:256`, instead of --offset and --size.
+        #[arg(long, value_parser = parse_address_range, conflicts_with_all = ["offset", "size"])]
+        range: Option<AddressRange>,
+
+        /// Checksum algorithm.
+        #[arg(long, value_enum, default_value_t = ChecksumAlgorithm::Crc32)]
+        algorithm: ChecksumAlgorithm,
+
+        /// Size of read buffer.
+        ///
+        /// Defaults to the largest size safe to use with given --serial-frame-size.
+        #[arg(long)]
+        buffer_size: Option<u8>,
+
+        #[command(flatten)]
+        external_control_settings: ExternalControlSettings,
+    },
+    /// Write an image and store its checksum in a reserved slot
+    ///
+    /// The checksum covers image bytes preceding the slot. The image is written first, then the
+    /// checksum is written into the slot.
+    Sign {
+        /// Address of the first byte of the image.
+        #[arg(long, default_value_t = 0u16)]
+        offset: u16,
+
+        /// Path to input file.
+        ///
+        /// If not specified, the standard input will be used.
+        #[arg(long)]
+        input: Option<PathBuf>,
+
+        /// Format of the input.
+        #[arg(long, value_enum, default_value_t = DataFormat::Auto)]
+        format: DataFormat,
+
+        /// Value added to addresses of Intel HEX and S-record records before they are added to
+        /// --offset, e.g. `-0x8000` for an image linked at 0x8000 that must be placed at the
+        /// beginning of device memory.
+        #[arg(long, value_parser = parse_address_bias, allow_hyphen_values = true, default_value_t = 0)]
+        address_bias: i64,
+
+        /// Checksum algorithm.
+        #[arg(long, value_enum, default_value_t = SignatureAlgorithm::Crc16)]
+        algorithm: SignatureAlgorithm,
+
+        /// Offset of the checksum slot from the start of the image.
+        ///
+        /// By default, the slot occupies the last bytes of the image.
+        #[arg(long)]
+        slot_offset: Option<usize>,
+
+        /// Size of write buffer.
+        ///
+        /// Defaults to the largest size safe to use with given --serial-frame-size.
+        #[arg(long)]
+        buffer_size: Option<u8>,
+
+        /// Read back the image and the checksum after writing and compare them with written data.
+        #[arg(long)]
+        verify: bool,
+
+        #[command(flatten)]
+        external_control_settings: ExternalControlSettings,
+    },
+    /// Write the same byte to every address from a list
+    ///
+    /// Useful for patching scattered bytes, e.g. replacing specific opcodes by no-ops.
+    /// Consecutive addresses are written together.
+    WriteList {
+        /// File with addresses to write, one hexadecimal address per line (e.g. `1F00` or `0x1F00`).
+        ///
+        /// Empty lines and lines starting with `#` are ignored.
+        /// If not specified, the standard input will be used.
+        #[arg(long)]
+        addresses: Option<PathBuf>,
+
+        /// Byte to write.
+        #[arg(long, value_parser = parse_byte)]
+        value: u8,
+
+        /// Size of write buffer.
+        ///
+        /// Defaults to the largest size safe to use with given --serial-frame-size.
+        #[arg(long)]
+        buffer_size: Option<u8>,
+
+        /// Read back written bytes and compare them with --value.
+        #[arg(long)]
+        verify: bool,
+
+        #[command(flatten)]
+        external_control_settings: ExternalControlSettings,
+    },
+    /// Test RAM using March C- algorithm
+    ///
+    /// Checkerboard pattern and its complement are written to and read from the region in
+    /// ascending and descending order of chunks. Address of the first byte holding unexpected value
+    /// is reported on failure.
+    ///
+    /// The test is destructive: original content of the region is lost. It is only meaningful for
+    /// RAM, running it on EEPROM wears the memory out.
+    MarchTest {
+        /// Address of the first byte of the region.
+        #[arg(long, default_value_t = 0u16)]
+        offset: u16,
+
+        /// Size of the region.
+        ///
+        /// By default, all memory from --offset to the end of device address space is tested.
+        #[arg(long)]
+        size: Option<usize>,
+
+        /// Region given as `<first>-<last>` with inclusive last address or as `<first>:<size>`, e.g.
+        /// `0x100-0x1FF` or `0x100:256`, instead of --offset and --size.
+        #[arg(long, value_parser = parse_address_range, conflicts_with_all = ["offset", "size"])]
+        range: Option<AddressRange>,
+
+        /// Size of chunks read and written at once.
+        ///
+        /// Defaults to the largest size safe for both reads and writes with given --serial-frame-size.
+        #[arg(long)]
+        buffer_size: Option<u8>,
+
+        /// Do not ask for confirmation before overwriting the region.
+        ///
+        /// Confirmation is asked only when running in a terminal.
+        #[arg(short, long)]
+        yes: bool,
+
+        #[command(flatten)]
+        external_control_settings: ExternalControlSettings,
+    },
+    /// Change selected bits of a single byte
+    ///
+    /// The byte is read, bits selected by --mask are replaced by corresponding bits of --value and
+    /// the result is written back. The operation is not atomic: data written to the byte by other
+    /// bus masters between the read and the write is lost.
+    SetBits {
+        /// Address of the byte.
+        #[arg(long)]
+        offset: u16,
+
+        /// Bits to change, e.g. `0x0C`.
+        #[arg(long, value_parser = parse_byte)]
+        mask: u8,
+
+        /// New values of bits selected by --mask. Other bits are ignored.
+        #[arg(long, value_parser = parse_byte)]
+        value: u8,
+
+        /// Read back the written byte and compare it with the expected value.
+        #[arg(long)]
+        verify: bool,
+
+        #[command(flatten)]
+        external_control_settings: ExternalControlSettings,
+    },
+    /// Read a region repeatedly, printing timestamped hexdump of each snapshot to standard output
+    ///
+    /// Runs until --count snapshots are printed or until interrupted.
+    Stream {
+        /// Address of the first byte of the region.
+        #[arg(long, default_value_t = 0u16)]
+        offset: u16,
+
+        /// Size of the region.
+        #[arg(long)]
+        size: NonZeroUsize,
+
+        /// Interval between starts of consecutive reads.
+        #[arg(long, value_parser = parse_duration, default_value = "1s")]
+        interval: Duration,
+
+        /// Number of snapshots to take. By default, reads continue until interrupted.
+        #[arg(long)]
+        count: Option<NonZeroUsize>,
+
+        /// A file to write snapshots to instead of standard output.
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        /// Write snapshots into a ring of at most <count> files of <size> bytes each.
+        ///
+        /// Files are named after --output with a numeric suffix appended: `<output>.000`,
+        /// `<output>.001`, etc. The oldest file is deleted when a new one is created, so long
+        /// captures use a bounded amount of disk space.
+        #[arg(long, value_name = "COUNT:SIZE", value_parser = Rotation::parse, requires = "output")]
+        rotate: Option<Rotation>,
+
+        /// Size of read buffer.
+        ///
+        /// Defaults to the largest size safe to use with given --serial-frame-size.
+        #[arg(long)]
+        buffer_size: Option<u8>,
+    },
+}
+
+/// Replaces --offset and --size of data commands by values given with --range.
+fn apply_range(mut command: DataCommand) -> DataCommand {
+    match &mut command {
+        DataCommand::Read { offset, size, range, .. }
+        | DataCommand::Checksum { offset, size, range, .. }
+        | DataCommand::MarchTest { offset, size, range, .. } => {
+            if let Some(range) = range.take() {
+                *offset = range.offset;
+                *size = Some(range.size.get());
+            }
+        }
+        DataCommand::Verify { offset, size, range, .. } => {
+            if let Some(range) = range.take() {
+                *offset = range.offset;
+                *size = Some(range.size);
+            }
+        }
+        _ => {}
+    }
+
+    command
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ReadErrorAction {
+    /// Stop reading at the first failed chunk
+    Abort,
+    /// Write --error-fill-byte in place of the failed chunk and continue
+    Fill,
+    /// Leave a gap in place of the failed chunk and continue, for Intel HEX and S-record output only
+    Skip,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+pub enum VerifyMode {
+    /// Read back and compare all written data
+    Full,
+    /// Read back and compare a random sample of written chunks
+    Sample,
+    /// Read back every chunk right after writing it and write it again if it differs
+    Heal,
+}
+
+/// Byte order shared by data commands dealing with multibyte numbers.
+#[derive(Copy, Clone, Args)]
+pub struct EndiannessSettings {
+    /// Byte order of multibyte numbers stored in device memory, e.g. checksums written by `sign`.
+    ///
+    /// May be given before or after the data command name. `--byte-order` is accepted as an alias.
+    #[arg(long, global = true, alias = "byte-order", value_enum, default_value_t = ByteOrder::Little)]
+    pub endianness: ByteOrder,
+}
+
+#[derive(Args)]
+pub struct ExternalControlSettings {
+    /// Do not switch to external control after operation completion.
+    ///
+    /// Can be made the default by setting ROME_NO_EXTERNAL_CONTROL environment variable to `true`.
+    #[arg(long)]
+    pub no_external_control: bool,
+
+    /// Switch to external control after operation completion even if ROME_NO_EXTERNAL_CONTROL
+    /// environment variable is set.
+    #[arg(long, conflicts_with = "no_external_control")]
+    pub external_control: bool,
+}
+
+/// Environment variable that makes --no-external-control the default.
+const NO_EXTERNAL_CONTROL_ENV: &str = "ROME_NO_EXTERNAL_CONTROL";
+
+impl ExternalControlSettings {
+    fn apply(&self, device: &mut Device) -> Result<()> {
+        let disabled = self.no_external_control || (!self.external_control && env_flag(NO_EXTERNAL_CONTROL_ENV));
+
+        if !disabled {
+            device.enable_external_control()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns size of region starting at `offset`, which is the rest of device memory by default.
+fn region_size(device: &mut Device, offset: u16, size: Option<usize>) -> Result<Option<NonZeroUsize>> {
+    Ok(match size {
+        None => {
+            let device_size = device.memory_size()?;
+            let remaining_size = device_size.checked_sub(offset as usize)
+                .ok_or_else(|| anyhow!(
+                    "Offset 0x{:04X} is outside of device memory of 0x{:X} bytes",
+                    offset,
+                    device_size,
+                ))?;
+            NonZeroUsize::new(remaining_size)
+        }
+        Some(sz) => NonZeroUsize::new(sz),
+    })
+}
+
+/// Image to write and checksum slot with the checksum.
+type SignedImage = (DataChunk<Vec<u8>>, DataChunk<Vec<u8>>);
+
+/// Reads image to sign and computes its checksum.
+fn signed_image(
+    offset: u16,
+    input: Option<PathBuf>,
+    format: DataFormat,
+    address_bias: i64,
+    algorithm: SignatureAlgorithm,
+    slot_offset: Option<usize>,
+    byte_order: ByteOrder,
+) -> Result<SignedImage> {
+    let (data_address, data) = read_input_data(input, format, address_bias)?;
+    let offset = input_data_offset(offset, data_address)?;
+
+    if data.is_empty() {
+        return Err(anyhow!("Empty input data file or stream provided. Nothing to sign"));
+    }
+
+    let slot_offset = match slot_offset {
+        Some(slot_offset) => slot_offset,
+        None => data.len().checked_sub(algorithm.size())
+            .ok_or_else(|| anyhow!("Image of {} bytes is too small to hold a {}-byte checksum", data.len(), algorithm.size()))?,
+    };
+    let signature = algorithm.sign(&data[..slot_offset.min(data.len())], byte_order);
+    let slot_address = u16::try_from(offset as usize + slot_offset)
+        .map_err(|_| anyhow!("Checksum slot at offset 0x{:X} is outside of device address space", slot_offset))?;
+
+    Ok((DataChunk { offset, data }, DataChunk { offset: slot_address, data: signature }))
+}
+
+/// Takes part of input data selected by --input-offset and --input-length options.
+fn slice_input_data(mut data: Vec<u8>, input_offset: usize, input_length: Option<usize>) -> Result<Vec<u8>> {
+    if input_offset > data.len() {
+        return Err(anyhow!("--input-offset of {} bytes is beyond the end of input ({} bytes)", input_offset, data.len()));
+    }
+
+    data.drain(..input_offset);
+
+    if let Some(length) = input_length {
+        if length > data.len() {
+            return Err(anyhow!(
+                "Input is too short: {} bytes requested by --input-length, only {} available after --input-offset",
+                length,
+                data.len(),
+            ));
+        }
+
+        data.truncate(length);
+    }
+
+    Ok(data)
+}
+
+/// Converts regions of input data into regions of its part selected by --input-offset and of
+/// `length` bytes.
+fn slice_input_regions(regions: &[(usize, usize)], input_offset: usize, length: usize) -> Vec<(usize, usize)> {
+    regions.iter()
+        .filter_map(|(start, region_length)| {
+            let start_in_slice = start.saturating_sub(input_offset);
+            let end_in_slice = min((start + region_length).saturating_sub(input_offset), length);
+
+            (start_in_slice < end_in_slice).then_some((start_in_slice, end_in_slice - start_in_slice))
+        })
+        .collect()
+}
+
+/// Reads list of addresses from given file or the standard input.
+fn read_address_list(path: Option<PathBuf>) -> Result<Vec<u16>> {
+    let mut text = String::new();
+    open_input_stream(path)?.read_to_string(&mut text)?;
+
+    parse_address_list(text.as_str())
+}
+
+/// Reads part of a previous dump covering `size` bytes starting at `offset`.
+fn baseline_region(path: PathBuf, offset: u16, size: NonZeroUsize) -> Result<DataChunk<Vec<u8>>> {
+    let has_addresses = DataFormat::Auto.resolve_input(Some(path.as_path())) != DataFormat::Raw;
+    let description = path.display().to_string();
+    let (data_address, data) = read_input_data(Some(path), DataFormat::Auto, 0)?;
+    let baseline_offset = if has_addresses { data_address } else { offset as usize };
+    let start = (offset as usize).checked_sub(baseline_offset)
+        .filter(|start| start.checked_add(size.get()).is_some_and(|end| end <= data.len()))
+        .ok_or_else(|| anyhow!(
+            "Baseline {} (0x{:04X}:0x{:04X}) does not cover read region 0x{:04X}:0x{:04X}",
+            description,
+            baseline_offset,
+            baseline_offset + data.len(),
+            offset,
+            (offset as usize).saturating_add(size.get()),
+        ))?;
+
+    Ok(DataChunk { offset, data: data[start..start + size.get()].to_vec() })
+}
+
+/// Computes device address of input data located at `data_address` according to input file.
+fn input_data_offset(offset: u16, data_address: usize) -> Result<u16> {
+    data_address.checked_add(offset as usize).and_then(|address| u16::try_from(address).ok()).ok_or_else(|| anyhow!(
+        "Input data address 0x{:X} (with offset 0x{:04X}) is outside of device address range",
+        data_address,
+        offset,
+    ))
+}
+
+/// Regions larger than this require confirmation before being overwritten.
+const CONFIRMATION_SIZE_THRESHOLD: usize = 0x1000;
+
+/// Asks user to confirm an operation.
+///
+/// Returns `true` without asking when not running in a terminal.
+fn confirm(question: &str) -> Result<bool> {
+    if !stdin().is_terminal() || !stderr().is_terminal() {
+        return Ok(true);
+    }
+
+    eprint!("{} [y/N] ", question);
+
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Minimal interval between progress events printed in NDJSON output format.
+const PROGRESS_EVENT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reports progress of data transfer to standard error (text) or standard output (NDJSON events).
+struct Progress {
+    show: bool,
+    output_format: OutputFormat,
+    last_event: Cell<Option<Instant>>,
+}
+
+impl Progress {
+    fn new(show: bool, output_format: OutputFormat) -> Self {
+        Progress { show, output_format, last_event: Cell::new(None) }
+    }
+
+    /// Reports that `done` bytes out of `total` (if known) were transferred.
+    fn report(&self, operation: &str, done: usize, total: Option<usize>) {
+        if self.output_format == OutputFormat::Ndjson {
+            let finished = total.is_some_and(|total| done >= total);
+            let now = Instant::now();
+
+            if !finished && self.last_event.get().is_some_and(|last| now - last < PROGRESS_EVENT_INTERVAL) {
+                return;
+            }
+            self.last_event.set(Some(now));
+
+            println!(
+                "{{\"event\":\"progress\",\"done\":{},\"total\":{}}}",
+                done,
+                total.map_or("null".to_string(), |total| total.to_string()),
+            );
+            return;
+        }
+
+        if !self.show {
+            return;
+        }
+
+        match total {
+            Some(total) => {
+                eprint!("\r{}: {}/{} bytes ({}%)", operation, done, total, done * 100 / total);
+
+                if done >= total {
+                    eprintln!();
+                }
+            }
+            None => eprint!("\r{}: {} bytes", operation, done),
+        }
+    }
+
+    /// Terminates progress line of a transfer of unknown size.
+    fn finish(&self) {
+        if self.show && self.output_format != OutputFormat::Ndjson {
+            eprintln!();
+        }
+    }
+
+    /// Prints final event with given fields (names and JSON values) in NDJSON output format.
+    fn done(&self, fields: &[(&str, String)]) {
+        if self.output_format != OutputFormat::Ndjson {
+            return;
+        }
+
+        let mut event = String::from("{\"event\":\"done\"");
+        for (name, value) in fields {
+            event.push_str(format!(",{}:{}", json_string(name), value).as_str());
+        }
+        event.push('}');
+
+        println!("{}", event);
+    }
+}
+
+/// Error returned by commands that have already reported their failure, so the caller only has to
+/// exit with non-zero code.
+#[derive(Debug)]
+pub struct CommandFailed;
+
+impl Display for CommandFailed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command failed")
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// Executes a command.
+///
+/// Command results are printed to standard output and diagnostics to standard error, as when
+/// running `romectl` from command line. Failures of device tests that were already reported return
+/// [CommandFailed] error.
+pub fn run(args: Config) -> Result<()> {
+    match args.command {
+        Command::Port(PortCommand::List) => {
+            let ports = list_potential_devices()?;
+
+            if ports.is_empty() {
+                return Err(anyhow!("No ports found"));
+            }
+
+            for port_info in ports {
+                println!("{}", port_info.port_name);
+            }
+        }
+        Command::Port(PortCommand::Watch { interval }) => {
+            let mut known_ports = BTreeSet::new();
+
+            while signals::received_signal().is_none() {
+                let ports: BTreeSet<String> = list_potential_devices()?
+                    .into_iter()
+                    .map(|port_info| port_info.port_name)
+                    .collect();
+
+                for port in ports.difference(&known_ports) {
+                    println!("+ {}", port);
+                }
+                for port in known_ports.difference(&ports) {
+                    println!("- {}", port);
+                }
+                stdout().flush()?;
+
+                known_ports = ports;
+                if signals::sleep_unless_interrupted(interval) {
+                    break;
+                }
+            }
+        }
+        Command::Port(PortCommand::Detect(device_settings)) => {
+            let device = safe_detect_device(&device_settings)?;
+
+            println!("{}", device.name());
+        }
+        Command::Device(DeviceCommand::Version { detector_settings }) => {
+            let mut device = detect_device(&detector_settings)?;
+
+            println!("{}", device.version()?);
+        }
+        Command::Device(DeviceCommand::Test {
+                            test_timeout,
+                            detector_settings,
+                            external_control_settings,
+                        }) => {
+            let mut device = detect_device(&detector_settings)?;
+
+            let response = device.run_test(test_timeout)?;
+
+            match args.output_format {
+                OutputFormat::Text => match &response {
+                    TestResponse::Passed => eprintln!("Test passed"),
+                    TestResponse::Failed { detail: None } => eprintln!("Test failed"),
+                    TestResponse::Failed { detail: Some(detail) } => eprintln!("Test failed: {}", detail),
+                },
+                OutputFormat::Json | OutputFormat::Ndjson => {
+                    let (result, detail) = match &response {
+                        TestResponse::Passed => ("passed", None),
+                        TestResponse::Failed { detail } => ("failed", detail.as_deref()),
+                    };
+
+                    println!(
+                        "{{\"test\": {}, \"detail\": {}}}",
+                        json_string(result),
+                        detail.map_or("null".to_string(), json_string),
+                    );
+                }
+            }
+
+            if response != TestResponse::Passed {
+                device.print_recent_info_messages();
+                return Err(CommandFailed.into());
+            }
+
+            external_control_settings.apply(&mut device)?;
+        }
+        Command::Device(DeviceCommand::Info {
+                            detector_settings,
+                            all,
+                            test_timeout,
+                        }) => {
+            let mut device = detect_device(&detector_settings)?;
+
+            let version = device.version();
+            let capabilities: Vec<&str> = device.firmware_info()
+                .map(|firmware| firmware.capabilities.iter().map(Capability::name).collect())
+                .unwrap_or_default();
+            let memory_size = device.memory_size();
+            let test_result = if all { Some(device.run_test(test_timeout)) } else { None };
+
+            match args.output_format {
+                OutputFormat::Text => {
+                    println!("Port: {}", device.name());
+                    match version {
+                        Ok(version) => println!("Version: {}", version),
+                        Err(e) => println!("Version: unavailable ({:#})", e),
+                    }
+                    if capabilities.is_empty() {
+                        println!("Capabilities: none");
+                    } else {
+                        println!("Capabilities: {}", capabilities.join(", "));
+                    }
+                    match memory_size {
+                        Ok(size) => println!("Memory size: 0x{:X} ({} bytes)", size, size),
+                        Err(e) => println!("Memory size: unavailable ({:#})", e),
+                    }
+                    match test_result {
+                        None => {}
+                        Some(Ok(TestResponse::Passed)) => println!("Test: passed"),
+                        Some(Ok(TestResponse::Failed { detail: None })) => println!("Test: failed"),
+                        Some(Ok(TestResponse::Failed { detail: Some(detail) })) => println!("Test: failed ({})", detail),
+                        Some(Err(e)) => println!("Test: unavailable ({:#})", e),
+                    }
+                }
+                OutputFormat::Json | OutputFormat::Ndjson => {
+                    let fields = [
+                        ("port", json_string(device.name())),
+                        ("version", version.map_or("null".to_string(), |version| json_string(version.as_str()))),
+                        (
+                            "capabilities",
+                            format!("[{}]", capabilities.iter().map(|name| json_string(name)).collect::<Vec<_>>().join(", ")),
+                        ),
+                        ("memory_size", memory_size.map_or("null".to_string(), |size| size.to_string())),
+                        ("test", match &test_result {
+                            Some(Ok(TestResponse::Passed)) => "\"passed\"",
+                            Some(Ok(TestResponse::Failed { .. })) => "\"failed\"",
+                            _ => "null",
+                        }.to_string()),
+                        ("test_detail", match &test_result {
+                            Some(Ok(TestResponse::Failed { detail: Some(detail) })) => json_string(detail),
+                            _ => "null".to_string(),
+                        }),
+                    ];
+                    let fields = fields.iter().map(|(name, value)| format!("{}: {}", json_string(name), value));
+
+                    if args.output_format == OutputFormat::Ndjson {
+                        println!("{{{}}}", fields.collect::<Vec<_>>().join(", "));
+                    } else {
+                        println!("{{\n  {}\n}}", fields.collect::<Vec<_>>().join(",\n  "));
+                    }
+                }
+            }
+        }
+        Command::Device(DeviceCommand::Repl { detector_settings }) => {
+            let mut device = detect_device(&detector_settings)?;
+            let interactive = stdin().is_terminal();
+
+            if interactive {
+                eprintln!("Connected to {}. Type `help` for list of commands", device.name());
+            }
+
+            run_repl(&mut device, stdin().lock(), &mut stdout(), interactive, args.output_hex_case)?;
+        }
+        Command::Device(DeviceCommand::Id { detector_settings }) => {
+            let mut device = detect_device(&detector_settings)?;
+            let id = device.chip_id()?;
+            let manufacturer = id.first().and_then(|code| manufacturer_name(*code));
+            let chip = chip_name(id.as_slice());
+
+            match args.output_format {
+                OutputFormat::Text => {
+                    let id_bytes = id.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ");
+
+                    match (manufacturer, chip) {
+                        (Some(manufacturer), Some(chip)) => println!("{} ({} {})", id_bytes, manufacturer, chip),
+                        (Some(manufacturer), None) => println!("{} ({}, unknown device)", id_bytes, manufacturer),
+                        _ => println!("{} (unknown)", id_bytes),
+                    }
+                }
+                OutputFormat::Json | OutputFormat::Ndjson => {
+                    println!(
+                        "{{\"id\": {}, \"manufacturer\": {}, \"chip\": {}}}",
+                        json_string(id.iter().map(|b| format!("{:02X}", b)).collect::<String>().as_str()),
+                        manufacturer.map_or("null".to_string(), json_string),
+                        chip.map_or("null".to_string(), json_string),
+                    );
+                }
+            }
+        }
+        Command::Device(DeviceCommand::Sync { detector_settings, count, interval }) => {
+            let mut device = detect_device(&detector_settings)?;
+            let mut round_trips = Vec::with_capacity(count.get());
+            let mut attempts = 0;
+
+            while attempts < count.get() && signals::received_signal().is_none() {
+                let start_time = Instant::now();
+                attempts += 1;
+
+                match device.sync() {
+                    Ok(()) => round_trips.push(start_time.elapsed()),
+                    Err(e) => eprintln!("Attempt {} failed: {:#}", attempts, e),
+                }
+
+                if attempts < count.get() {
+                    signals::sleep_unless_interrupted(interval.saturating_sub(start_time.elapsed()));
+                }
+            }
+
+            let min = round_trips.iter().min().copied().unwrap_or_default();
+            let max = round_trips.iter().max().copied().unwrap_or_default();
+            let avg = match round_trips.len() {
+                0 => Duration::ZERO,
+                n => round_trips.iter().sum::<Duration>() / n as u32,
+            };
+
+            match args.output_format {
+                OutputFormat::Text => {
+                    eprintln!("{} of {} synchronizations succeeded", round_trips.len(), attempts);
+
+                    if !round_trips.is_empty() {
+                        eprintln!("Round-trip time: min {:.3?}, avg {:.3?}, max {:.3?}", min, avg, max);
+                    }
+                }
+                OutputFormat::Json | OutputFormat::Ndjson => {
+                    println!(
+                        "{{\"attempts\": {}, \"succeeded\": {}, \"min_us\": {}, \"avg_us\": {}, \"max_us\": {}}}",
+                        attempts,
+                        round_trips.len(),
+                        min.as_micros(),
+                        avg.as_micros(),
+                        max.as_micros(),
+                    );
+                }
+            }
+
+            if round_trips.len() < attempts {
+                device.print_recent_info_messages();
+                return Err(CommandFailed.into());
+            }
+        }
+        Command::Device(DeviceCommand::EnableExternalControl { detector_settings }) => {
+            detect_device(&detector_settings)?.enable_external_control()?;
+        }
+        Command::Device(DeviceCommand::SelfTest {
+                            detector_settings,
+                            offset,
+                            size,
+                            external_control_settings,
+                        }) => {
+            let mut device = detect_device(&detector_settings)?;
+            let read_buffer_size = NonZeroU8::new(read_buffer_size_for_frame(device.serial_frame_size()))
+                .ok_or_else(|| anyhow!("Serial frame size is too small"))?;
+            let write_buffer_size = NonZeroU8::new(write_buffer_size_for_frame(device.serial_frame_size()))
+                .ok_or_else(|| anyhow!("Serial frame size is too small"))?;
+
+            match self_test(&mut device, offset, size, read_buffer_size, write_buffer_size) {
+                Ok(()) => {
+                    eprintln!("Self-test passed");
+                }
+                Err(e) => {
+                    eprintln!("Self-test failed: {:#}", e);
+                    device.print_recent_info_messages();
+                    return Err(CommandFailed.into());
+                }
+            }
+
+            external_control_settings.apply(&mut device)?;
+        }
+        Command::Capabilities { device: false, .. } => {
+            print_capabilities(args.output_format, None);
+        }
+        Command::Capabilities { device: true, detector_settings } => {
+            let device = detect_device(&detector_settings)?;
+
+            print_capabilities(args.output_format, Some(&device));
+        }
+        Command::Data { detector_settings, dump_protocol: true, endianness_settings, command, .. } => {
+            dump_data_command_protocol(detector_settings.device_settings(), endianness_settings.endianness, apply_range(*command))?;
+        }
+        Command::Data { detector_settings, progress, endianness_settings, command, .. } => {
+            let mut device = detect_device(&detector_settings)?;
+            let progress = Progress::new(progress, args.output_format);
+            let result = run_data_command(&mut device, apply_range(*command), endianness_settings.endianness, args.output_hex_case, &progress);
+
+            if result.is_err() {
+                device.print_recent_info_messages();
+            }
+
+            result?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Names of values of an option accepted by this program.
+fn value_names<T: ValueEnum>(include: impl Fn(&T) -> bool) -> Vec<String> {
+    T::value_variants().iter()
+        .filter(|value| include(value))
+        .filter_map(|value| value.to_possible_value())
+        .map(|value| value.get_name().to_string())
+        .collect()
+}
+
+fn print_capabilities(output_format: OutputFormat, device: Option<&Device>) {
+    let firmware_capabilities = |device: &Device| -> Vec<String> {
+        device.firmware_info()
+            .map(|firmware| firmware.capabilities.iter().map(|capability| capability.name().to_string()).collect())
+            .unwrap_or_default()
+    };
+    let lists = [
+        ("formats", "Formats", value_names::<DataFormat>(|format| *format != DataFormat::Auto)),
+        ("checksum_algorithms", "Checksum algorithms", value_names::<ChecksumAlgorithm>(|_| true)),
+        ("signature_algorithms", "Signature algorithms", value_names::<SignatureAlgorithm>(|_| true)),
+        ("transfer_modes", "Transfer modes", value_names::<TransferMode>(|_| true)),
+        (
+            "protocol_features",
+            "Protocol features",
+            Capability::ALL.iter().map(|capability| capability.name().to_string()).collect(),
+        ),
+    ];
+
+    match output_format {
+        OutputFormat::Text => {
+            for (_, title, names) in lists.iter() {
+                println!("{}: {}", title, names.join(", "));
+            }
+            if let Some(device) = device {
+                let capabilities = firmware_capabilities(device);
+
+                println!("Port: {}", device.name());
+                if capabilities.is_empty() {
+                    println!("Device capabilities: none");
+                } else {
+                    println!("Device capabilities: {}", capabilities.join(", "));
+                }
+            }
+        }
+        OutputFormat::Json | OutputFormat::Ndjson => {
+            let json_list = |names: &[String]| format!(
+                "[{}]",
+                names.iter().map(|name| json_string(name)).collect::<Vec<_>>().join(", "),
+            );
+            let mut fields: Vec<(&str, String)> = lists.iter()
+                .map(|(name, _, names)| (*name, json_list(names)))
+                .collect();
+
+            fields.push(("port", device.map_or("null".to_string(), |device| json_string(device.name()))));
+            fields.push((
+                "device_capabilities",
+                device.map_or("null".to_string(), |device| json_list(&firmware_capabilities(device))),
+            ));
+
+            let fields = fields.iter().map(|(name, value)| format!("{}: {}", json_string(name), value));
+
+            if output_format == OutputFormat::Ndjson {
+                println!("{{{}}}", fields.collect::<Vec<_>>().join(", "));
+            } else {
+                println!("{{\n  {}\n}}", fields.collect::<Vec<_>>().join(",\n  "));
+            }
+        }
+    }
+}
+
+/// Builds read request for a region of memory of 64KiB device.
+fn offline_read_request(offset: u16, size: Option<usize>, buffer_size: u8) -> Result<Option<DataReadRequest>> {
+    let size = size.unwrap_or(DEFAULT_MEMORY_SIZE - offset as usize);
+
+    if size.checked_add(offset as usize).is_none_or(|end| end > DEFAULT_MEMORY_SIZE) {
+        return Err(anyhow!("Region 0x{:04X}+0x{:X} exceeds 64KiB of device memory", offset, size));
+    }
+
+    let (size, buffer_size) = match (NonZeroUsize::new(size), NonZeroU8::new(buffer_size)) {
+        (None, _) => { return Ok(None); }
+        (_, None) => { return Err(anyhow!("Illegal buffer size")); }
+        (Some(size), Some(buffer_size)) => (size, buffer_size),
+    };
+
+    Ok(Some(DataReadRequest { offset, size, buffer_size, allow_short_read: false, reverse: false }))
+}
+
+/// Prints commands that would be sent to the device to execute a data command.
+fn dump_data_command_protocol(settings: &DeviceSettings, byte_order: ByteOrder, command: DataCommand) -> Result<()> {
+    let dialect = settings.protocol_dialect();
+    let default_read_buffer_size = read_buffer_size_for_frame(settings.serial_frame_size);
+    let default_write_buffer_size = write_buffer_size_for_frame(settings.serial_frame_size);
+    let mut commands = vec![];
+
+    match command {
+        DataCommand::Read { offset, size, buffer_size, reverse, .. } => {
+            if let Some(request) = offline_read_request(offset, size, buffer_size.unwrap_or(default_read_buffer_size))? {
+                commands.extend(read_commands(&DataReadRequest { reverse, ..request }, &dialect)?);
+            }
+        }
+        DataCommand::Sign { offset, input, format, address_bias, algorithm, slot_offset, buffer_size, verify, .. } => {
+            let (image, slot) = signed_image(offset, input, format, address_bias, algorithm, slot_offset, byte_order)?;
+            let buffer_size = NonZeroU8::new(buffer_size.unwrap_or(default_write_buffer_size))
+                .ok_or_else(|| anyhow!("Illegal buffer size"))?;
+
+            for chunk in [&image, &slot] {
+                commands.extend(write_commands(&DataWriteRequest { data: chunk, buffer_size, page_size: None }, &dialect));
+            }
+
+            if verify {
+                for chunk in [&image, &slot] {
+                    if let Some(request) = offline_read_request(chunk.offset, Some(chunk.data.len()), default_read_buffer_size)? {
+                        commands.extend(read_commands(&request, &dialect)?);
+                    }
+                }
+            }
+        }
+        DataCommand::WriteList { addresses, value, buffer_size, verify, .. } => {
+            let chunks = coalesce_addresses(read_address_list(addresses)?.as_slice(), value);
+            let buffer_size = NonZeroU8::new(buffer_size.unwrap_or(default_write_buffer_size))
+                .ok_or_else(|| anyhow!("Illegal buffer size"))?;
+
+            for chunk in chunks.iter() {
+                commands.extend(write_commands(&DataWriteRequest { data: chunk, buffer_size, page_size: None }, &dialect));
+            }
+
+            if verify {
+                for chunk in chunks.iter() {
+                    if let Some(request) = offline_read_request(chunk.offset, Some(chunk.data.len()), default_read_buffer_size)? {
+                        commands.extend(read_commands(&request, &dialect)?);
+                    }
+                }
+            }
+        }
+        DataCommand::MarchTest { offset, size, buffer_size, .. } => {
+            let chunk_size = buffer_size.unwrap_or(min(default_read_buffer_size, default_write_buffer_size));
+
+            if let Some(request) = offline_read_request(offset, size, chunk_size)? {
+                for (_, operation) in march_operations(request.offset, request.size, request.buffer_size) {
+                    match operation {
+                        MarchOperation::Verify(chunk) => {
+                            if let Some(request) = offline_read_request(chunk.offset, Some(chunk.data.len()), chunk_size)? {
+                                commands.extend(read_commands(&request, &dialect)?);
+                            }
+                        }
+                        MarchOperation::Write(chunk) => {
+                            commands.extend(write_commands(&DataWriteRequest { data: &chunk, buffer_size: request.buffer_size, page_size: None }, &dialect));
+                        }
+                    }
+                }
+            }
+        }
+        DataCommand::SetBits { offset, .. } => {
+            // Written value depends on the byte read from the device
+            if let Some(request) = offline_read_request(offset, Some(1), 1)? {
+                commands.extend(read_commands(&request, &dialect)?);
+            }
+        }
+        DataCommand::Stream { offset, size, buffer_size, .. } => {
+            // Every snapshot is read using the same commands
+            if let Some(request) = offline_read_request(offset, Some(size.get()), buffer_size.unwrap_or(default_read_buffer_size))? {
+                commands.extend(read_commands(&request, &dialect)?);
+            }
+        }
+        DataCommand::Checksum { offset, size, buffer_size, .. } => {
+            if let Some(request) = offline_read_request(offset, size, buffer_size.unwrap_or(default_read_buffer_size))? {
+                commands.extend(read_commands(&request, &dialect)?);
+            }
+        }
+        DataCommand::Verify { offset, size, input, format, address_bias, buffer_size, .. } => {
+            let (data_address, data) = read_input_data(input, format, address_bias)?;
+            let offset = input_data_offset(offset, data_address)?;
+            let size = size.map_or(data.len(), NonZeroUsize::get);
+
+            if let Some(request) = offline_read_request(offset, Some(size), buffer_size.unwrap_or(default_read_buffer_size))? {
+                commands.extend(read_commands(&request, &dialect)?);
+            }
+        }
+        DataCommand::Write {
+            offset,
+            input,
+            input_offset,
+            input_length,
+            format,
+            address_bias,
+            buffer_size,
+            page_size,
+            verify,
+            verification_read_buffer_size,
+            ..
+        } => {
+            let (data_address, data, _) = read_concatenated_input_data(input, format, address_bias)?;
+            let data = slice_input_data(data, input_offset, input_length)?;
+            let offset = input_data_offset(offset, data_address)?;
+            let buffer_size = NonZeroU8::new(buffer_size.unwrap_or(default_write_buffer_size))
+                .ok_or_else(|| anyhow!("Illegal buffer size"))?;
+
+            commands.extend(write_commands(&DataWriteRequest {
+                data: &DataChunk { offset, data: data.as_slice() },
+                buffer_size,
+                page_size,
+            }, &dialect));
+
+            if matches!(verify, Some(VerifyMode::Full)) {
+                let verification_read_buffer_size = verification_read_buffer_size.unwrap_or(BufferSize::Auto).resolve(default_read_buffer_size);
+
+                if let Some(request) = offline_read_request(offset, Some(data.len()), verification_read_buffer_size)? {
+                    commands.extend(read_commands(&request, &dialect)?);
+                }
+            }
+        }
+    }
+
+    for command in commands {
+        print!("{}", command);
+    }
+
+    Ok(())
+}
+
+fn run_data_command(
+    device: &mut Device,
+    command: DataCommand,
+    byte_order: ByteOrder,
+    output_hex_case: HexCase,
+    progress: &Progress,
+) -> Result<()> {
+    let default_read_buffer_size = read_buffer_size_for_frame(device.serial_frame_size());
+    let default_write_buffer_size = write_buffer_size_for_frame(device.serial_frame_size());
+
+    match command {
+        DataCommand::Read {
+            offset,
+            size,
+            range: _,
+            output,
+            format,
+            bytes_per_line,
+            array_name,
+            array_type,
+            asm_directive,
+            asm_label_prefix,
+            split,
+            mmap,
+            no_clobber,
+            append,
+            tee,
+            buffer_size,
+            skip_fill,
+            fill_byte,
+            on_error,
+            error_fill_byte,
+            allow_short_read,
+            reverse,
+            max_bytes,
+            baseline,
+            fail_on_empty,
+            external_control_settings,
+        } => {
+            let size = match region_size(device, offset, size)? {
+                None => {
+                    let message = match size {
+                        Some(_) => "Nothing to read: --size is 0".to_string(),
+                        None => format!("Nothing to read: offset 0x{:04X} equals memory size", offset),
+                    };
+
+                    if fail_on_empty {
+                        return Err(anyhow!(message));
+                    }
+
+                    eprintln!("Warning: {}", message);
+                    return Ok(());
+                }
+                Some(nzsz) => nzsz
+            };
+            let buffer_size = match NonZeroU8::new(buffer_size.unwrap_or(default_read_buffer_size)) {
+                Some(nz_bsz) => nz_bsz,
+                None => {
+                    return Err(anyhow!("Illegal buffer size"));
+                }
+            };
+
+            if let Some(baseline) = baseline {
+                let baseline = baseline_region(baseline, offset, size)?;
+                let mismatches = find_mismatches(device, &baseline, buffer_size)?;
+                let regions = changed_regions(mismatches.as_slice());
+                let output_mode = if no_clobber { OutputFileMode::CreateNew } else { OutputFileMode::Truncate };
+
+                write_patch(open_output_stream(output, output_mode)?, regions.as_slice())?;
+
+                eprintln!(
+                    "{} of {} bytes changed in {} regions",
+                    mismatches.len(),
+                    size,
+                    regions.len(),
+                );
+                progress.done(&[
+                    ("operation", json_string("read")),
+                    ("bytes", size.to_string()),
+                    ("changed_bytes", mismatches.len().to_string()),
+                    ("changed_regions", regions.len().to_string()),
+                ]);
+
+                return external_control_settings.apply(device);
+            }
+
+            let skipped_index_path = output.as_ref().map(|path| {
+                let mut index_path = path.clone().into_os_string();
+                index_path.push(".skipped");
+                PathBuf::from(index_path)
+            });
+            if progress.output_format == OutputFormat::Ndjson && (output.is_none() || tee) {
+                return Err(anyhow!("Data must be written to --output without --tee in NDJSON output format"));
+            }
+
+            let format = format.resolve(output.as_deref());
+            if format != DataFormat::Raw && (mmap || skip_fill || split.is_some()) {
+                return Err(anyhow!("--mmap, --skip-fill and --split can be used with raw output format only"));
+            }
+            if on_error == ReadErrorAction::Skip && (!matches!(format, DataFormat::IntelHex | DataFormat::Srec) || tee) {
+                return Err(anyhow!("--on-error skip can be used with Intel HEX and S-record output formats without --tee only"));
+            }
+
+            let output_mode = match (no_clobber, append) {
+                (true, _) => OutputFileMode::CreateNew,
+                (_, true) => OutputFileMode::Append,
+                _ => OutputFileMode::Truncate,
+            };
+            let mut skipped_regions: Vec<(usize, usize)> = vec![];
+            let sink: Box<dyn Write> = match (split, output) {
+                (Some(part_size), Some(path)) => Box::new(SplitFileWriter::new(path, part_size, output_mode)),
+                (None, Some(path)) if mmap => open_mmap_output_stream(path, size, output_mode)?,
+                (_, output) => open_output_stream(output, output_mode)?,
+            };
+            let mut stream: Box<dyn SparseWrite> = match format {
+                DataFormat::IntelHex => Box::new(IntelHexWriter::new(sink, offset as usize, output_hex_case)),
+                DataFormat::Srec => Box::new(SrecWriter::new(sink, offset as usize, output_hex_case)),
+                DataFormat::Hexdump => Box::new(DenseWriter(HexDumpWriter::new(sink, offset as usize, output_hex_case))),
+                DataFormat::AsmHex => Box::new(DenseWriter(AsmHexWriter::new(sink, offset as usize, AsmHexStyle {
+                    bytes_per_line,
+                    directive: asm_directive,
+                    label_prefix: asm_label_prefix,
+                    hex_case: output_hex_case,
+                }))),
+                DataFormat::CArray => Box::new(DenseWriter(CArrayWriter::new(sink, CArrayStyle {
+                    name: array_name,
+                    element_type: array_type,
+                    elements_per_line: bytes_per_line,
+                    hex_case: output_hex_case,
+                }))),
+                DataFormat::Raw | DataFormat::Auto => Box::new(DenseWriter(sink)),
+            };
+            if tee {
+                stream = Box::new(DenseWriter(MultiWriter::new(vec![
+                    stream,
+                    Box::new(HexDumpWriter::new(stdout(), offset as usize, output_hex_case)),
+                ])));
+            }
+
+            let mut total_read = 0;
+            let mut total_written = 0;
+            let mut failed_segments: Vec<FailedSegment> = vec![];
+
+            let mut consume = |chunk: std::result::Result<DataChunk<&[u8]>, FailedSegment>| -> Result<()> {
+                let fill_data;
+                let chunk = match chunk {
+                    Ok(chunk) => {
+                        total_read += chunk.data.len();
+                        chunk
+                    }
+                    Err(failed) if on_error == ReadErrorAction::Abort => {
+                        return Err(failed.error);
+                    }
+                    Err(failed) => {
+                        let (failed_offset, failed_size) = (failed.offset, failed.size);
+                        failed_segments.push(failed);
+
+                        if on_error == ReadErrorAction::Skip {
+                            stream.skip(failed_size)?;
+                            return Ok(());
+                        }
+
+                        fill_data = vec![error_fill_byte; failed_size];
+                        DataChunk { offset: failed_offset, data: fill_data.as_slice() }
+                    }
+                };
+
+                if skip_fill && chunk.data.iter().all(|b| *b == fill_byte) {
+                    match skipped_regions.last_mut() {
+                        Some((region_offset, region_length)) if *region_offset + *region_length == chunk.offset as usize => {
+                            *region_length += chunk.data.len();
+                        }
+                        _ => {
+                            skipped_regions.push((chunk.offset as usize, chunk.data.len()));
+                        }
+                    }
+                    return Ok(());
+                }
+
+                if let Some(max_bytes) = max_bytes.filter(|max_bytes| total_written + chunk.data.len() > *max_bytes) {
+                    stream.flush()?;
+                    return Err(anyhow!(
+                        "Output limit of {} bytes set by --max-bytes exceeded at 0x{:04X}",
+                        max_bytes,
+                        chunk.offset,
+                    ));
+                }
+
+                stream.write_all(chunk.data)?;
+                total_written += chunk.data.len();
+
+                Ok(())
+            };
+            let request = DataReadRequest {
+                offset,
+                size,
+                buffer_size,
+                allow_short_read,
+                reverse,
+            };
+            let show_progress = |done, total| progress.report("Reading", done, Some(total));
+
+            if reverse {
+                let mut chunks = vec![];
+
+                read_data_into(device, request, show_progress, |chunk| {
+                    chunks.push(DataChunk { offset: chunk.offset, data: chunk.data.to_vec() });
+                    Ok(())
+                })?;
+
+                // Chunks were read from the end, restore address order
+                for chunk in chunks.iter().rev() {
+                    consume(Ok(DataChunk { offset: chunk.offset, data: chunk.data.as_slice() }))?;
+                }
+            } else if let Err(e) = read_data_into_tolerant(device, request, show_progress, consume) {
+                stream.flush()?;
+                return Err(e.context(format!("Read failed after writing {} bytes of data to the output", total_written)));
+            }
+
+            if skip_fill {
+                if let Some(index_path) = skipped_index_path {
+                    write_skipped_regions_index(&index_path, skipped_regions.as_slice())?;
+                }
+            }
+
+            stream.flush()?;
+            let failed_bytes: usize = failed_segments.iter().map(|failed| failed.size).sum();
+
+            if !failed_segments.is_empty() {
+                eprintln!("Warning: {} of {} requested bytes could not be read:", failed_bytes, size);
+
+                for failed in failed_segments.iter() {
+                    eprintln!(
+                        "  0x{:04X}-0x{:04X}: {}",
+                        failed.offset,
+                        failed.offset as usize + failed.size - 1,
+                        failed.error.root_cause(),
+                    );
+                }
+            } else if total_read < size.get() {
+                eprintln!("Warning: device returned only {} of {} requested bytes", total_read, size);
+            }
+
+            progress.done(&[
+                ("operation", json_string("read")),
+                ("bytes", total_read.to_string()),
+                ("failed_bytes", failed_bytes.to_string()),
+            ]);
+
+            external_control_settings.apply(device)?;
+        }
+        DataCommand::Write {
+            input,
+            input_offset,
+            input_length,
+            format,
+            address_bias,
+            offset,
+            buffer_size,
+            page_size,
+            verify,
+            parallel_verify,
+            verify_only_written,
+            verify_sample_percent,
+            verify_sample_seed,
+            verification_read_buffer_size,
+            verify_delay,
+            max_rewrites,
+            diff_output,
+            repeat,
+            keep_going,
+            yes,
+            stream,
+            fail_on_empty,
+            external_control_settings,
+        } => {
+            let buffer_size = match NonZeroU8::new(buffer_size.unwrap_or(default_write_buffer_size)) {
+                None => {
+                    return Err(anyhow!("Illegal buffer size"));
+                }
+                Some(bsz) => bsz,
+            };
+            let verification_read_buffer_size = match NonZeroU8::new(
+                verification_read_buffer_size.unwrap_or(BufferSize::Auto).resolve(default_read_buffer_size)
+            ) {
+                None => {
+                    return Err(anyhow!("Illegal verification buffer size"));
+                }
+                Some(bsz) => bsz,
+            };
+
+            // Checked by clap only partially, and Config may be constructed directly
+            if diff_output.is_some() {
+                if !matches!(verify, Some(VerifyMode::Full)) {
+                    return Err(anyhow!("--diff-output requires --verify=full"));
+                }
+                if parallel_verify {
+                    return Err(anyhow!("--diff-output cannot be used with --parallel-verify"));
+                }
+            }
+
+            if stream {
+                if resolve_input_format(input.as_slice(), format)? != DataFormat::Raw {
+                    return Err(anyhow!("--stream can be used with raw input format only"));
+                }
+                if address_bias != 0 {
+                    return Err(anyhow!("{}", ADDRESS_BIAS_RAW_ERROR));
+                }
+
+                let mut input = open_concatenated_input_stream(input)?;
+                let skipped = std::io::copy(&mut (&mut input).take(input_offset as u64), &mut std::io::sink())?;
+                if skipped < input_offset as u64 {
+                    return Err(anyhow!("--input-offset of {} bytes is beyond the end of input ({} bytes)", input_offset, skipped));
+                }
+                let mut input = input.take(input_length.map_or(u64::MAX, |length| length as u64));
+
+                let written_size = write_stream(device, offset, &mut input, buffer_size, page_size, |done| {
+                    progress.report("Writing", done, None)
+                })?;
+
+                progress.finish();
+
+                if written_size == 0 {
+                    if fail_on_empty {
+                        return Err(anyhow!("Empty input data file or stream provided. Nothing was written"));
+                    }
+
+                    eprintln!("Empty input data file or stream provided. Nothing was written.");
+                }
+
+                progress.done(&[("operation", json_string("write")), ("bytes", written_size.to_string())]);
+                external_control_settings.apply(device)?;
+                return Ok(());
+            }
+
+            // Standard input can't be used to answer the question when data is read from it
+            let can_confirm = !input.is_empty();
+            let (data_address, data, regions) = read_concatenated_input_data(input, format, address_bias)?;
+            let data = slice_input_data(data, input_offset, input_length)?;
+            let offset = input_data_offset(offset, data_address)?;
+
+            if data.is_empty() {
+                if fail_on_empty {
+                    return Err(anyhow!("Empty input data file or stream provided. Nothing was written"));
+                }
+
+                eprintln!("Empty input data file or stream provided. Exiting without writing anything.");
+                return Ok(());
+            }
+
+            check_write_bounds(device, offset, data.len())?;
+
+            if !yes && can_confirm && data.len() > CONFIRMATION_SIZE_THRESHOLD {
+                let question = format!(
+                    "About to overwrite 0x{:04X}-0x{:04X}, continue?",
+                    offset,
+                    offset as usize + data.len() - 1,
+                );
+
+                if !confirm(&question)? {
+                    return Err(anyhow!("Cancelled by user"));
+                }
+            }
+
+            let written = DataChunk {
+                data: data.as_slice(),
+                offset,
+            };
+            let verified: Vec<DataChunk<&[u8]>> = if verify_only_written {
+                if matches!(verify, Some(VerifyMode::Heal)) {
+                    return Err(anyhow!("--verify-only-written cannot be used with --verify=heal"));
+                }
+
+                slice_input_regions(regions.as_slice(), input_offset, data.len())
+                    .into_iter()
+                    .map(|(start, length)| DataChunk { offset: offset + start as u16, data: &data[start..start + length] })
+                    .collect()
+            } else {
+                vec![DataChunk { offset, data: data.as_slice() }]
+            };
+
+            let write_and_verify = |device: &mut Device| -> Result<()> {
+                if matches!(verify, Some(VerifyMode::Heal)) {
+                    let rewritten_chunks = write_data_healing(device, DataWriteRequest {
+                        data: &written,
+                        buffer_size,
+                        page_size,
+                    }, verification_read_buffer_size, max_rewrites, verify_delay, |done, total| {
+                        progress.report("Writing", done, Some(total))
+                    })?;
+
+                    eprintln!("Written data verified, {} chunks needed rewriting", rewritten_chunks);
+                    return Ok(());
+                }
+
+                write_data_with_progress(device, DataWriteRequest {
+                    data: &written,
+                    buffer_size,
+                    page_size,
+                }, |done, total| progress.report("Writing", done, Some(total)))?;
+
+                if verify.is_some() && !verify_delay.is_zero() {
+                    sleep(verify_delay);
+                }
+
+                match verify {
+                    None | Some(VerifyMode::Heal) => {}
+                    Some(VerifyMode::Full) => {
+                        eprintln!("Verifying written data...");
+
+                        match diff_output.as_ref() {
+                            None => for chunk in verified.iter() {
+                                if parallel_verify {
+                                    verify_region_parallel(device, chunk, verification_read_buffer_size)?;
+                                } else {
+                                    verify_region(device, chunk, verification_read_buffer_size)?;
+                                }
+                            },
+                            Some(diff_path) => {
+                                let mut mismatches = vec![];
+                                for chunk in verified.iter() {
+                                    mismatches.extend(find_mismatches(device, chunk, verification_read_buffer_size)?);
+                                }
+
+                                let diff_file = File::create(diff_path)?;
+
+                                if diff_path.extension().is_some_and(|ext| ext == "json") {
+                                    write_mismatches_json(diff_file, mismatches.as_slice())?;
+                                } else {
+                                    write_mismatches_csv(diff_file, mismatches.as_slice())?;
+                                }
+
+                                if !mismatches.is_empty() {
+                                    return Err(anyhow!(
+                                        "Verification failed: {} bytes differ. See {} for details",
+                                        mismatches.len(),
+                                        diff_path.display(),
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                    Some(VerifyMode::Sample) => {
+                        let seed = verify_sample_seed.unwrap_or_else(|| {
+                            SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+                        });
+
+                        eprintln!(
+                            "Verifying a sample of {}% of written data (seed {}). Note: this is weaker than full verification.",
+                            verify_sample_percent,
+                            seed,
+                        );
+
+                        let (mut checked, mut total) = (0, 0);
+                        for chunk in verified.iter() {
+                            let (chunk_checked, chunk_total) = verify_region_sample(
+                                device,
+                                chunk,
+                                verification_read_buffer_size,
+                                verify_sample_percent,
+                                seed,
+                            )?;
+
+                            checked += chunk_checked;
+                            total += chunk_total;
+                        }
+
+                        eprintln!("Sample verification passed: {} of {} chunks checked", checked, total);
+                    }
+                }
+
+                Ok(())
+            };
+
+            if repeat.get() == 1 {
+                write_and_verify(device)?;
+            } else {
+                let start_time = Instant::now();
+                let mut succeeded = 0;
+                let mut first_failure = None;
+
+                for iteration in 1..=repeat.get() {
+                    eprintln!("Iteration {} of {}", iteration, repeat);
+
+                    match write_and_verify(device) {
+                        Ok(()) => {
+                            succeeded += 1;
+                        }
+                        Err(e) => {
+                            eprintln!("Iteration {} failed: {:#}", iteration, e);
+                            first_failure.get_or_insert(iteration);
+
+                            if !keep_going {
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                eprintln!(
+                    "{} of {} iterations succeeded in {}",
+                    succeeded,
+                    repeat,
+                    humantime::format_duration(Duration::from_millis(start_time.elapsed().as_millis() as u64)),
+                );
+
+                if let Some(iteration) = first_failure {
+                    return Err(anyhow!("First failure at iteration {}", iteration));
+                }
+            }
+
+            progress.done(&[("operation", json_string("write")), ("bytes", data.len().to_string())]);
+            external_control_settings.apply(device)?;
+        }
+        DataCommand::Verify {
+            offset,
+            size,
+            range: _,
+            input,
+            format,
+            address_bias,
+            buffer_size,
+            external_control_settings,
+        } => {
+            let buffer_size = match NonZeroU8::new(buffer_size.unwrap_or(default_read_buffer_size)) {
+                None => {
+                    return Err(anyhow!("Illegal buffer size"));
+                }
+                Some(bsz) => bsz,
+            };
+
+            let compared = match format.resolve_input(input.as_deref()) {
+                DataFormat::Raw if address_bias != 0 => {
+                    return Err(anyhow!("{}", ADDRESS_BIAS_RAW_ERROR));
+                }
+                DataFormat::Raw => {
+                    let mut reference = open_input_stream(input)?;
+
+                    eprintln!("Verifying data...");
+                    verify_stream(device, offset, size, &mut reference, buffer_size)?
+                }
+                format => {
+                    let (data_address, data) = read_input_data(input, format, address_bias)?;
+                    let offset = input_data_offset(offset, data_address)?;
+
+                    eprintln!("Verifying data...");
+                    verify_stream(device, offset, size, &mut data.as_slice(), buffer_size)?
+                }
+            };
+            if compared == 0 {
+                return Err(anyhow!("Input is empty"));
+            }
+            eprintln!("Data matches (0x{:X} bytes compared)", compared);
+            progress.done(&[("operation", json_string("verify")), ("bytes", compared.to_string())]);
+
+            external_control_settings.apply(device)?;
+        }
+        DataCommand::Checksum {
+            offset,
+            size,
+            range: _,
+            algorithm,
+            buffer_size,
+            external_control_settings,
+        } => {
+            let size = region_size(device, offset, size)?
+                .ok_or_else(|| anyhow!("Cannot compute checksum of an empty region"))?;
+            let buffer_size = NonZeroU8::new(buffer_size.unwrap_or(default_read_buffer_size))
+                .ok_or_else(|| anyhow!("Illegal buffer size"))?;
+            let mut checksum = Checksum::new(algorithm);
+
+            read_data_into(device, DataReadRequest {
+                offset,
+                size,
+                buffer_size,
+                allow_short_read: false,
+                reverse: false,
+            }, |done, total| progress.report("Reading", done, Some(total)), |chunk| {
+                checksum.update(chunk.data);
+                Ok(())
+            })?;
+
+            let checksum = checksum.finish();
+            if progress.output_format == OutputFormat::Ndjson {
+                progress.done(&[
+                    ("operation", json_string("checksum")),
+                    ("bytes", size.to_string()),
+                    ("checksum", json_string(checksum.as_str())),
+                ]);
+            } else {
+                println!("{}", checksum);
+            }
+
+            external_control_settings.apply(device)?;
+        }
+        DataCommand::Sign {
+            offset,
+            input,
+            format,
+            address_bias,
+            algorithm,
+            slot_offset,
+            buffer_size,
+            verify,
+            external_control_settings,
+        } => {
+            let buffer_size = NonZeroU8::new(buffer_size.unwrap_or(default_write_buffer_size))
+                .ok_or_else(|| anyhow!("Illegal buffer size"))?;
+            let read_buffer_size = NonZeroU8::new(default_read_buffer_size)
+                .ok_or_else(|| anyhow!("Serial frame size is too small"))?;
+            let (image, slot) = signed_image(offset, input, format, address_bias, algorithm, slot_offset, byte_order)?;
+
+            check_write_bounds(device, image.offset, image.data.len())?;
+            check_write_bounds(device, slot.offset, slot.data.len())?;
+
+            write_data_with_progress(device, DataWriteRequest {
+                data: &image,
+                buffer_size,
+                page_size: None,
+            }, |done, total| progress.report("Writing", done, Some(total)))?;
+            write_data(device, DataWriteRequest {
+                data: &slot,
+                buffer_size,
+                page_size: None,
+            })?;
+
+            eprintln!(
+                "Checksum {} written at 0x{:04X}",
+                slot.data.iter().map(|b| format!("{:02X}", b)).collect::<String>(),
+                slot.offset,
+            );
+
+            if verify {
+                eprintln!("Verifying written data...");
+
+                // Part of the image may have been overwritten by the checksum
+                let mut expected_image = image.data.clone();
+                for (i, b) in slot.data.iter().enumerate() {
+                    if let Some(image_byte) = expected_image.get_mut((slot.offset - image.offset) as usize + i) {
+                        *image_byte = *b;
+                    }
+                }
+
+                verify_region(device, &DataChunk { offset: image.offset, data: expected_image }, read_buffer_size)?;
+                verify_region(device, &slot, read_buffer_size)?;
+            }
+
+            progress.done(&[("operation", json_string("sign")), ("bytes", image.data.len().to_string())]);
+            external_control_settings.apply(device)?;
+        }
+        DataCommand::WriteList {
+            addresses,
+            value,
+            buffer_size,
+            verify,
+            external_control_settings,
+        } => {
+            let chunks = coalesce_addresses(read_address_list(addresses)?.as_slice(), value);
+            let buffer_size = NonZeroU8::new(buffer_size.unwrap_or(default_write_buffer_size))
+                .ok_or_else(|| anyhow!("Illegal buffer size"))?;
+            let read_buffer_size = NonZeroU8::new(default_read_buffer_size)
+                .ok_or_else(|| anyhow!("Serial frame size is too small"))?;
+            let total: usize = chunks.iter().map(|chunk| chunk.data.len()).sum();
+            let mut done = 0;
+
+            for chunk in chunks.iter() {
+                check_write_bounds(device, chunk.offset, chunk.data.len())?;
+            }
+
+            for chunk in chunks.iter() {
+                write_data(device, DataWriteRequest { data: chunk, buffer_size, page_size: None })?;
+                done += chunk.data.len();
+                progress.report("Writing", done, Some(total));
+            }
+            progress.finish();
+
+            eprintln!("Written 0x{:02X} to {} addresses in {} regions", value, total, chunks.len());
+
+            if verify {
+                eprintln!("Verifying written data...");
+
+                for chunk in chunks.iter() {
+                    verify_region(device, chunk, read_buffer_size)?;
+                }
+            }
+
+            progress.done(&[("operation", json_string("write-list")), ("bytes", total.to_string())]);
+            external_control_settings.apply(device)?;
+        }
+        DataCommand::MarchTest {
+            offset,
+            size,
+            range: _,
+            buffer_size,
+            yes,
+            external_control_settings,
+        } => {
+            let size = region_size(device, offset, size)?
+                .ok_or_else(|| anyhow!("Cannot test an empty region"))?;
+            let chunk_size = NonZeroU8::new(buffer_size.unwrap_or(min(default_read_buffer_size, default_write_buffer_size)))
+                .ok_or_else(|| anyhow!("Illegal buffer size"))?;
+
+            if !yes {
+                let question = format!(
+                    "March test overwrites 0x{:04X}-0x{:04X}, continue?",
+                    offset,
+                    offset as usize + size.get() - 1,
+                );
+
+                if !confirm(&question)? {
+                    return Err(anyhow!("Cancelled by user"));
+                }
+            }
+
+            march_test(device, offset, size, chunk_size, |done, total| progress.report("Testing", done, Some(total)))?;
+
+            eprintln!("March test passed");
+            progress.done(&[("operation", json_string("march-test")), ("bytes", size.to_string())]);
+            external_control_settings.apply(device)?;
+        }
+        DataCommand::SetBits {
+            offset,
+            mask,
+            value,
+            verify,
+            external_control_settings,
+        } => {
+            let (original, modified) = modify_byte(device, offset, mask, value, verify)?;
+
+            if progress.output_format == OutputFormat::Ndjson {
+                progress.done(&[
+                    ("operation", json_string("set-bits")),
+                    ("offset", offset.to_string()),
+                    ("original", original.to_string()),
+                    ("written", modified.to_string()),
+                ]);
+            } else {
+                eprintln!("0x{:04X}: 0x{:02X} -> 0x{:02X}", offset, original, modified);
+            }
+
+            external_control_settings.apply(device)?;
+        }
+        DataCommand::Stream {
+            offset,
+            size,
+            interval,
+            count,
+            output,
+            rotate,
+            buffer_size,
+        } => {
+            let buffer_size = NonZeroU8::new(buffer_size.unwrap_or(default_read_buffer_size))
+                .ok_or_else(|| anyhow!("Illegal buffer size"))?;
+            let request = DataReadRequest {
+                offset,
+                size,
+                buffer_size,
+                allow_short_read: false,
+                reverse: false,
+            };
+            let mut out: Box<dyn Write> = match (rotate, output) {
+                (Some(rotation), Some(path)) => Box::new(RotatingFileWriter::new(path, rotation)),
+                (None, path) => open_output_stream(path, OutputFileMode::Truncate)?,
+                // Checked by clap, but Config may be constructed directly
+                (Some(_), None) => {
+                    return Err(anyhow!("--rotate requires --output"));
+                }
+            };
+            let mut taken = 0;
+
+            while signals::received_signal().is_none() && count.is_none_or(|count| taken < count.get()) {
+                let start_time = Instant::now();
+                writeln!(out, "# {}", humantime::format_rfc3339_millis(SystemTime::now()))?;
+
+                let mut dump = HexDumpWriter::new(&mut out, offset as usize, output_hex_case);
+                read_data_into(device, request, |_, _| {}, |chunk| {
+                    dump.write_all(chunk.data)?;
+                    Ok(())
+                })?;
+                dump.flush()?;
+                drop(dump);
+
+                taken += 1;
+                if count.is_none_or(|count| taken < count.get()) {
+                    signals::sleep_unless_interrupted(interval.saturating_sub(start_time.elapsed()));
+                }
+            }
+
+            // Keeps the last, possibly partially filled, file when interrupted
+            out.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{open_mock_device, MockPort, MOCK_PORT_NAME};
+
+    #[test]
+    fn offset_beyond_address_space_is_rejected() {
+        for offset in ["0x20000", "131072"] {
+            let result = Config::try_parse_from(["romectl", "data", "--mock-device", "read", "--offset", offset]);
+
+            assert!(result.is_err(), "offset {} was accepted", offset);
+        }
+    }
+
+    #[test]
+    fn huge_read_size_is_rejected() {
+        let size = usize::MAX.to_string();
+        let config = Config::try_parse_from(["romectl", "data", "--mock-device", "read", "--offset", "16", "--size", size.as_str()]).unwrap();
+
+        assert!(run(config).is_err());
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("romectl-cli-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn diff_output_requires_full_verification() {
+        let result = Config::try_parse_from(["romectl", "data", "--mock-device", "write", "--diff-output", "diff.csv"]);
+        assert!(result.is_err());
+
+        // Combinations rejected by clap can still be constructed directly
+        for (verify, parallel) in [("--verify=heal", false), ("--verify=sample", false), ("--verify", true)] {
+            let mut config = Config::try_parse_from(["romectl", "data", "--mock-device", "write", verify, "--input", "in.bin"]).unwrap();
+            let Command::Data { command, .. } = &mut config.command else { unreachable!() };
+            let DataCommand::Write { diff_output, parallel_verify, .. } = command.as_mut() else { unreachable!() };
+            *diff_output = Some(PathBuf::from("diff.csv"));
+            *parallel_verify = parallel;
+
+            let err = run(config).unwrap_err();
+            assert!(err.to_string().contains("--diff-output"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn diff_output_is_written_when_verification_passes() {
+        let input_path = temp_path("diff-input.bin");
+        let diff_path = temp_path("diff.csv");
+        std::fs::write(&input_path, [0xAA; 16]).unwrap();
+        std::fs::write(&diff_path, "stale").unwrap();
+
+        let config = Config::try_parse_from([
+            "romectl", "data", "--mock-device", "write", "-y", "--verify",
+            "--input", input_path.to_str().unwrap(),
+            "--diff-output", diff_path.to_str().unwrap(),
+        ]).unwrap();
+        let result = run(config);
+        let diff = std::fs::read_to_string(&diff_path);
+        std::fs::remove_file(&input_path).unwrap();
+        std::fs::remove_file(&diff_path).unwrap();
+
+        result.unwrap();
+        assert_eq!(diff.unwrap(), "address,expected,actual\n");
+    }
+
+    #[test]
+    fn march_test_runs_without_confirmation_with_yes() {
+        let config = Config::try_parse_from(["romectl", "data", "--mock-device", "march-test", "--size", "256", "-y"]).unwrap();
+
+        run(config).unwrap();
+    }
+
+    #[test]
+    fn default_region_size_is_rest_of_memory() {
+        let mut device = open_mock_device(&DeviceSettings::default()).unwrap();
+
+        assert_eq!(region_size(&mut device, 0, None).unwrap(), NonZeroUsize::new(0x10000));
+        assert_eq!(region_size(&mut device, 0xFFFF, None).unwrap(), NonZeroUsize::new(1));
+        assert_eq!(region_size(&mut device, 0x10, Some(0x20)).unwrap(), NonZeroUsize::new(0x20));
+    }
+
+    #[test]
+    fn offset_beyond_device_memory_is_rejected() {
+        let mut port = MockPort::new();
+        port.set_memory_size(0x8000);
+        let mut device = Device::with_port(MOCK_PORT_NAME, Box::new(port), &DeviceSettings::default());
+        device.check().unwrap();
+
+        assert_eq!(region_size(&mut device, 0x7000, None).unwrap(), NonZeroUsize::new(0x1000));
+
+        let err = region_size(&mut device, 0x9000, None).unwrap_err();
+        assert!(err.to_string().contains("outside of device memory"), "{}", err);
+    }
+}
@@ -1,114 +1,1433 @@
 use std::cmp::min;
+use std::fmt::{Display, Formatter};
 use std::num::{NonZeroU8, NonZeroUsize};
-use std::str::from_utf8;
-use std::io::Write;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::io::{ErrorKind, Read};
 use anyhow::{anyhow, Context, Result};
-use crate::device::Device;
+use tracing::trace_span;
+use crate::checksum::crc8;
+use crate::device::{Capability, Device};
+use crate::metrics;
+use crate::protocol::{decode_hex_into, read_response_payload, HexCase, ProtocolDialect, WriteResponse, ADDRESS_SPACE_SIZE};
+use crate::signals::received_signal;
 
-// (64 bytes of arduino read buffer - 'R' - '\n') / 2 digits per byte of data
-pub const DEFAULT_READ_BUFFER_SIZE: u8 = (64 - 2) / 2;
+// Size of arduino's default serial receive buffer
+pub const DEFAULT_SERIAL_FRAME_SIZE: usize = 64;
 
-// (64 bytes - 'W' - '\n' - 4 address digits) / 2 digits per byte of data
-pub const DEFAULT_WRITE_BUFFER_SIZE: u8 = (64 - 2 - 4) / 2;
+pub const DEFAULT_READ_BUFFER_SIZE: u8 = read_buffer_size_for_frame(DEFAULT_SERIAL_FRAME_SIZE);
 
+pub const DEFAULT_WRITE_BUFFER_SIZE: u8 = write_buffer_size_for_frame(DEFAULT_SERIAL_FRAME_SIZE);
+
+/// Computes largest read buffer size safe to use with given device serial buffer size.
+pub const fn read_buffer_size_for_frame(frame_size: usize) -> u8 {
+    // (frame size - 'R' - '\n') / 2 digits per byte of data
+    let size = frame_size.saturating_sub(2) / 2;
+
+    if size > u8::MAX as usize { u8::MAX } else { size as u8 }
+}
+
+/// Computes largest write buffer size safe to use with given device serial buffer size.
+pub const fn write_buffer_size_for_frame(frame_size: usize) -> u8 {
+    // (frame size - 'W' - '\n' - 4 address digits) / 2 digits per byte of data
+    let size = frame_size.saturating_sub(2 + 4) / 2;
+
+    if size > u8::MAX as usize { u8::MAX } else { size as u8 }
+}
+
+#[derive(Debug)]
 pub struct DataChunk<T: AsRef<[u8]>> {
     pub offset: u16,
     pub data: T,
 }
 
-#[derive(Copy, Clone)]
+/// Number of bytes shown by [DataChunk]'s [Display] implementation.
+const DATA_CHUNK_PREVIEW_SIZE: usize = 8;
+
+impl<T: AsRef<[u8]>> Display for DataChunk<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let data = self.data.as_ref();
+
+        write!(f, "{} bytes at 0x{:04X}:", data.len(), self.offset)?;
+
+        for b in data.iter().take(DATA_CHUNK_PREVIEW_SIZE) {
+            write!(f, " {:02X}", b)?;
+        }
+
+        if data.len() > DATA_CHUNK_PREVIEW_SIZE {
+            write!(f, " ...")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
 pub struct DataReadRequest {
     pub offset: u16,
     pub size: NonZeroUsize,
     pub buffer_size: NonZeroU8,
+    /// Accept less data than requested in response to the last read command.
+    ///
+    /// Some firmware returns a short read at the end of addressable memory.
+    pub allow_short_read: bool,
+    /// Read chunks starting from the highest address.
+    ///
+    /// Chunks are returned in the order they are read.
+    pub reverse: bool,
 }
 
 pub fn read_data<'a>(
     device: &'a mut Device,
     request: DataReadRequest,
 ) -> Result<impl Iterator<Item=Result<DataChunk<Vec<u8>>>> + 'a> {
-    if (request.size.get() + request.offset as usize) > device.memory_size()? {
-        return Err(anyhow!("Last requested byte address is outside of device address range (offset + size - 1 > total memory size)"));
-    }
+    read_data_with_progress(device, request, |_, _| {})
+}
 
-    let num_segments = request.size.get().div_ceil(request.buffer_size.get().into()) as u16;
+/// Same as [read_data] but calls `progress` with number of bytes read so far and total number of
+/// bytes to read after each received chunk.
+pub fn read_data_with_progress<'a, F: FnMut(usize, usize) + 'a>(
+    device: &'a mut Device,
+    request: DataReadRequest,
+    mut progress: F,
+) -> Result<impl Iterator<Item=Result<DataChunk<Vec<u8>>>> + 'a> {
+    let num_segments = check_read_request(device, &request)?;
+    let mut response = vec![];
+    let mut done = 0;
 
-    Ok((0..num_segments)
+    Ok(segment_numbers(num_segments, request.reverse)
         .map(move |segment_number| {
-            let segment_start_address = request.offset + segment_number * (request.buffer_size.get() as u16);
-            let remaining_size = request.size.get() - (segment_start_address - request.offset) as usize;
-            let segment_size: u8 = min::<usize>(request.buffer_size.get().into(), remaining_size) as u8;
+            let mut data = vec![];
+            let offset = read_segment(device, &request, segment_number, num_segments, &mut response, &mut data)
+                .map_err(|e| read_segment_error(e, &request, segment_number, done))?;
 
-            device.send(format!("R{:04X}{:02X}\n", segment_start_address, segment_size).as_bytes())?;
-            let response = device.receive(2 + (segment_size as usize) * 2)?;
+            done += data.len();
+            progress(done, request.size.get());
 
-            if !response.as_slice().starts_with(b"R") {
-                return Err(anyhow!(
-                    "Received unexpected response to 'R' command: '{}'",
-                    String::from_utf8_lossy(response.as_slice()),
-                ));
+            Ok(DataChunk { offset, data })
+        }))
+}
+
+/// Same as [read_data_with_progress] but passes received chunks to `consume` instead of returning
+/// an iterator.
+///
+/// Chunks borrow a single buffer reused for the whole request, so no memory is allocated per chunk.
+pub fn read_data_into<P, C>(
+    device: &mut Device,
+    request: DataReadRequest,
+    progress: P,
+    mut consume: C,
+) -> Result<()>
+where
+    P: FnMut(usize, usize),
+    C: FnMut(DataChunk<&[u8]>) -> Result<()>,
+{
+    read_data_into_tolerant(device, request, progress, |chunk| match chunk {
+        Ok(chunk) => consume(chunk),
+        Err(failed) => Err(failed.error),
+    })
+}
+
+/// A part of read request that could not be read.
+#[derive(Debug)]
+pub struct FailedSegment {
+    pub offset: u16,
+    pub size: usize,
+    pub error: anyhow::Error,
+}
+
+/// Same as [read_data_into] but passes segments that could not be read to `consume` instead of
+/// failing.
+///
+/// If `consume` accepts a failed segment, the stream is re-synchronized with the device and reading
+/// continues with the next segment. Interruption by a signal always stops reading.
+pub fn read_data_into_tolerant<P, C>(
+    device: &mut Device,
+    request: DataReadRequest,
+    mut progress: P,
+    mut consume: C,
+) -> Result<()>
+where
+    P: FnMut(usize, usize),
+    C: FnMut(std::result::Result<DataChunk<&[u8]>, FailedSegment>) -> Result<()>,
+{
+    let num_segments = check_read_request(device, &request)?;
+    let mut response = Vec::with_capacity(2 + 2 * request.buffer_size.get() as usize);
+    let mut data = Vec::with_capacity(request.buffer_size.get() as usize);
+
+    let mut done = 0;
+
+    for segment_number in segment_numbers(num_segments, request.reverse) {
+        match read_segment(device, &request, segment_number, num_segments, &mut response, &mut data) {
+            Ok(offset) => {
+                done += data.len();
+                progress(done, request.size.get());
+
+                consume(Ok(DataChunk { offset, data: data.as_slice() }))?;
             }
+            Err(e) => {
+                let error = read_segment_error(e, &request, segment_number, done);
+                if received_signal().is_some() {
+                    return Err(error);
+                }
 
-            let response_payload = &response.as_slice()[1..];
+                let (offset, size) = read_segment_bounds(&request, segment_number);
+                consume(Err(FailedSegment { offset, size: size as usize, error }))?;
 
-            if response_payload.len() != 2 * (segment_size as usize) {
-                return Err(anyhow!(
-                    "Received payload of unexpected length ({} instead of {})",
-                    response_payload.len(),
-                    segment_size * 2,
-                ));
+                done += size as usize;
+                progress(done, request.size.get());
+
+                device.sync().context("Error re-synchronizing with device after failed read")?;
             }
+        }
+    }
+
+    Ok(())
+}
 
-            Ok(DataChunk {
-                offset: segment_start_address,
-                data: response_payload
-                    .chunks(2)
-                    .map(|chunk| {
-                        assert_eq!(chunk.len(), 2);
+/// Adds location of a failed segment and progress of the request to an error.
+fn read_segment_error(error: anyhow::Error, request: &DataReadRequest, segment_number: usize, done: usize) -> anyhow::Error {
+    let (address, size) = read_segment_bounds(request, segment_number);
 
-                        Ok(u8::from_str_radix(from_utf8(chunk)?, 16)?)
-                    })
-                    .collect::<Result<Vec<u8>>>()
-                    .context("Error parsing response payload")?,
-            })
-        }))
+    error.context(format!(
+        "Error reading {} bytes at 0x{:04X}: {} of {} bytes were read successfully, {} remaining",
+        size,
+        address,
+        done,
+        request.size,
+        request.size.get() - done,
+    ))
+}
+
+/// Returns numbers of segments of a read request in order they should be read.
+fn segment_numbers(num_segments: usize, reverse: bool) -> impl Iterator<Item=usize> {
+    (0..num_segments).map(move |i| if reverse { num_segments - 1 - i } else { i })
+}
+
+/// Returns address of the first byte and size of given segment of a read request.
+fn read_segment_bounds(request: &DataReadRequest, segment_number: usize) -> (u16, u8) {
+    let segment_offset = segment_number * request.buffer_size.get() as usize;
+    let segment_start_address = request.offset as usize + segment_offset;
+    let remaining_size = request.size.get() - segment_offset;
+    let segment_size: u8 = min::<usize>(request.buffer_size.get().into(), remaining_size) as u8;
+
+    // Requests are checked by read_segment_count to stay within 16-bit address space
+    (segment_start_address as u16, segment_size)
+}
+
+/// Formats command reading `size` bytes starting at `address`.
+pub fn read_command_text(read_command: char, address: u16, size: u8, hex_case: HexCase) -> String {
+    format!("{}{}{}\n", read_command, hex_case.digits(address, 4), hex_case.digits(size, 2))
+}
+
+/// Returns commands that would be sent to perform given read request, without sending them.
+///
+/// Unlike [read_data], doesn't check the request against device memory size.
+pub fn read_commands(request: &DataReadRequest, dialect: &ProtocolDialect) -> Result<Vec<String>> {
+    let num_segments = read_segment_count(request)?;
+
+    Ok(segment_numbers(num_segments, request.reverse)
+        .map(|segment_number| {
+            let (address, size) = read_segment_bounds(request, segment_number);
+            read_command_text(dialect.read, address, size, dialect.hex_case)
+        })
+        .collect())
+}
+
+/// Suffix of read command requesting CRC of the response data.
+const CHECKED_READ_SUFFIX: char = 'C';
+
+/// Number of times a chunk is read before giving up when its CRC doesn't match.
+const CHECKED_READ_ATTEMPTS: usize = 3;
+
+/// CRC received in response to a checked read command doesn't match the received data.
+#[derive(Debug)]
+pub struct ReadCrcMismatch {
+    pub address: u16,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+impl Display for ReadCrcMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "CRC mismatch in data read at 0x{:04X}: device sent {:02X}, received data has {:02X}",
+            self.address,
+            self.expected,
+            self.actual,
+        )
+    }
+}
+
+impl std::error::Error for ReadCrcMismatch {}
+
+/// Validates a read request and returns number of read commands needed to complete it.
+fn check_read_request(device: &mut Device, request: &DataReadRequest) -> Result<usize> {
+    if device.checked_reads() {
+        device.require(Capability::ReadCrc, "checked reads")?;
+    }
+
+    let memory_size = device.memory_size()?;
+
+    if request.size.get().checked_add(request.offset as usize).is_none_or(|end| end > memory_size) {
+        return Err(anyhow!("Last requested byte address is outside of device address range (offset + size - 1 > total memory size)"));
+    }
+
+    let max_buffer_size = read_buffer_size_for_frame(device.serial_frame_size());
+
+    if request.buffer_size.get() > max_buffer_size {
+        return Err(anyhow!(
+            "Read buffer size of {} bytes is too large: responses to read commands will not fit into serial frame of {} bytes. \
+            Maximal read buffer size is {} bytes. Use smaller buffer size or set --serial-frame-size if the firmware supports larger frames",
+            request.buffer_size,
+            device.serial_frame_size(),
+            max_buffer_size,
+        ));
+    }
+
+    read_segment_count(request)
+}
+
+/// Returns number of read commands needed to complete a request, failing if the request doesn't fit
+/// into 16-bit address space of read commands.
+fn read_segment_count(request: &DataReadRequest) -> Result<usize> {
+    if request.size.get().checked_add(request.offset as usize).is_none_or(|end| end > ADDRESS_SPACE_SIZE) {
+        return Err(anyhow!(
+            "Read region 0x{:04X}+0x{:X} exceeds 16-bit address space of read commands",
+            request.offset,
+            request.size,
+        ));
+    }
+
+    Ok(request.size.get().div_ceil(request.buffer_size.get().into()))
+}
+
+/// Reads one segment of a request.
+///
+/// Raw response is received into `response` buffer, decoded data replaces content of `data`.
+/// Returns address of the first byte of the segment.
+///
+/// With checked reads, the segment is read again if its CRC doesn't match.
+fn read_segment(
+    device: &mut Device,
+    request: &DataReadRequest,
+    segment_number: usize,
+    num_segments: usize,
+    response: &mut Vec<u8>,
+    data: &mut Vec<u8>,
+) -> Result<u16> {
+    let mut attempt = 1;
+
+    loop {
+        match read_segment_once(device, request, segment_number, num_segments, response, data) {
+            Err(e) if attempt < CHECKED_READ_ATTEMPTS && e.is::<ReadCrcMismatch>() => {
+                eprintln!("{}, reading again", e);
+                device.wait_before_retry(attempt);
+                attempt += 1;
+            }
+            result => { return result; }
+        }
+    }
+}
+
+fn read_segment_once(
+    device: &mut Device,
+    request: &DataReadRequest,
+    segment_number: usize,
+    num_segments: usize,
+    response: &mut Vec<u8>,
+    data: &mut Vec<u8>,
+) -> Result<u16> {
+    let dialect = device.dialect();
+    let checked = device.checked_reads();
+    let binary = device.binary_transfers();
+    let (segment_start_address, segment_size) = read_segment_bounds(request, segment_number);
+    let _span = trace_span!("read_chunk", offset = segment_start_address, size = segment_size).entered();
+
+    let read_command = if binary { dialect.binary_read } else { dialect.read };
+    let mut command = read_command_text(read_command, segment_start_address, segment_size, dialect.hex_case);
+    if checked {
+        command.insert(command.len() - 1, CHECKED_READ_SUFFIX);
+    }
+    device.send(command.as_bytes())?;
+
+    let allow_short_read = request.allow_short_read && segment_number + 1 == num_segments;
+    let expected_crc = if binary {
+        receive_binary_read_response(device, segment_size, checked, allow_short_read, data)?
+    } else {
+        receive_hex_read_response(device, segment_start_address, segment_size, checked, allow_short_read, response, data)?
+    };
+
+    if let Some(expected) = expected_crc {
+        let actual = crc8(data.as_slice());
+
+        if expected != actual {
+            return Err(ReadCrcMismatch { address: segment_start_address, expected, actual }.into());
+        }
+    }
+
+    metrics::record_data_bytes(data.len());
+    Ok(segment_start_address)
 }
 
+/// Receives response to a hex read command, decoded data replaces content of `data`.
+///
+/// Returns CRC sent by the device if the read is checked.
+fn receive_hex_read_response(
+    device: &mut Device,
+    address: u16,
+    size: u8,
+    checked: bool,
+    allow_short_read: bool,
+    response: &mut Vec<u8>,
+    data: &mut Vec<u8>,
+) -> Result<Option<u8>> {
+    let crc_size = if checked { 2 } else { 0 };
+    let response_size = 2 + (size as usize) * 2 + crc_size;
+    device.receive_into_within(response, response_size, device.read_response_timeout(response_size))?;
+
+    let response_payload = read_response_payload(response.as_slice(), &device.dialect())?;
+    let (response_payload, crc_digits) = response_payload.split_at(response_payload.len().saturating_sub(crc_size));
+
+    let is_acceptable_short_read = allow_short_read
+        && response_payload.len() < 2 * (size as usize)
+        && response_payload.len().is_multiple_of(2);
+
+    if response_payload.len() != 2 * (size as usize) && !is_acceptable_short_read {
+        return Err(anyhow!(
+            "Received payload of unexpected length ({} instead of {})",
+            response_payload.len(),
+            size as usize * 2,
+        ));
+    }
+
+    data.clear();
+    decode_hex_into(response_payload, data)
+        .with_context(|| format!("Error parsing payload of response to read at 0x{:04X}", address))?;
+
+    if !checked {
+        return Ok(None);
+    }
+
+    let mut crc = Vec::with_capacity(1);
+    decode_hex_into(crc_digits, &mut crc).context("Error parsing response CRC")?;
+
+    Ok(Some(*crc.first().ok_or_else(|| anyhow!("Response CRC is missing"))?))
+}
+
+/// Receives response to a binary read command, received data replaces content of `data`.
+///
+/// Returns CRC sent by the device if the read is checked.
+fn receive_binary_read_response(
+    device: &mut Device,
+    size: u8,
+    checked: bool,
+    allow_short_read: bool,
+    data: &mut Vec<u8>,
+) -> Result<Option<u8>> {
+    let crc_size = if checked { 1 } else { 0 };
+    let timeout = device.read_response_timeout(3 + size as usize + crc_size);
+    device.receive_binary_into_within(device.dialect().binary_read, data, size as usize, crc_size, timeout)?;
+
+    let crc = if checked { data.pop() } else { None };
+
+    if data.len() != size as usize && !(allow_short_read && data.len() < size as usize) {
+        return Err(anyhow!("Received {} bytes of data instead of {}", data.len(), size));
+    }
+
+    Ok(crc)
+}
+
+/// Checks that `size` bytes of data written at `offset` fit into device memory.
+pub fn check_write_bounds(device: &mut Device, offset: u16, size: usize) -> Result<()> {
+    let memory_size = device.memory_size()?.min(ADDRESS_SPACE_SIZE);
+
+    if (offset as usize).checked_add(size).is_none_or(|end| end > memory_size) {
+        return Err(anyhow!(
+            "Data file size is too large: 0x{:X} bytes of data supplied at offset 0x{:04X}. Total device memory size is 0x{:X}",
+            size,
+            offset,
+            memory_size,
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
 pub struct DataWriteRequest<'a, T: AsRef<[u8]>> {
     pub data: &'a DataChunk<T>,
     pub buffer_size: NonZeroU8,
+    /// Size of memory pages no write command should cross.
+    ///
+    /// Page-programmed memory may handle writes straddling page boundary inefficiently or
+    /// incorrectly.
+    pub page_size: Option<NonZeroUsize>,
 }
 
 pub fn write_data<T: AsRef<[u8]>>(
     device: &mut Device,
     request: DataWriteRequest<T>
 ) -> Result<()> {
+    write_data_with_progress(device, request, |_, _| {})
+}
+
+/// Same as [write_data] but calls `progress` with number of bytes written so far and total number
+/// of bytes to write after each written chunk.
+pub fn write_data_with_progress<T: AsRef<[u8]>, F: FnMut(usize, usize)>(
+    device: &mut Device,
+    request: DataWriteRequest<T>,
+    mut progress: F,
+) -> Result<()> {
+    let total_size = request.data.data.as_ref().len();
+    let mut written_size = 0;
+
+    for (address, sub_chunk) in write_segments(&request) {
+        write_segment(device, address, sub_chunk)?;
+
+        written_size += sub_chunk.len();
+        progress(written_size, total_size);
+    }
+
+    Ok(())
+}
+
+/// Same as [write_data_with_progress] but reads every written chunk back right after writing it and
+/// writes it again if it doesn't match, at most `max_rewrites` times per chunk.
+///
+/// Waits for `read_back_delay` before each read back.
+/// Returns number of chunks that had to be written more than once.
+pub fn write_data_healing<T: AsRef<[u8]>, F: FnMut(usize, usize)>(
+    device: &mut Device,
+    request: DataWriteRequest<T>,
+    read_buffer_size: NonZeroU8,
+    max_rewrites: usize,
+    read_back_delay: Duration,
+    mut progress: F,
+) -> Result<usize> {
+    let total_size = request.data.data.as_ref().len();
+    let mut written_size = 0;
+    let mut rewritten_chunks = 0;
+    let mut read_back = Vec::with_capacity(request.buffer_size.get() as usize);
+
+    for (address, sub_chunk) in write_segments(&request) {
+        let mut rewrites = 0;
+
+        loop {
+            write_segment(device, address, sub_chunk)?;
+
+            if !read_back_delay.is_zero() {
+                thread::sleep(read_back_delay);
+            }
+
+            read_back.clear();
+            read_data_into(device, DataReadRequest {
+                offset: address,
+                size: NonZeroUsize::new(sub_chunk.len()).expect("write segments are not empty"),
+                buffer_size: read_buffer_size,
+                allow_short_read: false,
+                reverse: false,
+            }, |_, _| {}, |chunk| {
+                read_back.extend_from_slice(chunk.data);
+                Ok(())
+            })?;
+
+            if read_back.as_slice() == sub_chunk {
+                break;
+            }
+
+            if rewrites == max_rewrites {
+                return Err(anyhow!(
+                    "Verification failed: {} bytes at 0x{:04X} still differ after {} rewrites",
+                    sub_chunk.len(),
+                    address,
+                    max_rewrites,
+                ));
+            }
+
+            rewrites += 1;
+            eprintln!("Data read back at 0x{:04X} differs from written, rewriting ({} of {})", address, rewrites, max_rewrites);
+            device.wait_before_retry(rewrites);
+        }
+
+        if rewrites > 0 {
+            rewritten_chunks += 1;
+        }
+
+        written_size += sub_chunk.len();
+        progress(written_size, total_size);
+    }
+
+    Ok(rewritten_chunks)
+}
+
+/// Sends a single write command and checks the response.
+fn write_segment(device: &mut Device, address: u16, data: &[u8]) -> Result<()> {
+    let dialect = device.dialect();
+    let _span = trace_span!("write_chunk", offset = address, size = data.len()).entered();
+    let expected = WriteResponse { start: address, end: address.wrapping_add(data.len() as u16) };
+
+    if device.binary_transfers() {
+        device.send(binary_write_command(dialect.binary_write, address, data, dialect.hex_case).as_slice())?;
+    } else {
+        device.send(write_command_text(dialect.write, address, data, dialect.hex_case).as_bytes())?;
+    }
+    let response = device.receive(32)?;
+
+    if WriteResponse::parse(response.as_slice(), &dialect).ok() != Some(expected) {
+        return Err(anyhow!(
+            "Unexpected write command response: '{}', expected '{}'",
+            String::from_utf8_lossy(&response),
+            expected.encode(&dialect),
+        ));
+    }
+
+    metrics::record_data_bytes(data.len());
+    Ok(())
+}
+
+/// Splits data of a write request into parts written by separate commands.
+///
+/// Each part is at most `buffer_size` bytes long and doesn't cross page boundary if page size is set.
+fn write_segments<'a, T: AsRef<[u8]>>(request: &DataWriteRequest<'a, T>) -> impl Iterator<Item=(u16, &'a [u8])> {
+    let buffer_size = request.buffer_size.get() as usize;
+    let page_size = request.page_size;
     let mut address = request.data.offset;
+    let mut remaining = request.data.data.as_ref();
 
-    for sub_chunk in request.data.data.as_ref().chunks(request.buffer_size.get() as usize) {
-        let end_address = address.wrapping_add(sub_chunk.len() as u16);
-        let mut command = format!("W{:04X}", address).into_bytes();
+    std::iter::from_fn(move || {
+        if remaining.is_empty() {
+            return None;
+        }
 
-        for b in sub_chunk {
-            write!(command, "{:02X}", b)?;
+        let mut size = min(buffer_size, remaining.len());
+        if let Some(page_size) = page_size {
+            size = min(size, page_size.get() - address as usize % page_size.get());
         }
 
-        write!(command, "\n")?;
+        let (segment, rest) = remaining.split_at(size);
+        let segment_address = address;
+        remaining = rest;
+        address = address.wrapping_add(size as u16);
 
-        device.send(command.as_slice())?;
-        let response = device.receive(16)?;
-        let expected_response = format!("W{:04X}{:04X}", address, end_address);
+        Some((segment_address, segment))
+    })
+}
+
+/// Formats command writing `data` starting at `address`.
+pub fn write_command_text(write_command: char, address: u16, data: &[u8], hex_case: HexCase) -> String {
+    let mut command = String::with_capacity(6 + 2 * data.len());
+    command.push(write_command);
+    command.push_str(hex_case.digits(address, 4).to_string().as_str());
+
+    for &b in data {
+        command.push_str(hex_case.digits(b, 2).to_string().as_str());
+    }
+
+    command.push('\n');
+    command
+}
 
-        if response.as_slice() != expected_response.as_bytes() {
+/// Builds binary write command: command character, 4-digit address, data size byte, raw data and a
+/// new line.
+fn binary_write_command(write_command: char, address: u16, data: &[u8], hex_case: HexCase) -> Vec<u8> {
+    assert!(data.len() <= u8::MAX as usize, "binary write command data doesn't fit into 255 bytes");
+
+    let mut command = format!("{}{}", write_command, hex_case.digits(address, 4)).into_bytes();
+    command.push(data.len() as u8);
+    command.extend_from_slice(data);
+    command.push(b'\n');
+
+    command
+}
+
+/// Returns commands that would be sent to perform given write request, without sending them.
+pub fn write_commands<T: AsRef<[u8]>>(request: &DataWriteRequest<T>, dialect: &ProtocolDialect) -> Vec<String> {
+    write_segments(request)
+        .map(|(address, data)| write_command_text(dialect.write, address, data, dialect.hex_case))
+        .collect()
+}
+
+/// Writes data from `input` stream to device memory starting at `offset` as soon as each chunk of
+/// `buffer_size` bytes arrives, without reading the whole stream first.
+///
+/// A slow producer (e.g. a process writing to a named pipe) makes the write wait for the data,
+/// device timeouts don't apply to this waiting. A partial chunk at the end of the stream is written
+/// as well. If the stream contains more data than fits into device memory, the error is reported
+/// after writing all data that fits.
+///
+/// Calls `progress` with number of bytes written so far after each written chunk.
+/// Returns total number of written bytes.
+pub fn write_stream<F: FnMut(usize)>(
+    device: &mut Device,
+    offset: u16,
+    input: &mut dyn Read,
+    buffer_size: NonZeroU8,
+    page_size: Option<NonZeroUsize>,
+    mut progress: F,
+) -> Result<usize> {
+    let available_size = device.memory_size()?.saturating_sub(offset as usize);
+    let mut buffer = vec![0u8; buffer_size.get() as usize];
+    let mut written_size = 0;
+
+    loop {
+        let size = read_up_to(input, buffer.as_mut_slice())?;
+
+        if size == 0 {
+            return Ok(written_size);
+        }
+
+        let fitting_size = min(size, available_size - written_size);
+
+        if fitting_size > 0 {
+            write_data(device, DataWriteRequest {
+                data: &DataChunk { offset: offset + written_size as u16, data: &buffer[..fitting_size] },
+                buffer_size,
+                page_size,
+            })?;
+
+            written_size += fitting_size;
+            progress(written_size);
+        }
+
+        if fitting_size < size {
             return Err(anyhow!(
-                "Unexpected write command response: '{}', expected '{}'",
-                String::from_utf8_lossy(&response),
-                expected_response,
+                "Input data does not fit into device memory: 0x{:X} bytes written at offset 0x{:04X}, more data remaining",
+                written_size,
+                offset,
             ));
         }
 
-        address = end_address;
+        if size < buffer.len() {
+            return Ok(written_size);
+        }
+    }
+}
+
+impl Device {
+    /// Reads whole device memory.
+    pub fn read_all(&mut self, buffer_size: NonZeroU8) -> Result<Vec<u8>> {
+        let size = NonZeroUsize::new(self.memory_size()?)
+            .ok_or_else(|| anyhow!("Device reported zero memory size"))?;
+        let mut data = Vec::with_capacity(size.get());
+
+        for chunk in read_data(self, DataReadRequest {
+            offset: 0,
+            size,
+            buffer_size,
+            allow_short_read: false,
+            reverse: false,
+        })? {
+            data.extend_from_slice(chunk?.data.as_slice());
+        }
+
+        Ok(data)
+    }
+
+    /// Writes given data to device memory starting from the first address.
+    ///
+    /// Fails if the data does not fit into device memory.
+    pub fn write_all(&mut self, data: &[u8], buffer_size: NonZeroU8) -> Result<()> {
+        check_write_bounds(self, 0, data.len())?;
+
+        write_data(self, DataWriteRequest {
+            data: &DataChunk { offset: 0, data },
+            buffer_size,
+            page_size: None,
+        })
+    }
+}
+
+/// Reads the region covered by `expected` back from the device and compares it with `expected` data.
+pub fn verify_region<T: AsRef<[u8]>>(
+    device: &mut Device,
+    expected: &DataChunk<T>,
+    buffer_size: NonZeroU8,
+) -> Result<()> {
+    let _span = trace_span!("verify", offset = expected.offset, size = expected.data.as_ref().len()).entered();
+    let expected_data = expected.data.as_ref();
+    let size = match NonZeroUsize::new(expected_data.len()) {
+        None => { return Ok(()); }
+        Some(sz) => sz,
+    };
+
+    for read_chunk in read_data(device, DataReadRequest {
+        offset: expected.offset,
+        size,
+        buffer_size,
+        allow_short_read: false,
+        reverse: false,
+    })? {
+        compare_chunk(expected, &read_chunk?)?;
     }
 
     Ok(())
 }
+
+/// Compares device memory starting at `offset` with data pulled from `reference` stream.
+///
+/// The reference is consumed chunk by chunk, so it doesn't have to fit in memory (e.g. when it is
+/// decompressed on the fly).
+/// When `size` is given, exactly that many bytes are compared and the stream must provide at least
+/// as many bytes. Otherwise, the comparison continues until the end of the stream.
+///
+/// Returns number of compared bytes.
+pub fn verify_stream(
+    device: &mut Device,
+    offset: u16,
+    size: Option<NonZeroUsize>,
+    reference: &mut dyn Read,
+    buffer_size: NonZeroU8,
+) -> Result<usize> {
+    let _span = trace_span!("verify", offset, size = size.map(NonZeroUsize::get)).entered();
+    let memory_size = device.memory_size()?;
+    let limit = match size {
+        Some(size) => {
+            check_write_bounds(device, offset, size.get())?;
+            size.get()
+        }
+        None => memory_size.saturating_sub(offset as usize),
+    };
+
+    let mut expected = vec![0u8; buffer_size.get() as usize];
+    let mut compared = 0;
+
+    while compared < limit {
+        let want = min(expected.len(), limit - compared);
+        let got = read_up_to(reference, &mut expected[..want])?;
+
+        if got < want && size.is_some() {
+            return Err(anyhow!(
+                "Reference data ended at 0x{:04X}, before the end of compared region (0x{:04X})",
+                offset as usize + compared + got,
+                offset as usize + limit,
+            ));
+        }
+
+        if let Some(got) = NonZeroUsize::new(got) {
+            let chunk_offset = offset + compared as u16;
+            let expected_chunk = DataChunk { offset: chunk_offset, data: &expected[..got.get()] };
+
+            for read_chunk in read_data(device, DataReadRequest {
+                offset: chunk_offset,
+                size: got,
+                buffer_size,
+                allow_short_read: false,
+                reverse: false,
+            })? {
+                compare_chunk(&expected_chunk, &read_chunk?)?;
+            }
+
+            compared += got.get();
+        }
+
+        if got < want {
+            return Ok(compared);
+        }
+    }
+
+    if size.is_none() && read_up_to(reference, &mut expected[..1])? > 0 {
+        return Err(anyhow!(
+            "Reference data is larger than device memory after offset 0x{:04X} (0x{:X} bytes)",
+            offset,
+            limit,
+        ));
+    }
+
+    Ok(compared)
+}
+
+/// Reads from `reader` until `buffer` is full or the stream ends, returns number of read bytes.
+fn read_up_to(reader: &mut dyn Read, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(filled)
+}
+
+/// Same as [verify_region] but compares received chunks in a separate thread while next chunks are
+/// being read.
+///
+/// Only the comparison is moved off the main thread, the serial port still transfers one chunk at a
+/// time. Measurements with the simulated device (`cargo bench --bench parallel_verify`) show no
+/// speedup: comparison takes much less time than passing chunks to another thread.
+pub fn verify_region_parallel<T: AsRef<[u8]> + Sync>(
+    device: &mut Device,
+    expected: &DataChunk<T>,
+    buffer_size: NonZeroU8,
+) -> Result<()> {
+    let _span = trace_span!("verify", offset = expected.offset, size = expected.data.as_ref().len()).entered();
+    let size = match NonZeroUsize::new(expected.data.as_ref().len()) {
+        None => { return Ok(()); }
+        Some(sz) => sz,
+    };
+
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel::<DataChunk<Vec<u8>>>();
+        let comparator = scope.spawn(move || -> Result<()> {
+            for chunk in receiver {
+                compare_chunk(expected, &chunk)?;
+            }
+
+            Ok(())
+        });
+
+        let read_result = read_data(device, DataReadRequest {
+            offset: expected.offset,
+            size,
+            buffer_size,
+            allow_short_read: false,
+            reverse: false,
+        }).and_then(|chunks| {
+            for chunk in chunks {
+                // Sending fails only if the comparator has already found a mismatch
+                if sender.send(chunk?).is_err() {
+                    break;
+                }
+            }
+
+            Ok(())
+        });
+        drop(sender);
+
+        let compare_result = comparator.join()
+            .unwrap_or_else(|_| Err(anyhow!("Verification thread panicked")));
+
+        compare_result.and(read_result)
+    })
+}
+
+/// Byte of device memory that differs from expected value.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub address: usize,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+/// Reads the region covered by `expected` back from the device and returns all bytes that differ
+/// from `expected` data.
+///
+/// Unlike [verify_region], doesn't stop at first mismatch.
+pub fn find_mismatches<T: AsRef<[u8]>>(
+    device: &mut Device,
+    expected: &DataChunk<T>,
+    buffer_size: NonZeroU8,
+) -> Result<Vec<Mismatch>> {
+    let _span = trace_span!("verify", offset = expected.offset, size = expected.data.as_ref().len()).entered();
+    let expected_data = expected.data.as_ref();
+    let size = match NonZeroUsize::new(expected_data.len()) {
+        None => { return Ok(vec![]); }
+        Some(sz) => sz,
+    };
+    let mut mismatches = vec![];
+
+    for read_chunk in read_data(device, DataReadRequest {
+        offset: expected.offset,
+        size,
+        buffer_size,
+        allow_short_read: false,
+        reverse: false,
+    })? {
+        let read_chunk = read_chunk?;
+        let start = (read_chunk.offset - expected.offset) as usize;
+
+        for (i, (actual, expected)) in read_chunk.data.iter().zip(&expected_data[start..]).enumerate() {
+            if actual != expected {
+                mismatches.push(Mismatch {
+                    address: read_chunk.offset as usize + i,
+                    expected: *expected,
+                    actual: *actual,
+                });
+            }
+        }
+    }
+
+    Ok(mismatches)
+}
+
+/// Builds chunks filled with `value` covering given addresses.
+///
+/// Duplicate addresses are ignored and consecutive addresses are coalesced into a single chunk, so
+/// they are written with as few commands as possible.
+pub fn coalesce_addresses(addresses: &[u16], value: u8) -> Vec<DataChunk<Vec<u8>>> {
+    let mut addresses = addresses.to_vec();
+    addresses.sort();
+    addresses.dedup();
+
+    let mut chunks: Vec<DataChunk<Vec<u8>>> = vec![];
+
+    for address in addresses {
+        match chunks.last_mut() {
+            Some(chunk) if chunk.offset as usize + chunk.data.len() == address as usize => {
+                chunk.data.push(value);
+            }
+            _ => {
+                chunks.push(DataChunk { offset: address, data: vec![value] });
+            }
+        }
+    }
+
+    chunks
+}
+
+/// Groups mismatching bytes at consecutive addresses into regions holding their actual values.
+///
+/// `mismatches` must be sorted by address, as returned by [find_mismatches].
+pub fn changed_regions(mismatches: &[Mismatch]) -> Vec<DataChunk<Vec<u8>>> {
+    let mut regions: Vec<DataChunk<Vec<u8>>> = vec![];
+
+    for mismatch in mismatches {
+        match regions.last_mut() {
+            Some(region) if region.offset as usize + region.data.len() == mismatch.address => {
+                region.data.push(mismatch.actual);
+            }
+            _ => {
+                regions.push(DataChunk { offset: mismatch.address as u16, data: vec![mismatch.actual] });
+            }
+        }
+    }
+
+    regions
+}
+
+/// Reads back and compares only a pseudo-randomly chosen subset of `buffer_size`-sized chunks of the
+/// region covered by `expected`.
+///
+/// Chunks are chosen using given `seed`, so the same seed always selects the same chunks.
+/// Returns number of verified chunks and total number of chunks in the region.
+pub fn verify_region_sample<T: AsRef<[u8]>>(
+    device: &mut Device,
+    expected: &DataChunk<T>,
+    buffer_size: NonZeroU8,
+    percent: u8,
+    seed: u64,
+) -> Result<(usize, usize)> {
+    let _span = trace_span!("verify", offset = expected.offset, size = expected.data.as_ref().len()).entered();
+    let total_size = expected.data.as_ref().len();
+    let chunk_size: usize = buffer_size.get().into();
+    let num_chunks = total_size.div_ceil(chunk_size);
+    let num_samples = min(num_chunks, (num_chunks * percent as usize).div_ceil(100));
+
+    // Partial Fisher-Yates shuffle of chunk indices
+    let mut rng = SplitMix64(seed);
+    let mut indices: Vec<usize> = (0..num_chunks).collect();
+    for i in 0..num_samples {
+        let j = i + (rng.next() % (num_chunks - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    let mut samples = indices[..num_samples].to_vec();
+    samples.sort_unstable();
+
+    for chunk_index in samples {
+        let chunk_start = chunk_index * chunk_size;
+        let size = min(chunk_size, total_size - chunk_start);
+
+        for read_chunk in read_data(device, DataReadRequest {
+            offset: expected.offset + chunk_start as u16,
+            size: NonZeroUsize::new(size).unwrap(),
+            buffer_size,
+            allow_short_read: false,
+            reverse: false,
+        })? {
+            compare_chunk(expected, &read_chunk?)?;
+        }
+    }
+
+    Ok((num_samples, num_chunks))
+}
+
+/// Writes a test pattern to given region, reads it back and compares with the pattern.
+///
+/// Original content of the region is read before the test and written back after it, even if the
+/// test fails.
+pub fn self_test(
+    device: &mut Device,
+    offset: u16,
+    size: NonZeroUsize,
+    read_buffer_size: NonZeroU8,
+    write_buffer_size: NonZeroU8,
+) -> Result<()> {
+    check_write_bounds(device, offset, size.get())?;
+
+    let mut original = Vec::with_capacity(size.get());
+    for chunk in read_data(device, DataReadRequest {
+        offset,
+        size,
+        buffer_size: read_buffer_size,
+        allow_short_read: false,
+        reverse: false,
+    })? {
+        original.extend_from_slice(chunk?.data.as_slice());
+    }
+    let original = DataChunk { offset, data: original };
+
+    // Pattern varies both between neighbour bytes and between neighbour bits
+    let pattern = DataChunk {
+        offset,
+        data: (0..size.get()).map(|i| (i as u8) ^ if i % 2 == 0 { 0x55 } else { 0xAA }).collect::<Vec<u8>>(),
+    };
+
+    let test_result = write_data(device, DataWriteRequest { data: &pattern, buffer_size: write_buffer_size, page_size: None })
+        .context("Error writing test pattern")
+        .and_then(|_| verify_region(device, &pattern, read_buffer_size).context("Error verifying test pattern"));
+
+    let restore_result = write_data(device, DataWriteRequest { data: &original, buffer_size: write_buffer_size, page_size: None })
+        .and_then(|_| verify_region(device, &original, read_buffer_size))
+        .context("Error restoring original data");
+
+    if let Err(restore_error) = restore_result {
+        if let Err(test_error) = &test_result {
+            eprintln!("Self-test failed: {:#}", test_error);
+        }
+
+        return Err(restore_error);
+    }
+
+    test_result
+}
+
+/// Operation of a march memory test.
+#[derive(Debug)]
+pub enum MarchOperation {
+    /// Read a chunk and compare it with expected data.
+    Verify(DataChunk<Vec<u8>>),
+    Write(DataChunk<Vec<u8>>),
+}
+
+impl MarchOperation {
+    /// Data expected to be read or data to write.
+    pub fn chunk(&self) -> &DataChunk<Vec<u8>> {
+        match self {
+            MarchOperation::Verify(chunk) | MarchOperation::Write(chunk) => chunk,
+        }
+    }
+}
+
+/// Elements of March C- algorithm: whether chunks are processed in descending order, whether the
+/// background (`false`) or its complement (`true`) is expected to be read and which one is written.
+const MARCH_C_MINUS: [(bool, Option<bool>, Option<bool>); 6] = [
+    (false, None, Some(false)),
+    (false, Some(false), Some(true)),
+    (false, Some(true), Some(false)),
+    (true, Some(false), Some(true)),
+    (true, Some(true), Some(false)),
+    (false, Some(false), None),
+];
+
+/// Builds a chunk of checkerboard pattern (or its complement) covering `size` bytes at `offset`.
+fn march_pattern(offset: usize, size: usize, complement: bool) -> DataChunk<Vec<u8>> {
+    let inversion = if complement { 0xFF } else { 0x00 };
+
+    DataChunk {
+        offset: offset as u16,
+        data: (offset..offset + size)
+            .map(|address| inversion ^ if address % 2 == 0 { 0x55 } else { 0xAA })
+            .collect(),
+    }
+}
+
+/// Lists operations of March C- test of given region with numbers of march elements they belong to.
+///
+/// The algorithm is applied to chunks of `chunk_size` bytes instead of single bytes, as accessing
+/// device memory byte by byte would be too slow. Checkerboard pattern is used as background.
+pub fn march_operations(offset: u16, size: NonZeroUsize, chunk_size: NonZeroU8) -> Vec<(usize, MarchOperation)> {
+    let chunk_starts: Vec<usize> = (offset as usize..offset as usize + size.get())
+        .step_by(chunk_size.get() as usize)
+        .collect();
+    let mut operations = vec![];
+
+    for (element, (descending, read, write)) in MARCH_C_MINUS.into_iter().enumerate() {
+        let ordered_starts: Box<dyn Iterator<Item=&usize>> = if descending {
+            Box::new(chunk_starts.iter().rev())
+        } else {
+            Box::new(chunk_starts.iter())
+        };
+
+        for start in ordered_starts {
+            let length = min(chunk_size.get() as usize, offset as usize + size.get() - start);
+
+            if let Some(complement) = read {
+                operations.push((element, MarchOperation::Verify(march_pattern(*start, length, complement))));
+            }
+            if let Some(complement) = write {
+                operations.push((element, MarchOperation::Write(march_pattern(*start, length, complement))));
+            }
+        }
+    }
+
+    operations
+}
+
+/// Runs March C- memory test of given region, see [march_operations].
+///
+/// The test is destructive: original content of the region is not restored.
+/// Calls `progress` with number of bytes read or written so far and total number of bytes to read
+/// and write.
+/// Fails with address of the first byte that doesn't hold the expected value.
+pub fn march_test<F: FnMut(usize, usize)>(
+    device: &mut Device,
+    offset: u16,
+    size: NonZeroUsize,
+    chunk_size: NonZeroU8,
+    mut progress: F,
+) -> Result<()> {
+    let _span = trace_span!("march_test", offset, size).entered();
+    check_write_bounds(device, offset, size.get())?;
+
+    let operations = march_operations(offset, size, chunk_size);
+    let total = operations.iter().map(|(_, operation)| operation.chunk().data.len()).sum();
+    let mut done = 0;
+
+    for (element, operation) in operations.iter() {
+        match operation {
+            MarchOperation::Write(chunk) => {
+                write_data(device, DataWriteRequest { data: chunk, buffer_size: chunk_size, page_size: None })?;
+            }
+            MarchOperation::Verify(expected) => {
+                let mismatches = find_mismatches(device, expected, chunk_size)?;
+
+                if let Some(mismatch) = mismatches.first() {
+                    return Err(anyhow!(
+                        "March test failed at 0x{:04X} in element {}: expected 0x{:02X}, read 0x{:02X}",
+                        mismatch.address,
+                        element,
+                        mismatch.expected,
+                        mismatch.actual,
+                    ));
+                }
+            }
+        }
+
+        done += operation.chunk().data.len();
+        progress(done, total);
+    }
+
+    Ok(())
+}
+
+/// Replaces bits selected by `mask` in the byte at `offset` by corresponding bits of `value`.
+///
+/// The byte is read, modified and written back, so the operation is not atomic with respect to
+/// other bus masters accessing the memory. Returns the original and the written value of the byte.
+pub fn modify_byte(device: &mut Device, offset: u16, mask: u8, value: u8, verify: bool) -> Result<(u8, u8)> {
+    let _span = trace_span!("modify_byte", offset, mask, value).entered();
+    check_write_bounds(device, offset, 1)?;
+
+    let original = read_data(device, DataReadRequest {
+        offset,
+        size: NonZeroUsize::MIN,
+        buffer_size: NonZeroU8::MIN,
+        allow_short_read: false,
+        reverse: false,
+    })?
+        .next()
+        .ok_or_else(|| anyhow!("No data received"))??
+        .data
+        .first()
+        .copied()
+        .ok_or_else(|| anyhow!("No data received"))?;
+    let modified = DataChunk { offset, data: [(original & !mask) | (value & mask)] };
+
+    write_data(device, DataWriteRequest { data: &modified, buffer_size: NonZeroU8::MIN, page_size: None })?;
+
+    if verify {
+        verify_region(device, &modified, NonZeroU8::MIN)?;
+    }
+
+    Ok((original, modified.data[0]))
+}
+
+fn compare_chunk<T: AsRef<[u8]>>(expected: &DataChunk<T>, actual: &DataChunk<Vec<u8>>) -> Result<()> {
+    let start = (actual.offset - expected.offset) as usize;
+    let required_data = &expected.data.as_ref()[start..(start + actual.data.len())];
+
+    if actual.data.as_slice() != required_data {
+        return Err(anyhow!(
+            "Verification failed in range {:04X}:{:04X}",
+            actual.offset,
+            actual.offset as usize + actual.data.len(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Small deterministic pseudo-random number generator, good enough for sampling.
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DeviceSettings;
+    use crate::mock::{open_mock_device, MockPort, MOCK_PORT_NAME};
+
+    fn mock_device() -> Device {
+        open_mock_device(&DeviceSettings::default()).unwrap()
+    }
+
+    fn read_request(offset: u16, size: usize, buffer_size: u8) -> DataReadRequest {
+        DataReadRequest {
+            offset,
+            size: NonZeroUsize::new(size).unwrap(),
+            buffer_size: NonZeroU8::new(buffer_size).unwrap(),
+            allow_short_read: false,
+            reverse: false,
+        }
+    }
+
+    fn read_all(device: &mut Device, request: DataReadRequest) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        read_data_into(device, request, |_, _| {}, |chunk| {
+            data.extend_from_slice(chunk.data);
+            Ok(())
+        })?;
+        Ok(data)
+    }
+
+    fn short_reading_device(readable_size: usize) -> Device {
+        let mut port = MockPort::new();
+        port.set_readable_size(readable_size);
+        Device::with_port(MOCK_PORT_NAME, Box::new(port), &DeviceSettings::default())
+    }
+
+    #[test]
+    fn short_final_read_is_accepted_if_allowed() {
+        let mut device = short_reading_device(0xFFFC);
+        let request = DataReadRequest { allow_short_read: true, ..read_request(0xFFF0, 0x10, 8) };
+
+        assert_eq!(read_all(&mut device, request).unwrap().len(), 12);
+        assert!(read_all(&mut device, read_request(0xFFF0, 0x10, 8)).is_err());
+    }
+
+    #[test]
+    fn short_read_before_final_chunk_fails() {
+        let mut device = short_reading_device(0xFFEC);
+        let request = DataReadRequest { allow_short_read: true, ..read_request(0xFFE0, 0x20, 8) };
+
+        assert!(read_all(&mut device, request).is_err());
+    }
+
+    fn segment_bounds(offset: u16, size: usize, buffer_size: u8, page_size: Option<usize>) -> Vec<(u16, usize)> {
+        let data = DataChunk { offset, data: vec![0u8; size] };
+        let request = DataWriteRequest {
+            data: &data,
+            buffer_size: NonZeroU8::new(buffer_size).unwrap(),
+            page_size: page_size.and_then(NonZeroUsize::new),
+        };
+
+        write_segments(&request).map(|(address, segment)| (address, segment.len())).collect()
+    }
+
+    #[test]
+    fn write_segments_without_pages() {
+        assert_eq!(segment_bounds(0x0003, 20, 8, None), vec![(0x0003, 8), (0x000B, 8), (0x0013, 4)]);
+    }
+
+    #[test]
+    fn write_segments_with_misaligned_offset() {
+        assert_eq!(
+            segment_bounds(0x0003, 20, 8, Some(16)),
+            vec![(0x0003, 8), (0x000B, 5), (0x0010, 7)],
+        );
+        assert_eq!(segment_bounds(0x00FE, 4, 16, Some(256)), vec![(0x00FE, 2), (0x0100, 2)]);
+    }
+
+    #[test]
+    fn write_segments_with_pages_smaller_than_buffer() {
+        assert_eq!(segment_bounds(0x0006, 10, 29, Some(4)), vec![(0x0006, 2), (0x0008, 4), (0x000C, 4)]);
+    }
+
+    #[test]
+    fn huge_read_request_is_rejected() {
+        let mut device = mock_device();
+
+        assert!(read_segment_count(&read_request(0, usize::MAX, 16)).is_err());
+        assert!(read_segment_count(&read_request(0xFFFF, usize::MAX, 255)).is_err());
+        assert!(check_read_request(&mut device, &read_request(0x10, usize::MAX - 0x0F, 16)).is_err());
+        assert!(read_commands(&read_request(1, usize::MAX, 16), &ProtocolDialect::default()).is_err());
+    }
+
+    #[test]
+    fn chunk_ending_at_last_address() {
+        let request = read_request(0xFF00, 0x100, 255);
+
+        assert_eq!(read_segment_bounds(&request, 0), (0xFF00, 255));
+        assert_eq!(read_segment_bounds(&request, 1), (0xFFFF, 1));
+        assert_eq!(read_commands(&request, &ProtocolDialect::default()).unwrap(), vec!["RFF00FF\n", "RFFFF01\n"]);
+
+        let mut device = mock_device();
+        write_data(&mut device, DataWriteRequest {
+            data: &DataChunk { offset: 0xFFF0, data: (0..0x10).collect::<Vec<u8>>() },
+            buffer_size: NonZeroU8::new(8).unwrap(),
+            page_size: None,
+        }).unwrap();
+        let chunks = read_data(&mut device, read_request(0xFFF0, 0x10, 8)).unwrap()
+            .map(|chunk| chunk.map(|chunk| (chunk.offset, chunk.data)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(chunks, vec![(0xFFF0, (0..8).collect()), (0xFFF8, (8..0x10).collect())]);
+    }
+
+    #[test]
+    fn buffer_sizes_for_frame() {
+        assert_eq!(read_buffer_size_for_frame(64), 31);
+        assert_eq!(read_buffer_size_for_frame(3), 0);
+        assert_eq!(read_buffer_size_for_frame(512), 255);
+        assert_eq!(read_buffer_size_for_frame(513), 255);
+        assert_eq!(read_buffer_size_for_frame(usize::MAX), 255);
+
+        assert_eq!(write_buffer_size_for_frame(64), 29);
+        assert_eq!(write_buffer_size_for_frame(7), 0);
+        assert_eq!(write_buffer_size_for_frame(516), 255);
+        assert_eq!(write_buffer_size_for_frame(usize::MAX), 255);
+    }
+
+    #[test]
+    fn read_request_at_end_of_address_space() {
+        assert_eq!(read_segment_count(&read_request(0, 0x10000, 16)).unwrap(), 0x1000);
+        assert_eq!(read_segment_count(&read_request(0xFFF0, 0x10, 16)).unwrap(), 1);
+        assert_eq!(read_segment_count(&read_request(0xFFF0, 0x11, 16)).ok(), None);
+        assert_eq!(read_segment_count(&read_request(0, 0x10001, 16)).ok(), None);
+    }
+
+    #[test]
+    fn read_request_is_checked_against_memory_and_frame_size() {
+        let mut device = mock_device();
+        let max_buffer_size = read_buffer_size_for_frame(device.serial_frame_size());
+
+        assert_eq!(check_read_request(&mut device, &read_request(0, 0x10000, max_buffer_size)).unwrap(), 0x10000usize.div_ceil(max_buffer_size as usize));
+        assert!(check_read_request(&mut device, &read_request(1, 0x10000, max_buffer_size)).is_err());
+        assert!(check_read_request(&mut device, &read_request(0, 0x100, max_buffer_size + 1)).is_err());
+    }
+
+    #[test]
+    fn write_bounds_are_limited_by_address_space() {
+        let mut device = mock_device();
+
+        assert!(check_write_bounds(&mut device, 0xFFFF, 1).is_ok());
+        assert!(check_write_bounds(&mut device, 0xFFFF, 2).is_err());
+        assert!(check_write_bounds(&mut device, 0x0001, usize::MAX).is_err());
+    }
+}
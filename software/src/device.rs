@@ -1,8 +1,49 @@
+use std::cmp::max;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::fmt::{Display, Formatter};
 use std::io::{ErrorKind, Read, Write};
+use std::thread::sleep;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use anyhow::{anyhow, Context, Error, Result};
-use clap::Args;
-use serialport::SerialPort;
+use clap::{Args, Parser, ValueEnum};
+use serialport::{ClearBuffer, SerialPort};
+use tracing::trace;
+use crate::args::{parse_duration, parse_factor};
+use crate::data_ops::{SplitMix64, DEFAULT_SERIAL_FRAME_SIZE};
+use crate::formats::json_string;
+use crate::metrics;
+use crate::protocol::{parse_memory_size, ChipIdResponse, HexCase, MemorySizeFormat, ProtocolDialect, TestResponse, TransferMode, VersionResponse};
+use crate::signals::check_interrupted;
+
+/// Counter used for synchronization message payloads when system clock is unusable.
+static FALLBACK_SYNC_NONCE: AtomicU64 = AtomicU64::new(1);
+
+/// Returns payload for a synchronization message.
+///
+/// Current timestamp is used when possible.
+/// Clock of boards with dead RTC battery may be set before the epoch, in that case a counter is used instead.
+fn timestamp_sync_nonce() -> u128 {
+    sync_nonce_at(SystemTime::now())
+}
+
+/// Returns payload for a synchronization message sent at `now`.
+fn sync_nonce_at(now: SystemTime) -> u128 {
+    match now.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_micros(),
+        Err(_) => FALLBACK_SYNC_NONCE.fetch_add(1, Ordering::Relaxed) as u128,
+    }
+}
+
+/// Returns seed of retry jitter for a device opened at `now`.
+///
+/// Unlike [sync_nonce_at], works without a counter when the clock is set before the epoch.
+fn jitter_seed(now: SystemTime) -> u64 {
+    match now.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_nanos() as u64,
+        Err(e) => e.duration().as_nanos() as u64,
+    }
+}
 
 #[derive(Copy, Clone, Args)]
 pub struct DeviceSettings {
@@ -10,17 +51,65 @@ pub struct DeviceSettings {
     #[arg(long, default_value_t = 250_000)]
     pub baud_rate: u32,
 
+    /// Try common baud rates if the device does not respond at --baud-rate.
+    ///
+    /// The port is re-opened at each tried baud rate until the device responds.
+    #[arg(long)]
+    pub baud_scan: bool,
+
     /// Default I/O operations timeout
-    #[arg(long, value_parser = humantime::parse_duration, default_value = "1s")]
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
     pub timeout: Duration,
 
     /// Read timeout for first I/O operation.
     ///
     /// For some reason, first operation after connecting the device may take more time than normal,
     /// at least on windows.
-    #[arg(long, value_parser = humantime::parse_duration, default_value = "2s")]
+    #[arg(long, value_parser = parse_duration, default_value = "2s")]
     pub initial_timeout: Duration,
 
+    /// Time allowed for the device to start responding to a read command.
+    ///
+    /// Timeout for the whole response to a read command is this margin plus time needed to
+    /// transfer the response at --baud-rate, so larger chunks get proportionally more time.
+    /// Defaults to --timeout.
+    #[arg(long, value_parser = parse_duration)]
+    pub read_timeout_margin: Option<Duration>,
+
+    /// Request a CRC with every response to a read command and re-read chunks that fail the check.
+    ///
+    /// Catches data corrupted on a noisy serial line. Requires firmware supporting `crc` feature.
+    #[arg(long)]
+    pub checked_reads: bool,
+
+    /// Encoding of transferred data.
+    ///
+    /// Binary transfers are used only if the firmware supports `binary` feature, hex encoding is
+    /// used otherwise.
+    #[arg(long, value_enum, default_value_t = TransferMode::Hex)]
+    pub transfer_mode: TransferMode,
+
+    /// Delay strategy between attempts to transfer a chunk again.
+    ///
+    /// Applies to chunks re-read after failed --checked-reads CRC check and chunks rewritten by
+    /// `--verify=heal`. Without a delay, retries may hammer a link that is still recovering, e.g.
+    /// through a flaky USB hub.
+    #[arg(long, value_enum, default_value_t = RetryBackoff::None)]
+    pub retry_backoff: RetryBackoff,
+
+    /// Delay before the first retry.
+    ///
+    /// With `--retry-backoff exponential` the delay doubles with every next retry of the same chunk.
+    /// Delays never exceed 5s.
+    #[arg(long, value_parser = parse_duration, default_value = "50ms")]
+    pub retry_delay: Duration,
+
+    /// Randomize retry delays between half and full computed delay.
+    ///
+    /// Prevents retries of several devices connected through the same hub from happening in sync.
+    #[arg(long)]
+    pub retry_jitter: bool,
+
     /// Show info (starting with #) messages received from device
     #[arg(long, default_value_t = false)]
     pub show_info_messages: bool,
@@ -29,141 +118,896 @@ pub struct DeviceSettings {
     #[arg(long)]
     pub show_all_messages: bool,
 
+    /// Measure time between sending each command and receiving response to it.
+    ///
+    /// Minimal, average and maximal round-trip time for each command letter is printed to stderr
+    /// when the device is closed, as a table or as a single-line JSON object.
+    #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "text", value_name = "FORMAT")]
+    pub profile: Option<ProfileFormat>,
+
+    /// Delay between opening the port and sending the first command.
+    ///
+    /// Some boards run a bootloader for some time after the port is opened and ignore any data sent
+    /// during that time.
+    #[arg(long, value_parser = parse_duration, default_value = "0s")]
+    pub start_delay: Duration,
+
+    /// Do not discard data left in port buffers before communicating with the device.
+    ///
+    /// By default, stale bytes left by a previous session are discarded, so they are not mistaken
+    /// for responses.
+    #[arg(long)]
+    pub no_initial_flush: bool,
+
+    /// Size of device's serial receive buffer.
+    ///
+    /// Default read and write buffer sizes are computed from it.
+    /// Explicitly set buffer sizes take precedence over computed ones, but read buffer size must not
+    /// exceed what fits into a frame of this size.
+    #[arg(long, default_value_t = DEFAULT_SERIAL_FRAME_SIZE)]
+    pub serial_frame_size: usize,
+
+    /// Maximal length of a line received from the device.
+    ///
+    /// By default, the limit depends on the expected response and is between 16 and 128 bytes.
+    /// Firmware builds emitting long info messages may require a larger limit.
+    /// Limits required by data transfer commands are never lowered by this option.
+    #[arg(long)]
+    pub max_line: Option<usize>,
+
+    /// Number format used by the firmware to report memory size.
+    #[arg(long, value_enum, default_value_t = MemorySizeFormat::Hex)]
+    pub memory_size_format: MemorySizeFormat,
+
+    /// File describing protocol dialect of a non-standard firmware.
+    ///
+    /// The file consists of `key = character` lines, where key is one of `read`, `write`, `version`,
+    /// `external_control`, `test`, `memory_size`, `ping`, `ping_response`, `info`, `error`,
+    /// `binary_read`, `binary_write`, `chip_id`, `read_response` and `write_response`.
+    /// Characters not mentioned in the file keep their standard values.
+    #[arg(long = "protocol-dialect", value_parser = ProtocolDialect::load)]
+    pub dialect: Option<ProtocolDialect>,
+
+    /// Character expected at the start of responses to read commands.
+    ///
+    /// Overrides --protocol-dialect. Defaults to the read command character.
+    #[arg(long)]
+    pub expect_read_prefix: Option<char>,
+
+    /// Character expected at the start of responses to write commands.
+    ///
+    /// Overrides --protocol-dialect. Defaults to the write command character.
+    #[arg(long)]
+    pub expect_write_prefix: Option<char>,
+
+    /// Case of hexadecimal digits in read and write commands.
+    ///
+    /// Some firmware variants parse commands case-sensitively and accept only lowercase digits.
+    #[arg(long, value_enum, default_value_t = HexCase::Upper)]
+    pub command_hex_case: HexCase,
+
     /// Timeout for stream synchronization operation
-    #[arg(long, value_parser = humantime::parse_duration, default_value = "2s")]
+    #[arg(long, value_parser = parse_duration, default_value = "2s")]
     pub sync_timeout: Duration,
+
+    /// Multiply all timeouts (--timeout, --initial-timeout, --sync-timeout and
+    /// --read-timeout-margin) by given factor.
+    ///
+    /// Useful for slow connections, e.g. through USB hubs or network serial bridges.
+    #[arg(long, value_parser = parse_factor, default_value_t = 1.0)]
+    pub timeout_scaling: f64,
+
+    /// Fixed payload of synchronization (ping) messages.
+    ///
+    /// By default, current timestamp is used.
+    #[arg(long, hide = true)]
+    pub sync_nonce: Option<u64>,
+}
+
+/// Strategy of delays between retries of a failed transfer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum RetryBackoff {
+    /// Retry immediately
+    #[default]
+    None,
+    /// Wait --retry-delay before each retry
+    Fixed,
+    /// Double the delay with each retry, starting from --retry-delay
+    Exponential,
+}
+
+/// Upper limit of delays between retries.
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+impl RetryBackoff {
+    /// Computes delay before `retry`-th (starting from 1) retry.
+    pub fn delay(self, base: Duration, retry: usize) -> Duration {
+        let delay = match self {
+            RetryBackoff::None => Duration::ZERO,
+            RetryBackoff::Fixed => base,
+            RetryBackoff::Exponential => {
+                let factor = 1u32.checked_shl(retry.saturating_sub(1) as u32).unwrap_or(u32::MAX);
+
+                base.saturating_mul(factor)
+            }
+        };
+
+        delay.min(MAX_RETRY_DELAY)
+    }
+}
+
+/// Wrapper used to get default values of [DeviceSettings] from their argument definitions.
+#[derive(Parser)]
+struct DefaultDeviceSettings {
+    #[command(flatten)]
+    settings: DeviceSettings,
 }
 
+impl Default for DeviceSettings {
+    /// Returns settings used when no device options are given on command line.
+    fn default() -> Self {
+        DefaultDeviceSettings::parse_from(["romectl"]).settings
+    }
+}
+
+impl DeviceSettings {
+    /// Returns settings with all timeouts multiplied by --timeout-scaling factor.
+    pub fn with_scaled_timeouts(&self) -> Self {
+        let factor = self.timeout_scaling;
+
+        Self {
+            timeout: self.timeout.mul_f64(factor),
+            initial_timeout: self.initial_timeout.mul_f64(factor),
+            sync_timeout: self.sync_timeout.mul_f64(factor),
+            read_timeout_margin: self.read_timeout_margin.map(|margin| margin.mul_f64(factor)),
+            timeout_scaling: 1.0,
+            ..*self
+        }
+    }
+
+    /// Returns protocol dialect defined by --protocol-dialect with response prefix and hex case
+    /// overrides applied.
+    pub fn protocol_dialect(&self) -> ProtocolDialect {
+        let mut dialect = self.dialect.unwrap_or_default();
+        dialect.hex_case = self.command_hex_case;
+
+        if self.expect_read_prefix.is_some() {
+            dialect.read_response = self.expect_read_prefix;
+        }
+        if self.expect_write_prefix.is_some() {
+            dialect.write_response = self.expect_write_prefix;
+        }
+
+        dialect
+    }
+}
+
+/// Share of non-printable bytes in a response above which it's considered garbled.
+const GARBLED_RESPONSE_THRESHOLD: f64 = 0.25;
+
+/// Checks whether a response contains so many non-printable bytes that it's likely received at wrong
+/// baud rate.
+fn looks_garbled(response: &[u8]) -> bool {
+    let non_printable = response.iter()
+        .filter(|b| !(b.is_ascii_graphic() || **b == b' ' || **b == b'\t'))
+        .count();
+
+    !response.is_empty() && non_printable as f64 > response.len() as f64 * GARBLED_RESPONSE_THRESHOLD
+}
+
+/// Memory size of devices that do not support memory size query.
+pub const DEFAULT_MEMORY_SIZE: usize = 0x10000;
+
+/// Defines which messages received from the device are returned to the caller.
+///
+/// Empty lines are always skipped.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReceiveOptions {
+    /// Return info messages instead of skipping them.
+    pub return_info: bool,
+    /// Return error messages as received lines instead of failing with [DeviceError].
+    pub return_errors: bool,
+}
+
+impl ReceiveOptions {
+    /// Options used by regular commands: only responses are returned.
+    pub const STRICT: Self = Self { return_info: false, return_errors: false };
+    /// Every non-empty line is returned.
+    pub const LENIENT: Self = Self { return_info: true, return_errors: true };
+}
+
+/// Error message sent by the device.
+#[derive(Debug)]
+pub struct DeviceError(pub String);
+
+impl Display for DeviceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Device returned error: {}", self.0)
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+/// Oldest firmware version supported by this program.
+pub const MIN_FIRMWARE_VERSION: FirmwareVersion = FirmwareVersion(0, 0, 1);
+
+/// Numeric part of firmware version.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FirmwareVersion(pub u32, pub u32, pub u32);
+
+impl FirmwareVersion {
+    /// Parses version like `0.1.0` ignoring non-numeric suffixes, so `0.0.1a` is parsed as `0.0.1`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parts = text.splitn(3, '.').map(|part| {
+            let digits = part.find(|c: char| !c.is_ascii_digit()).map_or(part, |end| &part[..end]);
+            digits.parse::<u32>().ok()
+        });
+
+        Some(Self(parts.next()??, parts.next()??, parts.next().unwrap_or(Some(0))?))
+    }
+}
+
+impl Display for FirmwareVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// Optional protocol feature.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// Memory size query.
+    MemorySize,
+    /// Checksum of memory region.
+    Checksum,
+    /// CRC appended to responses to read commands on request.
+    ReadCrc,
+    /// Read and write commands transferring raw bytes.
+    Binary,
+    /// Manufacturer and device ID of the memory chip.
+    ChipId,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 5] = [
+        Capability::MemorySize,
+        Capability::Checksum,
+        Capability::ReadCrc,
+        Capability::Binary,
+        Capability::ChipId,
+    ];
+
+    /// Name of the feature in firmware version banner.
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::MemorySize => "size",
+            Capability::Checksum => "checksum",
+            Capability::ReadCrc => "crc",
+            Capability::Binary => "binary",
+            Capability::ChipId => "id",
+        }
+    }
+}
+
+/// Set of optional protocol features supported by firmware.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub fn insert(&mut self, capability: Capability) {
+        self.0 |= 1 << capability as u32;
+    }
+
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.0 & (1 << capability as u32) != 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=Capability> + '_ {
+        Capability::ALL.into_iter().filter(|capability| self.contains(*capability))
+    }
+}
+
+/// Information reported by firmware in response to version request.
+#[derive(Clone, Debug)]
+pub struct FirmwareInfo {
+    /// Full version string, e.g. `ROME-0.1.0 size`.
+    pub banner: String,
+    /// Numeric version, if it could be recognized.
+    pub version: Option<FirmwareVersion>,
+    pub capabilities: Capabilities,
+}
+
+impl FirmwareInfo {
+    /// Parses version banner consisting of `ROME-<version>` optionally followed by space-separated
+    /// names of supported features. Unknown features are ignored.
+    pub fn parse(banner: &str) -> Self {
+        let mut words = banner.split_whitespace();
+        let version = words.next()
+            .and_then(|name| name.strip_prefix("ROME-"))
+            .and_then(FirmwareVersion::parse);
+        let mut capabilities = Capabilities::default();
+
+        for word in words {
+            if let Some(capability) = Capability::ALL.into_iter().find(|c| c.name() == word) {
+                capabilities.insert(capability);
+            }
+        }
+
+        Self { banner: banner.to_string(), version, capabilities }
+    }
+}
+
+/// Computes time needed to transfer `bytes` bytes over serial line at given baud rate.
+///
+/// Each byte takes 10 bits on the line: start bit, 8 data bits and stop bit.
+pub fn transfer_time(baud_rate: u32, bytes: usize) -> Duration {
+    Duration::from_secs_f64((bytes * 10) as f64 / baud_rate.max(1) as f64)
+}
+
+/// Format of round-trip time statistics printed by `--profile`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ProfileFormat {
+    /// Human-readable table
+    Text,
+    /// JSON object with statistics of each command
+    Json,
+}
+
+#[derive(Copy, Clone)]
+struct CommandLatency {
+    count: u64,
+    min: Duration,
+    max: Duration,
+    total: Duration,
+}
+
+/// Round-trip times of commands sent to the device, collected when `--profile` is enabled.
+#[derive(Default)]
+struct Profile {
+    pending: Option<(char, Instant)>,
+    commands: BTreeMap<char, CommandLatency>,
+}
+
+impl Profile {
+    fn record(&mut self) {
+        let Some((command, sent_at)) = self.pending.take() else { return; };
+        let elapsed = sent_at.elapsed();
+
+        self.commands.entry(command)
+            .and_modify(|latency| {
+                latency.count += 1;
+                latency.min = latency.min.min(elapsed);
+                latency.max = latency.max.max(elapsed);
+                latency.total += elapsed;
+            })
+            .or_insert(CommandLatency { count: 1, min: elapsed, max: elapsed, total: elapsed });
+    }
+
+    fn print(&self, format: ProfileFormat) {
+        match format {
+            ProfileFormat::Text => {
+                eprintln!("{:<8} {:>8} {:>12} {:>12} {:>12}", "Command", "Count", "Min", "Avg", "Max");
+
+                for (command, latency) in self.commands.iter() {
+                    eprintln!(
+                        "{:<8} {:>8} {:>12} {:>12} {:>12}",
+                        command,
+                        latency.count,
+                        format!("{:.3?}", latency.min),
+                        format!("{:.3?}", latency.total / latency.count as u32),
+                        format!("{:.3?}", latency.max),
+                    );
+                }
+            }
+            ProfileFormat::Json => {
+                let commands = self.commands.iter()
+                    .map(|(command, latency)| format!(
+                        "{}:{{\"count\":{},\"min_us\":{},\"avg_us\":{},\"max_us\":{}}}",
+                        json_string(&command.to_string()),
+                        latency.count,
+                        latency.min.as_micros(),
+                        (latency.total / latency.count as u32).as_micros(),
+                        latency.max.as_micros(),
+                    ))
+                    .collect::<Vec<_>>();
+
+                eprintln!("{{\"profile\":{{{}}}}}", commands.join(","));
+            }
+        }
+    }
+}
+
+/// Number of attempts to flush the port after sending a command.
+const FLUSH_ATTEMPTS: usize = 3;
+
+/// Delay after a read that returned no data, so drivers that don't wait for data don't make the
+/// receiving loop spin.
+const EMPTY_READ_DELAY: Duration = Duration::from_millis(1);
+
+/// Number of last info messages kept by [Device] to be shown when an operation fails.
+pub const RECENT_INFO_MESSAGES_LIMIT: usize = 16;
+
 pub struct Device {
     name: String,
     settings: DeviceSettings,
     default_timeout_applied: bool,
+    memory_size: Option<usize>,
+    recent_info_messages: VecDeque<String>,
+    firmware: Option<FirmwareInfo>,
+    line_deadline: Option<Instant>,
+    profile: Option<Profile>,
+    /// Source of --retry-jitter delays, independent of synchronization message payloads.
+    jitter: SplitMix64,
     port: Box<dyn SerialPort>,
 }
 
+impl Drop for Device {
+    fn drop(&mut self) {
+        if let (Some(profile), Some(format)) = (self.profile.as_ref(), self.settings.profile) {
+            profile.print(format);
+        }
+    }
+}
+
+const PORT_BUSY_HINT: &str = "port is busy or unavailable. Close other programs using it (e.g. serial monitors) and try again";
+
+/// Converts error of port opening into an error explaining common causes of the failure.
+fn open_error(port_name: &str, err: serialport::Error) -> Error {
+    let hint = match err.kind() {
+        serialport::ErrorKind::Io(ErrorKind::PermissionDenied) => Some(
+            "permission denied. Add your user to the group owning the port (usually `dialout` or `uucp`) \
+            and log in again, or run with sufficient privileges"
+        ),
+        serialport::ErrorKind::Io(ErrorKind::NotFound) => Some(
+            "port does not exist. Check that the device is connected and use `port list` to see available ports"
+        ),
+        serialport::ErrorKind::NoDevice | serialport::ErrorKind::Io(ErrorKind::ResourceBusy) => Some(PORT_BUSY_HINT),
+        // EBUSY is not mapped to a distinct error kind on unix, only its description tells it apart
+        serialport::ErrorKind::Unknown if err.description.to_lowercase().contains("busy") => Some(PORT_BUSY_HINT),
+        _ => None,
+    };
+    let message = match hint {
+        Some(hint) => format!("Error opening port {}: {}", port_name, hint),
+        None => format!("Error opening port {}", port_name),
+    };
+
+    Error::new(err).context(message)
+}
+
 fn is_timeout(err: &Error) -> bool {
     if let Some(io_error) = err.root_cause().downcast_ref::<std::io::Error>() {
         return io_error.kind() == ErrorKind::TimedOut;
     }
 
-    return false;
+    false
 }
 
 impl Device {
     pub fn new(port_name: &str, settings: &DeviceSettings) -> Result<Self> {
         let port = serialport::new(port_name, settings.baud_rate)
-            .timeout(settings.initial_timeout)
+            .timeout(settings.with_scaled_timeouts().initial_timeout)
             .open()
-            .context("Error opening port")?;
+            .map_err(|e| open_error(port_name, e))?;
+
+        if !settings.start_delay.is_zero() {
+            sleep(settings.start_delay);
+        }
 
-        Ok(Self {
-            name: port_name.to_string(),
-            settings: settings.clone(),
+        Ok(Self::with_port(port_name, port, settings))
+    }
+
+    /// Creates a device communicating through an already opened port.
+    ///
+    /// The port is used as is: its baud rate is not changed and --start-delay is not waited.
+    /// `name` is used only to identify the device in messages.
+    /// Optional firmware features are not used until [Device::check] is called.
+    pub fn with_port(name: &str, port: Box<dyn SerialPort>, settings: &DeviceSettings) -> Self {
+        Self {
+            name: name.to_string(),
+            settings: settings.with_scaled_timeouts(),
             default_timeout_applied: false,
+            memory_size: None,
+            recent_info_messages: VecDeque::with_capacity(RECENT_INFO_MESSAGES_LIMIT),
+            firmware: None,
+            line_deadline: None,
+            profile: settings.profile.map(|_| Profile::default()),
+            jitter: SplitMix64(jitter_seed(SystemTime::now())),
             port,
-        })
+        }
     }
 
     pub fn name(&self) -> &str {
         self.name.as_str()
     }
 
+    pub fn dialect(&self) -> ProtocolDialect {
+        self.settings.protocol_dialect()
+    }
+
+    pub fn serial_frame_size(&self) -> usize {
+        self.settings.serial_frame_size
+    }
+
+    /// Waits before `retry`-th (starting from 1) retry of a failed transfer as configured by
+    /// --retry-backoff.
+    pub fn wait_before_retry(&mut self, retry: usize) {
+        let mut delay = self.settings.retry_backoff.delay(self.settings.retry_delay, retry);
+
+        if self.settings.retry_jitter {
+            let random = self.jitter.next();
+
+            delay = delay / 2 + (delay / 2).mul_f64(random as f64 / u64::MAX as f64);
+        }
+
+        metrics::record_retry();
+
+        if !delay.is_zero() {
+            trace!(retry, delay = ?delay, "wait_before_retry");
+            sleep(delay);
+        }
+    }
+
+    /// Checks whether read responses should be protected by CRC.
+    pub fn checked_reads(&self) -> bool {
+        self.settings.checked_reads
+    }
+
+    /// Checks whether data should be transferred as raw bytes instead of hexadecimal digits.
+    pub fn binary_transfers(&self) -> bool {
+        self.settings.transfer_mode == TransferMode::Binary && self.supports(Capability::Binary)
+    }
+
     pub fn send(&mut self, command: &[u8]) -> Result<()> {
+        check_interrupted()?;
+        trace!(bytes = command.len(), "send");
+
         if self.settings.show_all_messages {
             eprintln!("sending: {}", String::from_utf8_lossy(command).trim_end());
         }
 
-        self.port.write(command)
+        self.port.write_all(command)
             .context("Error sending command")?;
-        self.port.flush()?;
+        self.flush_port()?;
+
+        // Commands may be preceded by new lines, as the ping message sent by sync is
+        let letter = command.iter().find(|b| **b != b'\n');
+
+        if let (Some(profile), Some(letter)) = (self.profile.as_mut(), letter) {
+            profile.pending = Some((*letter as char, Instant::now()));
+        }
 
         Ok(())
     }
 
+    /// Flushes the port, retrying if flush fails.
+    ///
+    /// Some USB-serial drivers intermittently report flush errors even though the data was written.
+    fn flush_port(&mut self) -> Result<()> {
+        let mut attempt = 1;
+
+        loop {
+            match self.port.flush() {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < FLUSH_ATTEMPTS => {
+                    trace!(attempt, error = %err, "flush failed");
+                    attempt += 1;
+                }
+                Err(err) => return Err(Error::new(err).context("Error flushing port")),
+            }
+        }
+    }
+
     fn show_inbound_message(&self, msg: &[u8]) {
-        if self.settings.show_all_messages || (self.settings.show_info_messages && matches!(msg.first(), Some(c) if *c == b'#')) {
+        if self.settings.show_all_messages || (self.settings.show_info_messages && msg.first() == Some(&(self.dialect().info as u8))) {
             eprintln!("received: {}", String::from_utf8_lossy(msg));
         }
     }
 
-    fn receive_line_raw(&mut self, buffer: &mut Vec<u8>, limit: usize) -> Result<()> {
+    fn current_timeout(&self) -> Duration {
+        if self.default_timeout_applied {
+            self.settings.timeout
+        } else {
+            self.settings.initial_timeout
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8> {
+        let deadline = Instant::now() + self.current_timeout();
+        self.read_byte_until(deadline)
+    }
+
+    /// Reads a single byte, failing if it is not received before `deadline`.
+    fn read_byte_until(&mut self, deadline: Instant) -> Result<u8> {
         let mut b: [u8; 1] = [0; 1];
 
+        loop {
+            // Some drivers return no data instead of timeout error, so the timeout is checked here as well
+            if self.line_deadline.is_some_and(|deadline| Instant::now() > deadline) {
+                return Err(std::io::Error::new(ErrorKind::TimedOut, "Response was not received in time").into());
+            }
+            if Instant::now() > deadline {
+                return Err(std::io::Error::new(ErrorKind::TimedOut, "No data received from device").into());
+            }
+
+            let size = match self.port.read(&mut b) {
+                // Reads are interrupted by signals, stop only if the signal asks to terminate
+                Err(e) if e.kind() == ErrorKind::Interrupted => {
+                    check_interrupted()?;
+                    continue;
+                }
+                result => result?,
+            };
+
+            if !self.default_timeout_applied {
+                self.port.set_timeout(self.settings.timeout)?;
+                self.default_timeout_applied = true;
+            }
+
+            if size != 0 {
+                return Ok(b[0]);
+            }
+
+            sleep(EMPTY_READ_DELAY);
+        }
+    }
+
+    /// Reads a line into `buffer`, failing if the whole line is not received within the current
+    /// timeout (or before the deadline set for the response), even if the device keeps sending
+    /// bytes.
+    fn receive_line_raw(&mut self, buffer: &mut Vec<u8>, limit: usize) -> Result<()> {
+        let limit = self.settings.max_line.map_or(limit, |max_line| max(limit, max_line));
+        let deadline = self.line_deadline.unwrap_or_else(|| Instant::now() + self.current_timeout());
+
         loop {
             if buffer.len() > limit {
                 return Err(anyhow!("Response size exceeds limit of {} bytes", limit));
             }
 
-            if self.port.read(&mut b)? != 0 {
-                if b[0] == b'\n' {
+            match self.read_byte_until(deadline)? {
+                b'\n' => {
                     self.show_inbound_message(buffer.as_slice());
                     return Ok(());
-                } else {
-                    buffer.push(b[0]);
                 }
+                b => buffer.push(b),
             }
+        }
+    }
 
-            if !self.default_timeout_applied {
-                self.port.set_timeout(self.settings.timeout)?;
+    fn record_info_message(&mut self, line: &[u8]) {
+        if self.recent_info_messages.len() >= RECENT_INFO_MESSAGES_LIMIT {
+            self.recent_info_messages.pop_front();
+        }
+        self.recent_info_messages.push_back(String::from_utf8_lossy(&line[1..]).trim().to_string());
+    }
+
+    /// Receives a binary response: `prefix` character, a byte with data size, the data followed by
+    /// `trailer_size` more bytes and a new line character.
+    /// The data including the trailer replaces content of `data`.
+    ///
+    /// Info messages received before the response are handled the same way as by
+    /// [Device::receive_into]. Fails if the whole response is not received within `timeout`.
+    pub fn receive_binary_into_within(
+        &mut self,
+        prefix: char,
+        data: &mut Vec<u8>,
+        max_size: usize,
+        trailer_size: usize,
+        timeout: Duration,
+    ) -> Result<()> {
+        self.port.set_timeout(timeout.min(self.current_timeout()))?;
+        self.line_deadline = Some(Instant::now() + timeout);
+
+        let result = self.receive_binary_into(prefix, data, max_size, trailer_size);
+
+        self.line_deadline = None;
+        // The initial timeout may have been replaced by the default one while receiving
+        self.port.set_timeout(self.current_timeout())?;
+
+        result
+    }
+
+    fn receive_binary_into(&mut self, prefix: char, data: &mut Vec<u8>, max_size: usize, trailer_size: usize) -> Result<()> {
+        let dialect = self.dialect();
+        data.clear();
+
+        loop {
+            let first = self.read_byte()?;
+
+            if first == prefix as u8 {
+                break;
             }
+            if first == b'\n' {
+                continue;
+            }
+
+            let mut line = vec![first];
+            self.receive_line_raw(&mut line, 64)?;
+
+            match line[0] as char {
+                c if c == dialect.info => self.record_info_message(line.as_slice()),
+                c if c == dialect.error => {
+                    return Err(DeviceError(String::from_utf8_lossy(&line.as_slice()[1..]).trim().to_string()).into());
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "Received unexpected response to '{}' command: '{}'",
+                        prefix,
+                        String::from_utf8_lossy(line.as_slice()),
+                    ));
+                }
+            }
+        }
+
+        let size = self.read_byte()? as usize;
+        if size > max_size {
+            return Err(anyhow!("Binary response of {} bytes exceeds expected size of {} bytes", size, max_size));
+        }
+
+        for _ in 0..size + trailer_size {
+            data.push(self.read_byte()?);
+        }
+
+        match self.read_byte()? {
+            b'\n' => {}
+            b => {
+                return Err(anyhow!("Binary response is not terminated by a new line: got 0x{:02X} after {} bytes of data", b, size));
+            }
+        }
+
+        if self.settings.show_all_messages {
+            eprintln!("received: {} ({} bytes of binary data)", prefix, size);
+        }
+        trace!(bytes = size + trailer_size + 3, "receive");
+        self.record_response_time();
+
+        Ok(())
+    }
+
+    fn record_response_time(&mut self) {
+        if let Some(profile) = self.profile.as_mut() {
+            profile.record();
         }
     }
 
     pub fn receive(&mut self, limit: usize) -> Result<Vec<u8>> {
         let mut line = vec![];
+        self.receive_into(&mut line, limit)?;
+        Ok(line)
+    }
+
+    /// Receives the first non-empty line, including info and error messages.
+    ///
+    /// Useful for tools that need to see exactly what the device sent.
+    pub fn receive_lenient(&mut self, limit: usize) -> Result<Vec<u8>> {
+        let mut line = vec![];
+        self.receive_into_with_options(&mut line, limit, ReceiveOptions::LENIENT)?;
+        Ok(line)
+    }
+
+    /// Same as [Device::receive] but stores received line in given buffer, replacing its content.
+    pub fn receive_into(&mut self, line: &mut Vec<u8>, limit: usize) -> Result<()> {
+        self.receive_into_with_options(line, limit, ReceiveOptions::STRICT)
+    }
+
+    /// Same as [Device::receive_into] but `options` define which kinds of messages are returned.
+    pub fn receive_into_with_options(&mut self, line: &mut Vec<u8>, limit: usize, options: ReceiveOptions) -> Result<()> {
+        let dialect = self.dialect();
+        line.clear();
 
         loop {
-            self.receive_line_raw(&mut line, limit)?;
+            self.receive_line_raw(line, limit)?;
 
-            match line.first().cloned() {
+            match line.first().map(|c| *c as char) {
                 None => { continue; }
-                Some(b'#') => {
+                Some(c) if (c == dialect.info && options.return_info) || (c == dialect.error && options.return_errors) => {
+                    trace!(bytes = line.len(), "receive");
+                    return Ok(());
+                }
+                Some(c) if c == dialect.info => {
+                    self.record_info_message(line.as_slice());
                     line.clear();
                 }
-                Some(b'!') => {
-                    return Err(anyhow!(
-                        "Device returned error: {}",
-                        String::from_utf8_lossy(&line.as_slice()[1..]).trim(),
-                    ));
+                Some(c) if c == dialect.error => {
+                    return Err(DeviceError(
+                        String::from_utf8_lossy(&line.as_slice()[1..]).trim().to_string()
+                    ).into());
                 }
                 Some(_) => {
-                    return Ok(line);
+                    trace!(bytes = line.len(), "receive");
+                    self.record_response_time();
+                    return Ok(());
                 }
             }
         }
     }
 
+    /// Same as [Device::receive_into] but fails if the whole line is not received within `timeout`.
+    pub fn receive_into_within(&mut self, line: &mut Vec<u8>, limit: usize, timeout: Duration) -> Result<()> {
+        self.port.set_timeout(timeout.min(self.current_timeout()))?;
+        self.line_deadline = Some(Instant::now() + timeout);
+
+        let result = self.receive_into(line, limit);
+
+        self.line_deadline = None;
+        // The initial timeout may have been replaced by the default one while receiving
+        self.port.set_timeout(self.current_timeout())?;
+
+        result
+    }
+
+    /// Returns timeout for a response of given size to a read command.
+    pub fn read_response_timeout(&self, response_size: usize) -> Duration {
+        transfer_time(self.settings.baud_rate, response_size)
+            + self.settings.read_timeout_margin.unwrap_or(self.settings.timeout)
+    }
+
+    /// Returns last info messages received from the device, oldest first.
+    pub fn recent_info_messages(&self) -> impl Iterator<Item=&str> {
+        self.recent_info_messages.iter().map(String::as_str)
+    }
+
+    /// Prints last info messages received from the device, to give context for a failed operation.
+    ///
+    /// Does nothing if info messages were already shown as they were received.
+    pub fn print_recent_info_messages(&self) {
+        if self.settings.show_info_messages || self.settings.show_all_messages || self.recent_info_messages.is_empty() {
+            return;
+        }
+
+        eprintln!("Last info messages received from device:");
+
+        for message in self.recent_info_messages() {
+            eprintln!("  # {}", message);
+        }
+    }
+
     pub fn receive_with_timeout(&mut self, limit: usize, timeout: Duration) -> Result<Vec<u8>> {
         let end_time = Instant::now() + timeout;
 
         loop {
             match self.receive(limit) {
-                Err(e) if is_timeout(&e) && Instant::now() < end_time => {},
+                Err(e) if is_timeout(&e) && Instant::now() < end_time => {
+                    check_interrupted()?;
+                },
                 res => { return res; }
             }
         }
     }
 
-    fn sync(&mut self) -> Result<()> {
-        let message = format!("\nP{}\n", SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros());
+    /// Sends a ping message with unique payload and skips received data until the matching response.
+    pub fn sync(&mut self) -> Result<()> {
+        let dialect = self.dialect();
+        let nonce = match self.settings.sync_nonce {
+            Some(nonce) => nonce as u128,
+            None => timestamp_sync_nonce(),
+        };
+        let message = format!("\n{}{}\n", dialect.ping, nonce);
         self.send(message.as_bytes())?;
 
-        let sync_deadline = SystemTime::now() + self.settings.sync_timeout;
+        let sync_deadline = Instant::now() + self.settings.sync_timeout;
 
         let mut receive_buffer = vec![];
 
-        let expected_payload = &message.trim().as_bytes()[1..];
+        let sent_message = message.trim().as_bytes();
+        let expected_payload = &sent_message[1..];
 
         loop {
             self.receive_line_raw(&mut receive_buffer, 64)?;
 
-            if matches!(receive_buffer.first(), Some(c) if *c == b'p') {
-                if &receive_buffer[1..] == expected_payload {
-                    return Ok(());
-                }
+            if receive_buffer == sent_message {
+                return Err(anyhow!(
+                    "Received back exactly the same message that was sent ('{}'). \
+                    This is likely a loopback / wiring error: check that RX and TX lines are not swapped or connected together",
+                    message.trim(),
+                ));
             }
 
-            if SystemTime::now() > sync_deadline {
+            if receive_buffer.first() == Some(&(dialect.ping_response as u8)) && &receive_buffer[1..] == expected_payload {
+                self.record_response_time();
+                return Ok(());
+            }
+
+            if Instant::now() > sync_deadline {
                 return Err(anyhow!("Sync timeout exceeded"));
             }
 
@@ -172,31 +1016,125 @@ impl Device {
     }
 
     pub fn check(&mut self) -> Result<()> {
+        if !self.settings.no_initial_flush {
+            self.port.clear(ClearBuffer::All).context("Error discarding stale data in port buffers")?;
+        }
+
         if let Err(e) = self.sync() {
-            if is_timeout(&e) {
-                eprintln!("Got timeout, trying to synchronize again...");
-                self.sync()
-                    .context("Error synchronizing with device - it did not respond correctly to ping message")?;
+            if !is_timeout(&e) {
+                return Err(e);
             }
+
+            eprintln!("Got timeout, trying to synchronize again...");
+            self.sync()
+                .context("Error synchronizing with device - it did not respond correctly to ping message")?;
         };
 
-        self.send(b"V\n")?;
-        let response = self.receive(24)?;
-        if !response.starts_with(b"VROME") {
-            return Err(anyhow!(
-                "Unexpected response for 'V' command: {}",
+        let version_command = self.dialect().version;
+        self.send(format!("{}\n", version_command).as_bytes())?;
+        let response = self.receive(64)?;
+        if !response.starts_with(format!("{}ROME", version_command).as_bytes()) {
+            let error = anyhow!(
+                "Unexpected response for '{}' command: {}",
+                version_command,
                 String::from_utf8_lossy(response.as_slice())
-            ));
+            );
+
+            if looks_garbled(response.as_slice()) {
+                return Err(error.context(format!(
+                    "Device response looks garbled, probably the port baud rate ({}) doesn't match the firmware. \
+                    Set correct baud rate with --baud-rate or try common baud rates with --baud-scan",
+                    self.settings.baud_rate,
+                )));
+            }
+
+            return Err(error);
+        }
+
+        let firmware = FirmwareInfo::parse(&String::from_utf8_lossy(&response.as_slice()[1..]));
+        match firmware.version {
+            Some(version) if version < MIN_FIRMWARE_VERSION => {
+                return Err(anyhow!(
+                    "Firmware version {} is too old, at least {} is required",
+                    version,
+                    MIN_FIRMWARE_VERSION,
+                ));
+            }
+            Some(_) => {}
+            None => {
+                eprintln!("Warning: cannot recognize firmware version in '{}'", firmware.banner);
+            }
+        }
+        self.firmware = Some(firmware);
+        // The device may have been replaced or reconfigured since memory size was queried
+        self.memory_size = None;
+
+        if self.settings.transfer_mode == TransferMode::Binary && !self.supports(Capability::Binary) {
+            eprintln!("Warning: firmware does not support binary transfers, using hex transfer mode");
         }
 
         Ok(())
     }
 
+    /// Returns information about firmware received during [Device::check].
+    pub fn firmware_info(&self) -> Option<&FirmwareInfo> {
+        self.firmware.as_ref()
+    }
+
+    /// Checks whether the firmware supports given optional feature.
+    ///
+    /// Features are assumed to be unsupported until the firmware is checked by [Device::check].
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.firmware.as_ref().is_some_and(|firmware| firmware.capabilities.contains(capability))
+    }
+
+    /// Fails with a descriptive error if the firmware does not support feature required for
+    /// `operation`.
+    pub fn require(&self, capability: Capability, operation: &str) -> Result<()> {
+        if self.supports(capability) {
+            return Ok(());
+        }
+        if self.firmware.is_none() {
+            return Err(anyhow!(
+                "'{}' feature required for {} can't be used before firmware version is checked",
+                capability.name(),
+                operation,
+            ));
+        }
+
+        Err(anyhow!(
+            "Firmware too old for {}: '{}' feature is not supported by firmware {}",
+            operation,
+            capability.name(),
+            self.firmware.as_ref().map_or("", |firmware| firmware.banner.as_str()),
+        ))
+    }
+
+    /// Queries firmware version string.
+    pub fn version(&mut self) -> Result<String> {
+        let version_command = self.dialect().version;
+        self.send(format!("{}\n", version_command).as_bytes())?;
+        let response = self.receive(64)?;
+
+        Ok(VersionResponse::parse(response.as_slice(), &self.dialect())?.version)
+    }
+
+    /// Runs firmware's built-in memory test.
+    ///
+    /// Returns the test result with diagnostic detail reported by firmware, if any.
+    pub fn run_test(&mut self, timeout: Duration) -> Result<TestResponse> {
+        let dialect = self.dialect();
+        self.send(format!("{}\n", dialect.test).as_bytes())?;
+
+        TestResponse::parse(self.receive_with_timeout(128, timeout)?.as_slice(), &dialect)
+    }
+
     pub fn enable_external_control(&mut self) -> Result<()> {
-        self.send(b"E\n")?;
+        let command = self.dialect().external_control;
+        self.send(format!("{}\n", command).as_bytes())?;
 
         match self.receive(64)?.as_slice() {
-            b"EOK" => Ok(()),
+            x if x == format!("{}OK", command).as_bytes() => Ok(()),
             x => Err(anyhow!(
                 "Unexpected response received: {}",
                 String::from_utf8_lossy(x),
@@ -204,8 +1142,266 @@ impl Device {
         }
     }
 
+    /// Reads manufacturer and device ID of the memory chip.
+    ///
+    /// Fails if the firmware does not support `id` feature.
+    pub fn chip_id(&mut self) -> Result<Vec<u8>> {
+        self.require(Capability::ChipId, "reading chip ID")?;
+
+        let dialect = self.dialect();
+        self.send(format!("{}\n", dialect.chip_id).as_bytes())?;
+
+        Ok(ChipIdResponse::parse(self.receive(64)?.as_slice(), &dialect)?.id)
+    }
+
+    /// Returns size of device memory.
+    ///
+    /// The size reported by the device is cached until the device is checked again by
+    /// [Device::check]. Falls back to [DEFAULT_MEMORY_SIZE] if the firmware does not support the
+    /// query or reports the size in unrecognized format.
     pub fn memory_size(&mut self) -> Result<usize> {
-        // TODO: Support devices with 32KiB (24257) memory (?)
-        Ok(0x10000)
+        if let Some(size) = self.memory_size {
+            return Ok(size);
+        }
+
+        match self.query_memory_size()? {
+            Some(size) => {
+                self.memory_size = Some(size);
+                Ok(size)
+            }
+            None => Ok(DEFAULT_MEMORY_SIZE),
+        }
+    }
+
+    /// Queries memory size from the device, returns `None` if the size is not known.
+    fn query_memory_size(&mut self) -> Result<Option<usize>> {
+        if !self.supports(Capability::MemorySize) {
+            return Ok(None);
+        }
+
+        let command = self.dialect().memory_size;
+        self.send(format!("{}\n", command).as_bytes())?;
+
+        match self.receive(32) {
+            Ok(response) if response.first() == Some(&(command as u8)) => {
+                let text = String::from_utf8_lossy(&response.as_slice()[1..]);
+
+                match parse_memory_size(&text, self.settings.memory_size_format) {
+                    Ok(size) => Ok(Some(size)),
+                    Err(e) => {
+                        eprintln!("Warning: {:#}. Assuming memory size of 0x{:X} bytes", e, DEFAULT_MEMORY_SIZE);
+                        Ok(None)
+                    }
+                }
+            }
+            Ok(response) => {
+                eprintln!(
+                    "Warning: unexpected response to memory size query: '{}'. Assuming memory size of 0x{:X} bytes",
+                    String::from_utf8_lossy(response.as_slice()),
+                    DEFAULT_MEMORY_SIZE,
+                );
+                Ok(None)
+            }
+            // Older firmware does not know this command
+            Err(e) if e.downcast_ref::<DeviceError>().is_some() => Ok(None),
+            Err(e) => Err(e.context("Error querying memory size")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockPort, MOCK_PORT_NAME};
+
+    const TEST_TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn mock_device(port: MockPort) -> Device {
+        let settings = DeviceSettings {
+            timeout: TEST_TIMEOUT,
+            initial_timeout: TEST_TIMEOUT,
+            ..DeviceSettings::default()
+        };
+
+        Device::with_port(MOCK_PORT_NAME, Box::new(port), &settings)
+    }
+
+    #[test]
+    fn empty_reads_time_out() {
+        let mut port = MockPort::new();
+        port.set_empty_reads(true);
+        let mut device = mock_device(port);
+        let start_time = Instant::now();
+
+        // The start message is skipped, nothing follows it
+        let err = device.receive(64).unwrap_err();
+
+        assert_eq!(err.downcast_ref::<std::io::Error>().map(std::io::Error::kind), Some(ErrorKind::TimedOut));
+        assert!(start_time.elapsed() < 10 * TEST_TIMEOUT);
+    }
+
+    #[test]
+    fn slowly_received_line_times_out() {
+        let mut port = MockPort::new();
+        // Each byte arrives in time, but the whole start message doesn't
+        port.set_read_delay(TEST_TIMEOUT / 4);
+        let mut device = mock_device(port);
+
+        let err = device.receive_lenient(64).unwrap_err();
+
+        assert_eq!(err.downcast_ref::<std::io::Error>().map(std::io::Error::kind), Some(ErrorKind::TimedOut));
+    }
+
+    #[test]
+    fn default_timeout_is_kept_after_receiving_within_timeout() {
+        let settings = DeviceSettings {
+            timeout: TEST_TIMEOUT,
+            initial_timeout: 2 * TEST_TIMEOUT,
+            ..DeviceSettings::default()
+        };
+        let mut device = Device::with_port(MOCK_PORT_NAME, Box::new(MockPort::new()), &settings);
+        let mut line = vec![];
+
+        device.send(b"P1\n").unwrap();
+        device.receive_into_within(&mut line, 64, Duration::from_secs(1)).unwrap();
+
+        assert_eq!(line, b"p1");
+        assert_eq!(device.port.timeout(), TEST_TIMEOUT);
+    }
+
+    #[test]
+    fn flush_failing_once_is_retried() {
+        let mut port = MockPort::new();
+        port.fail_next_flushes(1);
+        let mut device = mock_device(port);
+
+        device.send(b"P1\n").unwrap();
+
+        assert_eq!(device.receive(64).unwrap(), b"p1");
+    }
+
+    #[test]
+    fn persistent_flush_failure_is_reported() {
+        let mut port = MockPort::new();
+        port.fail_next_flushes(FLUSH_ATTEMPTS);
+        let mut device = mock_device(port);
+
+        assert!(device.send(b"P1\n").is_err());
+    }
+
+    fn device_with_banner(banner: &str, max_line: Option<usize>) -> Device {
+        let mut port = MockPort::new();
+        port.set_version(banner);
+        let settings = DeviceSettings { max_line, ..DeviceSettings::default() };
+
+        Device::with_port(MOCK_PORT_NAME, Box::new(port), &settings)
+    }
+
+    #[test]
+    fn long_banner_within_max_line_is_received() {
+        // 100 characters, 101 with the response prefix
+        let banner = format!("ROME-0.1.0 {}", "x".repeat(89));
+
+        assert!(device_with_banner(&banner, None).version().is_err());
+        assert_eq!(device_with_banner(&banner, Some(101)).version().unwrap(), banner);
+        assert!(device_with_banner(&banner, Some(100)).version().is_err());
+    }
+
+    #[test]
+    fn sync_nonce_with_clock_before_epoch() {
+        let before_epoch = UNIX_EPOCH - Duration::from_secs(86400);
+
+        let first = sync_nonce_at(before_epoch);
+        let second = sync_nonce_at(before_epoch);
+
+        assert_ne!(first, second);
+        assert_eq!(sync_nonce_at(UNIX_EPOCH + Duration::from_micros(1234)), 1234);
+    }
+
+    #[test]
+    fn features_are_unsupported_until_checked() {
+        let mut device = mock_device(MockPort::new());
+
+        assert!(!device.supports(Capability::Binary));
+        assert!(!device.supports(Capability::MemorySize));
+        assert!(device.require(Capability::ReadCrc, "checked reads").is_err());
+
+        device.check().unwrap();
+
+        assert!(device.supports(Capability::Binary));
+        assert!(device.require(Capability::ReadCrc, "checked reads").is_ok());
+    }
+
+    #[test]
+    fn ping_and_command_times_are_profiled() {
+        let settings = DeviceSettings {
+            timeout: TEST_TIMEOUT,
+            initial_timeout: TEST_TIMEOUT,
+            profile: Some(ProfileFormat::Text),
+            ..DeviceSettings::default()
+        };
+        let mut device = Device::with_port(MOCK_PORT_NAME, Box::new(MockPort::new()), &settings);
+
+        device.check().unwrap();
+        device.sync().unwrap();
+
+        let commands = &device.profile.as_ref().unwrap().commands;
+        assert_eq!(commands.get(&'P').map(|latency| latency.count), Some(2));
+        assert_eq!(commands.get(&'V').map(|latency| latency.count), Some(1));
+        assert!(!commands.contains_key(&'\n'));
+    }
+
+    #[test]
+    fn memory_size_is_cached_only_when_reported() {
+        let mut port = MockPort::new();
+        port.set_memory_size(0x8000);
+        let mut device = mock_device(port);
+
+        // Memory size query is not supported until the firmware is checked
+        assert_eq!(device.memory_size().unwrap(), DEFAULT_MEMORY_SIZE);
+        assert_eq!(device.memory_size, None);
+
+        device.check().unwrap();
+        assert_eq!(device.memory_size().unwrap(), 0x8000);
+        assert_eq!(device.memory_size, Some(0x8000));
+
+        device.check().unwrap();
+        assert_eq!(device.memory_size, None);
+    }
+
+    #[test]
+    fn retry_jitter_advances_its_own_state() {
+        let settings = DeviceSettings {
+            retry_delay: Duration::ZERO,
+            retry_jitter: true,
+            ..DeviceSettings::default()
+        };
+        let mut device = Device::with_port(MOCK_PORT_NAME, Box::new(MockPort::new()), &settings);
+        device.jitter = SplitMix64(42);
+        let mut expected = SplitMix64(42);
+        expected.next();
+        expected.next();
+
+        device.wait_before_retry(1);
+        device.wait_before_retry(2);
+
+        assert_eq!(device.jitter.0, expected.0);
+        assert_ne!(jitter_seed(UNIX_EPOCH - Duration::from_secs(1)), jitter_seed(UNIX_EPOCH - Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn interrupted_reads_are_retried() {
+        let mut port = MockPort::new();
+        port.interrupt_next_reads(3);
+        let mut device = mock_device(port);
+
+        assert_eq!(device.receive_lenient(64).unwrap(), b"# Started");
+    }
+
+    #[test]
+    fn line_is_received_within_timeout() {
+        let mut device = mock_device(MockPort::new());
+
+        assert_eq!(device.receive_lenient(64).unwrap(), b"# Started");
     }
 }
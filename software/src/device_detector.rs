@@ -1,20 +1,44 @@
+use std::io::{stderr, stdin, IsTerminal, Write};
 use anyhow::{anyhow, Context, Result};
 use clap::Args;
 use serialport::{available_ports, SerialPortInfo, SerialPortType};
+use tracing::trace_span;
 use crate::device::{Device, DeviceSettings};
+use crate::mock::open_mock_device;
 
 #[derive(Clone, Args)]
 pub struct DeviceDetectorSettings {
     /// Name of known device port to use.
     /// If not specified, the program will try to detect the device automatically.
     ///
+    /// Instead of a full name, `usb<N>` selects N-th (starting from 0) port listed by
+    /// `port list` command and a pattern with `*` or `?` wildcards (e.g. `'ttyUSB*'`) selects the
+    /// only listed port whose name (or its last path component) matches the pattern.
+    ///
     /// Note: automatic detection may in some cases damage some other devices connected to the
     /// computer as the program will try to send messages to devices that look like ROME.
     #[arg(long, short)]
-    port: Option<String>,
+    pub port: Option<String>,
+
+    /// Use a simulated device with 64KiB of memory instead of a real one.
+    #[arg(long, hide = true, conflicts_with = "port")]
+    pub mock_device: bool,
+
+    /// Ask which port to use when more than one serial device is connected.
+    ///
+    /// Works only when standard input is a terminal, otherwise having several connected devices is
+    /// an error, as without this option.
+    #[arg(long, conflicts_with_all = ["port", "mock_device"])]
+    pub interactive: bool,
 
     #[command(flatten)]
-    device_settings: DeviceSettings,
+    pub device_settings: DeviceSettings,
+}
+
+impl DeviceDetectorSettings {
+    pub fn device_settings(&self) -> &DeviceSettings {
+        &self.device_settings
+    }
 }
 
 pub fn list_potential_devices() -> Result<Vec<SerialPortInfo>> {
@@ -26,16 +50,159 @@ pub fn list_potential_devices() -> Result<Vec<SerialPortInfo>> {
         .collect())
 }
 
-fn create_and_check_device(name: &str, settings: &DeviceSettings) -> Result<Device> {
+/// Matches `name` against a pattern where `*` stands for any sequence of characters and `?` for any
+/// single character.
+fn matches_wildcard(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| matches_wildcard(rest, &name[skip..])),
+        Some((p, rest)) => match name.split_first() {
+            Some((c, name_rest)) if *p == '?' || p == c => matches_wildcard(rest, name_rest),
+            _ => false,
+        },
+    }
+}
+
+/// Resolves port selector given by user into port name.
+///
+/// Selectors of form `usb<N>` and wildcard patterns are resolved using the list of potential
+/// devices, other values are treated as port names.
+pub fn resolve_port_selector(selector: &str) -> Result<String> {
+    let index = selector.strip_prefix("usb")
+        .filter(|digits| !digits.is_empty() && digits.bytes().all(|c| c.is_ascii_digit()))
+        .and_then(|digits| digits.parse::<usize>().ok());
+    let is_pattern = selector.contains(['*', '?']);
+
+    if index.is_none() && !is_pattern {
+        return Ok(selector.to_string());
+    }
+
+    let mut names: Vec<String> = list_potential_devices()?
+        .into_iter()
+        .map(|port| port.port_name)
+        .collect();
+    names.sort();
+
+    if let Some(index) = index {
+        return names.get(index).cloned().ok_or_else(|| anyhow!(
+            "Port {} does not exist: {} potential device ports found",
+            selector,
+            names.len(),
+        ));
+    }
+
+    let pattern: Vec<char> = selector.chars().collect();
+    let matching: Vec<String> = names.into_iter()
+        .filter(|name| {
+            let file_name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+
+            [name.as_str(), file_name].iter()
+                .any(|candidate| matches_wildcard(&pattern, &candidate.chars().collect::<Vec<_>>()))
+        })
+        .collect();
+
+    match matching.as_slice() {
+        [] => Err(anyhow!("No ports match '{}'", selector)),
+        [name] => Ok(name.clone()),
+        names => Err(anyhow!("More than one port matches '{}': {}", selector, names.join(", "))),
+    }
+}
+
+/// Baud rates tried when baud rate scanning is enabled.
+pub const SCANNED_BAUD_RATES: [u32; 4] = [250_000, 115_200, 57_600, 9_600];
+
+fn create_and_check_device_at_baud_rate(name: &str, settings: &DeviceSettings) -> Result<Device> {
     let mut device = Device::new(name, settings)?;
     device.check().context("Error checking device")?;
-    return Ok(device);
+    Ok(device)
+}
+
+fn create_and_check_device(name: &str, settings: &DeviceSettings) -> Result<Device> {
+    let first_error = match create_and_check_device_at_baud_rate(name, settings) {
+        Ok(device) => {
+            return Ok(device);
+        }
+        Err(e) if settings.baud_scan => e,
+        Err(e) => {
+            return Err(e);
+        }
+    };
+
+    for baud_rate in SCANNED_BAUD_RATES.into_iter().filter(|rate| *rate != settings.baud_rate) {
+        eprintln!("Trying baud rate {}...", baud_rate);
+
+        // The port is re-opened as not all drivers support changing baud rate of an open port
+        if let Ok(device) = create_and_check_device_at_baud_rate(name, &DeviceSettings { baud_rate, ..*settings }) {
+            eprintln!("Device responded at baud rate {}", baud_rate);
+            return Ok(device);
+        }
+    }
+
+    Err(first_error.context("Device did not respond at any of scanned baud rates"))
+}
+
+/// Describes a port for the user choosing among several ones: name, USB IDs and serial number.
+fn describe_port(port_info: &SerialPortInfo) -> String {
+    match &port_info.port_type {
+        SerialPortType::UsbPort(usb) => {
+            let mut description = format!("{} (VID {:04X}, PID {:04X}", port_info.port_name, usb.vid, usb.pid);
+
+            if let Some(serial_number) = usb.serial_number.as_ref() {
+                description.push_str(format!(", serial {}", serial_number).as_str());
+            }
+            if let Some(product) = usb.product.as_ref() {
+                description.push_str(format!(", {}", product).as_str());
+            }
+
+            description.push(')');
+            description
+        }
+        _ => port_info.port_name.clone(),
+    }
+}
+
+/// Asks the user to pick one of the candidate ports by its number.
+fn choose_port(candidates: &[SerialPortInfo]) -> Result<String> {
+    eprintln!("More than one serial device connected:");
+
+    for (index, port_info) in candidates.iter().enumerate() {
+        eprintln!("  {}) {}", index + 1, describe_port(port_info));
+    }
+
+    let mut line = String::new();
+
+    loop {
+        eprint!("Choose device [1-{}]: ", candidates.len());
+        stderr().flush()?;
+
+        line.clear();
+        if stdin().read_line(&mut line).context("Error reading choice")? == 0 {
+            return Err(anyhow!("No device chosen"));
+        }
+
+        match line.trim().parse::<usize>() {
+            Ok(number) if (1..=candidates.len()).contains(&number) => {
+                return Ok(candidates[number - 1].port_name.clone());
+            }
+            _ => eprintln!("Enter a number between 1 and {}", candidates.len()),
+        }
+    }
 }
 
 pub fn safe_detect_device(settings: &DeviceSettings) -> Result<Device> {
+    safe_detect_device_choosing(settings, false)
+}
+
+/// Same as [safe_detect_device] but lets the user choose the device if `interactive` is set and
+/// more than one device is connected.
+fn safe_detect_device_choosing(settings: &DeviceSettings, interactive: bool) -> Result<Device> {
     let candidates = list_potential_devices()?;
 
     if candidates.len() > 1 {
+        if interactive && stdin().is_terminal() {
+            return create_and_check_device(choose_port(&candidates)?.as_str(), settings);
+        }
+
         return Err(anyhow!("More than one serial device connected"));
     }
 
@@ -47,9 +214,17 @@ pub fn safe_detect_device(settings: &DeviceSettings) -> Result<Device> {
 }
 
 pub fn detect_device(settings: &DeviceDetectorSettings) -> Result<Device> {
-    if let Some(known_port_name) = settings.port.as_ref() {
-        create_and_check_device(known_port_name.as_str(), &settings.device_settings)
+    let _span = trace_span!("detect_device", port = settings.port.as_deref()).entered();
+
+    if settings.mock_device {
+        let mut device = open_mock_device(&settings.device_settings)?;
+        device.check().context("Error checking device")?;
+        return Ok(device);
+    }
+
+    if let Some(selector) = settings.port.as_ref() {
+        create_and_check_device(resolve_port_selector(selector)?.as_str(), &settings.device_settings)
     } else {
-        safe_detect_device(&settings.device_settings)
+        safe_detect_device_choosing(&settings.device_settings, settings.interactive)
     }
 }
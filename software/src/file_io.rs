@@ -1,18 +1,692 @@
-use std::fs::File;
-use std::io::{Read, stdin, stdout, Write};
-use std::path::PathBuf;
-use anyhow::Result;
+use std::cmp::max;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, stdin, stdout, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::str::from_utf8;
+use anyhow::{anyhow, Context, Result};
+use flate2::bufread::GzDecoder;
+use crate::formats::{flatten_chunks, parse_intel_hex, parse_srec, DataFormat};
+use crate::protocol::ADDRESS_SPACE_SIZE;
 
-pub fn open_output_stream(path: Option<PathBuf>) -> Result<Box<dyn Write>> {
+/// Defines what happens when output file already exists.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OutputFileMode {
+    /// Replace content of the existing file.
+    #[default]
+    Truncate,
+    /// Fail if the file exists.
+    CreateNew,
+    /// Write after the existing content.
+    Append,
+}
+
+impl OutputFileMode {
+    fn open_options(self) -> OpenOptions {
+        let mut options = OpenOptions::new();
+        options.write(true);
+
+        match self {
+            OutputFileMode::Truncate => options.create(true).truncate(true),
+            OutputFileMode::CreateNew => options.create_new(true),
+            OutputFileMode::Append => options.create(true).append(true),
+        };
+
+        options
+    }
+}
+
+/// Opens output file according to `mode`.
+pub fn open_output_file(path: &Path, mode: OutputFileMode) -> Result<File> {
+    mode.open_options().open(path).map_err(|e| match e.kind() {
+        ErrorKind::AlreadyExists => anyhow!("Output file {} already exists, refusing to overwrite it", path.display()),
+        _ => anyhow!("Error opening output file {}: {}", path.display(), e),
+    })
+}
+
+pub fn open_output_stream(path: Option<PathBuf>, mode: OutputFileMode) -> Result<Box<dyn Write>> {
     Ok(match path {
         None => Box::new(stdout()),
-        Some(path) => Box::new(File::create(path)?)
+        Some(path) => Box::new(open_output_file(&path, mode)?)
     })
 }
 
+/// Opens output file of known size as memory-mapped file, so flushing of written data is managed by
+/// the OS.
+///
+/// Falls back to a regular file if memory mapping is not available.
+/// Appending to an existing file is not supported.
+pub fn open_mmap_output_stream(path: PathBuf, size: NonZeroUsize, mode: OutputFileMode) -> Result<Box<dyn Write>> {
+    if mode == OutputFileMode::Append {
+        return Err(anyhow!("Memory-mapped output cannot be appended to an existing file"));
+    }
+
+    #[cfg(unix)]
+    match MmapFileWriter::create(&path, size, mode) {
+        Ok(writer) => {
+            return Ok(Box::new(writer));
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            return Err(anyhow!("Output file {} already exists, refusing to overwrite it", path.display()));
+        }
+        Err(e) => {
+            eprintln!("Cannot memory-map output file ({}), using regular file instead", e);
+        }
+    }
+
+    // A file that didn't exist before may have been created by the failed attempt
+    let mode = if mode == OutputFileMode::CreateNew && path.exists() { OutputFileMode::Truncate } else { mode };
+
+    open_output_stream(Some(path), mode)
+}
+
+/// Writes index of regions omitted from a sparse dump.
+///
+/// Each line of the index contains offset and length of one region as hexadecimal numbers.
+pub fn write_skipped_regions_index(path: &Path, regions: &[(usize, usize)]) -> Result<()> {
+    let mut file = File::create(path)?;
+
+    for (offset, length) in regions {
+        writeln!(file, "0x{:04X} 0x{:X}", offset, length)?;
+    }
+
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Guesses format of a data file by its extension.
+///
+/// Standard input/output (`None`) and files with unknown extensions are treated as raw binary data.
+/// Extension of compressed files (`.gz`) is ignored.
+pub fn detect_format(path: Option<&Path>) -> DataFormat {
+    let path = match path {
+        None => { return DataFormat::Raw; }
+        Some(path) if path.extension().is_some_and(|ext| ext == "gz") => path.file_stem().map(Path::new).unwrap_or(path),
+        Some(path) => path,
+    };
+
+    match path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase).as_deref() {
+        Some("hex" | "ihex" | "ihx") => DataFormat::IntelHex,
+        Some("srec" | "s19" | "s28" | "s37" | "mot") => DataFormat::Srec,
+        Some("txt") => DataFormat::Hexdump,
+        Some("asm") => DataFormat::AsmHex,
+        Some("h" | "c") => DataFormat::CArray,
+        _ => DataFormat::Raw,
+    }
+}
+
+/// Reads data from input file or the standard input in given format.
+///
+/// Returns address of the first byte of data and the data itself.
+/// Formats with addressed records (Intel HEX, S-records) may describe non-contiguous data, gaps
+/// between records are filled with 0xFF.
+/// `address_bias` is added to record addresses, it must be zero for raw data.
+pub fn read_input_data(path: Option<PathBuf>, format: DataFormat, address_bias: i64) -> Result<(usize, Vec<u8>)> {
+    let (data_address, data, _) = read_input_data_with_regions(path, format, address_bias)?;
+
+    Ok((data_address, data))
+}
+
+/// Address of the first byte of input data, the data and regions described by input records.
+pub type InputDataWithRegions = (usize, Vec<u8>, Vec<(usize, usize)>);
+
+/// Same as [read_input_data] but also returns regions of the data described by input records as
+/// offsets from the first byte of data and lengths.
+///
+/// Gaps between records are not included in the regions, adjacent records are merged.
+pub fn read_input_data_with_regions(
+    path: Option<PathBuf>,
+    format: DataFormat,
+    address_bias: i64,
+) -> Result<InputDataWithRegions> {
+    let format = format.resolve_input(path.as_deref());
+    let mut data = vec![];
+    open_input_stream(path)?.read_to_end(&mut data)?;
+
+    let chunks = match format {
+        DataFormat::Raw | DataFormat::Auto if address_bias != 0 => {
+            return Err(anyhow!("{}", ADDRESS_BIAS_RAW_ERROR));
+        }
+        DataFormat::Raw | DataFormat::Auto => {
+            let regions = if data.is_empty() { vec![] } else { vec![(0, data.len())] };
+
+            return Ok((0, data, regions));
+        }
+        DataFormat::Hexdump => {
+            return Err(anyhow!("Hexdump format can be used for output only"));
+        }
+        DataFormat::AsmHex => {
+            return Err(anyhow!("Asm-hex format can be used for output only"));
+        }
+        DataFormat::CArray => {
+            return Err(anyhow!("C array format can be used for output only"));
+        }
+        DataFormat::IntelHex => parse_intel_hex(from_utf8(&data)?)?,
+        DataFormat::Srec => parse_srec(from_utf8(&data)?)?,
+    };
+
+    let chunks = chunks.into_iter()
+        .map(|(address, data)| Ok((bias_address(address, data.len(), address_bias)?, data)))
+        .collect::<Result<Vec<_>>>()?;
+    let (data_address, data) = flatten_chunks(chunks.as_slice(), 0xFF).unwrap_or_default();
+
+    let mut ranges: Vec<(usize, usize)> = chunks.iter()
+        .filter(|(_, data)| !data.is_empty())
+        .map(|(address, data)| (*address, address + data.len()))
+        .collect();
+    ranges.sort();
+
+    let mut regions: Vec<(usize, usize)> = vec![];
+    for (start, end) in ranges {
+        match regions.last_mut() {
+            Some((region_offset, region_length)) if data_address + *region_offset + *region_length >= start => {
+                *region_length = max(*region_length, end - data_address - *region_offset);
+            }
+            _ => {
+                regions.push((start - data_address, end - start));
+            }
+        }
+    }
+
+    Ok((data_address, data, regions))
+}
+
+/// Error reported when address bias is requested for data without addresses.
+pub const ADDRESS_BIAS_RAW_ERROR: &str = "--address-bias can be used with Intel HEX and S-record input only, use --offset to place raw data";
+
+/// Translates address of a record of `size` bytes from input file to device address space.
+fn bias_address(address: usize, size: usize, address_bias: i64) -> Result<usize> {
+    let translated = address as i64 + address_bias;
+
+    if translated < 0 || translated + size as i64 > ADDRESS_SPACE_SIZE as i64 {
+        return Err(anyhow!(
+            "Record at 0x{:X} is outside of device address range when moved by {} bytes",
+            address,
+            address_bias,
+        ));
+    }
+
+    Ok(translated as usize)
+}
+
+/// Resolves format of input consisting of given files concatenated in order.
+///
+/// No files stand for the standard input. Only raw files can be concatenated.
+pub fn resolve_input_format(paths: &[PathBuf], format: DataFormat) -> Result<DataFormat> {
+    if let [] | [_] = paths {
+        return Ok(format.resolve_input(paths.first().map(PathBuf::as_path)));
+    }
+
+    for path in paths {
+        let part_format = format.resolve_input(Some(path.as_path()));
+
+        if part_format != DataFormat::Raw {
+            return Err(anyhow!("Only raw input files can be concatenated, {} is in {:?} format", path.display(), part_format));
+        }
+    }
+
+    Ok(DataFormat::Raw)
+}
+
+/// Same as [read_input_data_with_regions] but reads data from given files concatenated in order.
+///
+/// No files stand for the standard input. Only raw files can be concatenated, see
+/// [resolve_input_format].
+pub fn read_concatenated_input_data(paths: Vec<PathBuf>, format: DataFormat, address_bias: i64) -> Result<InputDataWithRegions> {
+    if paths.len() <= 1 {
+        return read_input_data_with_regions(paths.into_iter().next(), format, address_bias);
+    }
+
+    resolve_input_format(paths.as_slice(), format)?;
+    if address_bias != 0 {
+        return Err(anyhow!("{}", ADDRESS_BIAS_RAW_ERROR));
+    }
+
+    let mut data = vec![];
+    for path in paths {
+        open_input_stream(Some(path.clone()))?
+            .read_to_end(&mut data)
+            .with_context(|| format!("Error reading {}", path.display()))?;
+    }
+
+    let regions = if data.is_empty() { vec![] } else { vec![(0, data.len())] };
+
+    Ok((0, data, regions))
+}
+
+/// Opens given input files to be read one after another, or the standard input if there are no
+/// files.
+pub fn open_concatenated_input_stream(paths: Vec<PathBuf>) -> Result<Box<dyn Read>> {
+    let mut paths = paths.into_iter();
+    let mut stream = open_input_stream(paths.next())?;
+
+    for path in paths {
+        stream = Box::new(stream.chain(open_input_stream(Some(path))?));
+    }
+
+    Ok(stream)
+}
+
+/// Opens input file or the standard input.
+///
+/// Files with `.gz` extension are decompressed on the fly.
 pub fn open_input_stream(path: Option<PathBuf>) -> Result<Box<dyn Read>> {
     Ok(match path {
         None => Box::new(stdin()),
+        Some(path) if path.extension().is_some_and(|ext| ext == "gz") => {
+            Box::new(GzDecoder::new(BufReader::new(File::open(path)?)))
+        }
         Some(path) => Box::new(File::open(path)?),
     })
 }
+
+/// Writer that splits written data into files of fixed size named `<base>.000`, `<base>.001`, etc.
+///
+/// Next file is created only when there is some data to write into it.
+pub struct SplitFileWriter {
+    base_path: PathBuf,
+    part_size: NonZeroUsize,
+    mode: OutputFileMode,
+    current: Option<File>,
+    current_size: usize,
+    next_index: usize,
+}
+
+impl SplitFileWriter {
+    /// Creates a writer of parts of `part_size` bytes, each part file is opened according to `mode`.
+    pub fn new(base_path: PathBuf, part_size: NonZeroUsize, mode: OutputFileMode) -> Self {
+        Self {
+            base_path,
+            part_size,
+            mode,
+            current: None,
+            current_size: 0,
+            next_index: 0,
+        }
+    }
+
+    fn part_path(&self, index: usize) -> PathBuf {
+        let mut name = self.base_path.clone().into_os_string();
+        name.push(format!(".{:03}", index));
+        name.into()
+    }
+}
+
+impl Write for SplitFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.current.is_none() || self.current_size >= self.part_size.get() {
+            if let Some(mut file) = self.current.take() {
+                file.flush()?;
+            }
+
+            let file = open_output_file(&self.part_path(self.next_index), self.mode)
+                .map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
+            self.current = Some(file);
+            self.current_size = 0;
+            self.next_index += 1;
+        }
+
+        // Write only the part fitting into current file, write_all() will call this again for the rest
+        let size = buf.len().min(self.part_size.get() - self.current_size);
+        let written = self.current.as_mut().unwrap().write(&buf[..size])?;
+        self.current_size += written;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.current.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Limits of output kept by [RotatingFileWriter].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rotation {
+    /// Maximal number of files kept on disk.
+    pub files: NonZeroUsize,
+    /// Size of each file.
+    pub file_size: NonZeroUsize,
+}
+
+impl Rotation {
+    /// Parses rotation limits given as `<count>:<size>`, e.g. `10:1048576`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let (files, file_size) = value.split_once(':')
+            .ok_or_else(|| anyhow!("Expected <count>:<size>"))?;
+
+        Ok(Self {
+            files: files.trim().parse().context("Invalid number of files")?,
+            file_size: file_size.trim().parse().context("Invalid file size")?,
+        })
+    }
+}
+
+/// Writer that splits written data into files named like parts written by [SplitFileWriter] but
+/// keeps only a limited number of the latest files, deleting the oldest file when a new one is
+/// created.
+///
+/// The last file may be shorter than others if writing stops before it is filled.
+/// Failure to delete an old file is returned by the next call of `write()` or `flush()`, so data
+/// already written to the new file is not written again.
+pub struct RotatingFileWriter {
+    parts: SplitFileWriter,
+    rotation: Rotation,
+    /// Indices of parts currently kept on disk, oldest first.
+    kept: VecDeque<usize>,
+    removal_error: Option<std::io::Error>,
+}
+
+impl RotatingFileWriter {
+    pub fn new(base_path: PathBuf, rotation: Rotation) -> Self {
+        Self {
+            parts: SplitFileWriter::new(base_path, rotation.file_size, OutputFileMode::Truncate),
+            rotation,
+            kept: VecDeque::with_capacity(rotation.files.get() + 1),
+            removal_error: None,
+        }
+    }
+
+    fn take_removal_error(&mut self) -> std::io::Result<()> {
+        match self.removal_error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Write for RotatingFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.take_removal_error()?;
+
+        let written = self.parts.write(buf)?;
+
+        // A new part is created only when some data is written into it
+        if written > 0 && self.kept.back() != Some(&(self.parts.next_index - 1)) {
+            self.kept.push_back(self.parts.next_index - 1);
+
+            while self.kept.len() > self.rotation.files.get() {
+                let oldest = self.parts.part_path(self.kept.pop_front().unwrap());
+
+                if let Err(e) = std::fs::remove_file(&oldest) {
+                    self.removal_error = Some(std::io::Error::other(
+                        format!("Error removing old output file {}: {}", oldest.display(), e)
+                    ));
+                }
+            }
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.parts.flush()?;
+        self.take_removal_error()
+    }
+}
+
+/// Writer that writes the same data to all underlying writers.
+pub struct MultiWriter {
+    writers: Vec<Box<dyn Write>>,
+}
+
+impl MultiWriter {
+    pub fn new(writers: Vec<Box<dyn Write>>) -> Self {
+        Self { writers }
+    }
+}
+
+impl Write for MultiWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for writer in self.writers.iter_mut() {
+            writer.write_all(buf)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        for writer in self.writers.iter_mut() {
+            writer.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writer that writes data to a memory-mapped file of fixed size.
+///
+/// Besides sequential writes, allows writing at arbitrary offset.
+/// The file is truncated to the end of the last written byte when the writer is dropped.
+#[cfg(unix)]
+pub struct MmapFileWriter {
+    file: File,
+    map: *mut u8,
+    size: usize,
+    position: usize,
+    written_end: usize,
+}
+
+#[cfg(unix)]
+impl MmapFileWriter {
+    pub fn create(path: &Path, size: NonZeroUsize, mode: OutputFileMode) -> std::io::Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        let file = mode.open_options().read(true).open(path)?;
+        file.set_len(size.get() as u64)?;
+
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size.get(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+
+        if map == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            file,
+            map: map as *mut u8,
+            size: size.get(),
+            position: 0,
+            written_end: 0,
+        })
+    }
+
+    /// Writes data at given offset from the start of the file.
+    pub fn write_at(&mut self, offset: usize, data: &[u8]) -> std::io::Result<()> {
+        if offset.checked_add(data.len()).is_none_or(|end| end > self.size) {
+            return Err(std::io::Error::new(ErrorKind::WriteZero, "Write beyond the end of memory-mapped file"));
+        }
+
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), self.map.add(offset), data.len()) };
+        self.written_end = self.written_end.max(offset + data.len());
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Write for MmapFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_at(self.position, buf)?;
+        self.position += buf.len();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if unsafe { libc::msync(self.map as *mut libc::c_void, self.size, libc::MS_SYNC) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for MmapFileWriter {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map as *mut libc::c_void, self.size) };
+
+        // Don't leave unwritten tail of the file if less data than expected was written
+        if self.written_end < self.size {
+            let _ = self.file.set_len(self.written_end as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory for files of one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("romectl-file-io-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn part_files(dir: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut files: Vec<_> = std::fs::read_dir(dir).unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                (path.file_name().unwrap().to_string_lossy().to_string(), std::fs::read(&path).unwrap())
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn rotation(files: usize, file_size: usize) -> Rotation {
+        Rotation { files: NonZeroUsize::new(files).unwrap(), file_size: NonZeroUsize::new(file_size).unwrap() }
+    }
+
+    #[test]
+    fn rotation_keeps_latest_files() {
+        let dir = temp_dir("rotation");
+        let mut writer = RotatingFileWriter::new(dir.join("out"), rotation(2, 4));
+
+        writer.write_all(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        // The oldest part is deleted, the last one is kept shorter than others
+        assert_eq!(part_files(&dir), vec![
+            ("out.001".to_string(), vec![5, 6, 7, 8]),
+            ("out.002".to_string(), vec![9, 10]),
+        ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_creates_file_only_for_written_data() {
+        let dir = temp_dir("rotation-exact");
+        let mut writer = RotatingFileWriter::new(dir.join("out"), rotation(3, 2));
+
+        writer.write_all(&[1, 2]).unwrap();
+        writer.write_all(&[3, 4]).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        assert_eq!(part_files(&dir), vec![
+            ("out.000".to_string(), vec![1, 2]),
+            ("out.001".to_string(), vec![3, 4]),
+        ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_reports_removal_failure_without_rewriting_data() {
+        let dir = temp_dir("rotation-removal");
+        let mut writer = RotatingFileWriter::new(dir.join("out"), rotation(1, 2));
+
+        writer.write_all(&[1, 2]).unwrap();
+        std::fs::remove_file(dir.join("out.000")).unwrap();
+
+        assert_eq!(writer.write(&[3, 4]).unwrap(), 2);
+        assert!(writer.write(&[5]).is_err());
+        writer.flush().unwrap();
+        drop(writer);
+
+        assert_eq!(part_files(&dir), vec![("out.001".to_string(), vec![3, 4])]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mmap_writer_writes_whole_file() {
+        let dir = temp_dir("mmap-full");
+        let path = dir.join("out.bin");
+        let mut writer = MmapFileWriter::create(&path, NonZeroUsize::new(8).unwrap(), OutputFileMode::Truncate).unwrap();
+
+        writer.write_all(&[1, 2, 3, 4]).unwrap();
+        writer.write_at(4, &[5, 6, 7, 8]).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        assert_eq!(std::fs::read(&path).unwrap(), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mmap_writer_truncates_unwritten_tail_on_drop() {
+        let dir = temp_dir("mmap-partial");
+        let path = dir.join("out.bin");
+        let mut writer = MmapFileWriter::create(&path, NonZeroUsize::new(16).unwrap(), OutputFileMode::Truncate).unwrap();
+
+        writer.write_at(2, &[3, 4]).unwrap();
+        writer.write_all(&[1]).unwrap();
+        drop(writer);
+
+        assert_eq!(std::fs::read(&path).unwrap(), vec![1, 0, 3, 4]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mmap_writer_rejects_writes_past_end() {
+        let dir = temp_dir("mmap-past-end");
+        let path = dir.join("out.bin");
+        let mut writer = MmapFileWriter::create(&path, NonZeroUsize::new(4).unwrap(), OutputFileMode::Truncate).unwrap();
+
+        assert_eq!(writer.write_at(3, &[1, 2]).unwrap_err().kind(), ErrorKind::WriteZero);
+        assert_eq!(writer.write_at(usize::MAX, &[1]).unwrap_err().kind(), ErrorKind::WriteZero);
+        writer.write_all(&[1, 2, 3, 4]).unwrap();
+        assert!(writer.write_all(&[5]).is_err());
+        drop(writer);
+
+        assert_eq!(std::fs::read(&path).unwrap(), vec![1, 2, 3, 4]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mmap_writer_does_not_replace_existing_file_in_create_new_mode() {
+        let dir = temp_dir("mmap-create-new");
+        let path = dir.join("out.bin");
+        std::fs::write(&path, [0xAA; 2]).unwrap();
+
+        let result = MmapFileWriter::create(&path, NonZeroUsize::new(4).unwrap(), OutputFileMode::CreateNew);
+
+        assert_eq!(result.err().map(|e| e.kind()), Some(ErrorKind::AlreadyExists));
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0xAA; 2]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,759 @@
+use std::cmp::min;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use crate::data_ops::{DataChunk, Mismatch};
+use crate::file_io::detect_format;
+use crate::protocol::HexCase;
+
+const HEXDUMP_LINE_SIZE: usize = 16;
+
+/// Writer that formats written binary data as human-readable hexdump.
+///
+/// Each line contains address of the first byte of the line, up to 16 bytes as hexadecimal numbers
+/// and the same bytes as ASCII characters.
+/// Incomplete last line is written when the writer is flushed.
+pub struct HexDumpWriter<W: Write> {
+    inner: W,
+    address: usize,
+    hex_case: HexCase,
+    line: Vec<u8>,
+}
+
+impl<W: Write> HexDumpWriter<W> {
+    /// Creates a writer formatting data into `inner` with addresses starting at `start_address`.
+    pub fn new(inner: W, start_address: usize, hex_case: HexCase) -> Self {
+        Self {
+            inner,
+            address: start_address,
+            hex_case,
+            line: Vec::with_capacity(HEXDUMP_LINE_SIZE),
+        }
+    }
+
+    fn write_line(&mut self) -> std::io::Result<()> {
+        if self.line.is_empty() {
+            return Ok(());
+        }
+
+        write!(self.inner, "{} ", self.hex_case.digits(self.address, 4))?;
+
+        for i in 0..HEXDUMP_LINE_SIZE {
+            match self.line.get(i) {
+                Some(&b) => write!(self.inner, " {}", self.hex_case.digits(b, 2))?,
+                None => write!(self.inner, "   ")?,
+            }
+        }
+
+        write!(self.inner, "  |")?;
+
+        for b in self.line.iter() {
+            let c = if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' };
+            write!(self.inner, "{}", c)?;
+        }
+
+        writeln!(self.inner, "|")?;
+
+        self.address += self.line.len();
+        self.line.clear();
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for HexDumpWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let size = buf.len().min(HEXDUMP_LINE_SIZE - self.line.len());
+        self.line.extend_from_slice(&buf[..size]);
+
+        if self.line.len() == HEXDUMP_LINE_SIZE {
+            self.write_line()?;
+        }
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_line()?;
+        self.inner.flush()
+    }
+}
+
+/// Layout of lines written by [AsmHexWriter].
+#[derive(Clone, Debug)]
+pub struct AsmHexStyle {
+    /// Number of bytes on each line.
+    pub bytes_per_line: NonZeroUsize,
+    /// Assembler directive (e.g. `db` or `.byte`) preceding the bytes.
+    ///
+    /// Bytes are written as comma-separated `0xNN` numbers after a directive and as bare
+    /// space-separated hexadecimal numbers without it.
+    pub directive: Option<String>,
+    /// Text preceding the address at the start of each line, e.g. `L_` to make it a valid label.
+    pub label_prefix: String,
+    /// Case of hexadecimal digits of addresses and bytes.
+    pub hex_case: HexCase,
+}
+
+/// Writer that formats written binary data as lines of form `ADDR: bytes`, suitable for data
+/// directives of disassembled code.
+///
+/// Incomplete last line is written when the writer is flushed.
+pub struct AsmHexWriter<W: Write> {
+    inner: W,
+    address: usize,
+    style: AsmHexStyle,
+    line: Vec<u8>,
+}
+
+impl<W: Write> AsmHexWriter<W> {
+    /// Creates a writer formatting data into `inner` with addresses starting at `start_address`.
+    pub fn new(inner: W, start_address: usize, style: AsmHexStyle) -> Self {
+        Self {
+            inner,
+            address: start_address,
+            line: Vec::with_capacity(style.bytes_per_line.get()),
+            style,
+        }
+    }
+
+    fn write_line(&mut self) -> std::io::Result<()> {
+        if self.line.is_empty() {
+            return Ok(());
+        }
+
+        let hex_case = self.style.hex_case;
+        write!(self.inner, "{}{}:", self.style.label_prefix, hex_case.digits(self.address, 4))?;
+
+        match self.style.directive.as_deref() {
+            Some(directive) => {
+                write!(self.inner, " {}", directive)?;
+
+                for (i, &b) in self.line.iter().enumerate() {
+                    write!(self.inner, "{}0x{}", if i == 0 { " " } else { ", " }, hex_case.digits(b, 2))?;
+                }
+            }
+            None => {
+                for &b in self.line.iter() {
+                    write!(self.inner, " {}", hex_case.digits(b, 2))?;
+                }
+            }
+        }
+
+        writeln!(self.inner)?;
+
+        self.address += self.line.len();
+        self.line.clear();
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for AsmHexWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let size = buf.len().min(self.style.bytes_per_line.get() - self.line.len());
+        self.line.extend_from_slice(&buf[..size]);
+
+        if self.line.len() == self.style.bytes_per_line.get() {
+            self.write_line()?;
+        }
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_line()?;
+        self.inner.flush()
+    }
+}
+
+/// Declaration of array written by [CArrayWriter].
+#[derive(Clone, Debug)]
+pub struct CArrayStyle {
+    /// Name of the array, also used for `<name>_len` constant holding its length.
+    pub name: String,
+    /// Type of array elements.
+    pub element_type: String,
+    /// Number of elements on each line.
+    pub elements_per_line: NonZeroUsize,
+    /// Case of hexadecimal digits of elements.
+    pub hex_case: HexCase,
+}
+
+/// Writer that formats written binary data as definition of a C array of bytes.
+///
+/// The definition is completed by a constant holding length of the array when the writer is dropped.
+pub struct CArrayWriter<W: Write> {
+    inner: W,
+    style: CArrayStyle,
+    line: Vec<u8>,
+    length: usize,
+}
+
+impl<W: Write> CArrayWriter<W> {
+    pub fn new(inner: W, style: CArrayStyle) -> Self {
+        Self {
+            inner,
+            line: Vec::with_capacity(style.elements_per_line.get()),
+            style,
+            length: 0,
+        }
+    }
+
+    fn write_opening(&mut self) -> std::io::Result<()> {
+        writeln!(self.inner, "const {} {}[] = {{", self.style.element_type, self.style.name)
+    }
+
+    fn write_line(&mut self) -> std::io::Result<()> {
+        if self.line.is_empty() {
+            return Ok(());
+        }
+
+        if self.length == 0 {
+            self.write_opening()?;
+        }
+
+        write!(self.inner, "   ")?;
+        for &b in self.line.iter() {
+            write!(self.inner, " 0x{},", self.style.hex_case.digits(b, 2))?;
+        }
+        writeln!(self.inner)?;
+
+        self.length += self.line.len();
+        self.line.clear();
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for CArrayWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let size = buf.len().min(self.style.elements_per_line.get() - self.line.len());
+        self.line.extend_from_slice(&buf[..size]);
+
+        if self.line.len() == self.style.elements_per_line.get() {
+            self.write_line()?;
+        }
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_line()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for CArrayWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.write_line();
+        if self.length == 0 {
+            let _ = self.write_opening();
+        }
+        let _ = writeln!(self.inner, "}};");
+        let _ = writeln!(self.inner, "const size_t {}_len = {};", self.style.name, self.length);
+        let _ = self.inner.flush();
+    }
+}
+
+/// Writes list of mismatching bytes as CSV table with `address,expected,actual` columns.
+pub fn write_mismatches_csv<W: Write>(mut out: W, mismatches: &[Mismatch]) -> std::io::Result<()> {
+    writeln!(out, "address,expected,actual")?;
+
+    for mismatch in mismatches {
+        writeln!(out, "0x{:04X},0x{:02X},0x{:02X}", mismatch.address, mismatch.expected, mismatch.actual)?;
+    }
+
+    out.flush()
+}
+
+/// Writes regions of changed data as a patch.
+///
+/// Each line of the patch contains offset and length of one region as hexadecimal numbers followed
+/// by its data as hexadecimal digits, e.g. `0x0010 0x2 BEEF`.
+pub fn write_patch<W: Write>(mut out: W, regions: &[DataChunk<Vec<u8>>]) -> std::io::Result<()> {
+    for region in regions {
+        write!(out, "0x{:04X} 0x{:X} ", region.offset, region.data.len())?;
+        for b in region.data.iter() {
+            write!(out, "{:02X}", b)?;
+        }
+        writeln!(out)?;
+    }
+
+    out.flush()
+}
+
+/// Writes list of mismatching bytes as JSON array of `{"address", "expected", "actual"}` objects.
+pub fn write_mismatches_json<W: Write>(mut out: W, mismatches: &[Mismatch]) -> std::io::Result<()> {
+    writeln!(out, "[")?;
+
+    for (i, mismatch) in mismatches.iter().enumerate() {
+        let separator = if i + 1 < mismatches.len() { "," } else { "" };
+        writeln!(
+            out,
+            "  {{\"address\": {}, \"expected\": {}, \"actual\": {}}}{}",
+            mismatch.address,
+            mismatch.expected,
+            mismatch.actual,
+            separator,
+        )?;
+    }
+
+    writeln!(out, "]")?;
+    out.flush()
+}
+
+/// Formats a string as JSON string literal.
+pub fn json_string(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(format!("\\u{:04x}", c as u32).as_str()),
+            c => result.push(c),
+        }
+    }
+
+    result.push('"');
+    result
+}
+
+/// Format of data files.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DataFormat {
+    /// Detect format by file extension
+    Auto,
+    /// Raw binary data
+    Raw,
+    /// Intel HEX
+    IntelHex,
+    /// Motorola S-record
+    Srec,
+    /// Human-readable hexdump (output only)
+    Hexdump,
+    /// Address-prefixed lines of bytes for assembler data directives (output only)
+    AsmHex,
+    /// C array definition (output only)
+    CArray,
+}
+
+impl DataFormat {
+    /// Replaces [DataFormat::Auto] by format detected from file path.
+    pub fn resolve(self, path: Option<&Path>) -> DataFormat {
+        match self {
+            DataFormat::Auto => detect_format(path),
+            format => format,
+        }
+    }
+
+    /// Same as [DataFormat::resolve] but for input files.
+    ///
+    /// Files with extensions of output-only formats (e.g. `.txt` or `.c`) are read as raw data.
+    pub fn resolve_input(self, path: Option<&Path>) -> DataFormat {
+        match self.resolve(path) {
+            DataFormat::Hexdump | DataFormat::AsmHex | DataFormat::CArray if self == DataFormat::Auto => DataFormat::Raw,
+            format => format,
+        }
+    }
+}
+
+const HEX_RECORD_SIZE: usize = 16;
+
+/// Writer of output data able to leave gaps in it.
+pub trait SparseWrite: Write {
+    /// Skips `size` bytes, so the next written byte has address following the gap.
+    ///
+    /// Fails if the output format cannot represent gaps.
+    fn skip(&mut self, size: usize) -> std::io::Result<()>;
+}
+
+/// Adapter for writers of formats without addresses, which cannot leave gaps in data.
+pub struct DenseWriter<W: Write>(pub W);
+
+impl<W: Write> Write for DenseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> SparseWrite for DenseWriter<W> {
+    fn skip(&mut self, _size: usize) -> std::io::Result<()> {
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Output format cannot represent gaps in data"))
+    }
+}
+
+/// Writer that formats written binary data as Intel HEX records.
+///
+/// Data records contain up to 16 bytes each. Incomplete last record is written when the writer is
+/// flushed, end-of-file record is written when the writer is dropped.
+pub struct IntelHexWriter<W: Write> {
+    inner: W,
+    address: usize,
+    upper_address: usize,
+    hex_case: HexCase,
+    record: Vec<u8>,
+}
+
+impl<W: Write> IntelHexWriter<W> {
+    /// Creates a writer formatting data into `inner` with addresses starting at `start_address`.
+    pub fn new(inner: W, start_address: usize, hex_case: HexCase) -> Self {
+        Self {
+            inner,
+            address: start_address,
+            upper_address: 0,
+            hex_case,
+            record: Vec::with_capacity(HEX_RECORD_SIZE),
+        }
+    }
+
+    fn write_record(&mut self, record_type: u8, address: u16, data: &[u8]) -> std::io::Result<()> {
+        let [address_high, address_low] = address.to_be_bytes();
+        let mut sum = (data.len() as u8)
+            .wrapping_add(address_high)
+            .wrapping_add(address_low)
+            .wrapping_add(record_type);
+
+        let hex_case = self.hex_case;
+        write!(
+            self.inner,
+            ":{}{}{}",
+            hex_case.digits(data.len(), 2),
+            hex_case.digits(address, 4),
+            hex_case.digits(record_type, 2),
+        )?;
+
+        for &b in data {
+            sum = sum.wrapping_add(b);
+            write!(self.inner, "{}", hex_case.digits(b, 2))?;
+        }
+
+        writeln!(self.inner, "{}", hex_case.digits(sum.wrapping_neg(), 2))
+    }
+
+    fn write_data_record(&mut self) -> std::io::Result<()> {
+        if self.record.is_empty() {
+            return Ok(());
+        }
+
+        let upper_address = self.address >> 16;
+        if upper_address != self.upper_address {
+            self.write_record(0x04, 0, &(upper_address as u16).to_be_bytes())?;
+            self.upper_address = upper_address;
+        }
+
+        let record = std::mem::take(&mut self.record);
+        self.write_record(0x00, self.address as u16, record.as_slice())?;
+
+        self.address += record.len();
+        self.record = record;
+        self.record.clear();
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for IntelHexWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Records must not cross 64K boundary
+        let space = min(HEX_RECORD_SIZE, 0x10000 - (self.address & 0xFFFF)) - self.record.len();
+        let size = buf.len().min(space);
+        self.record.extend_from_slice(&buf[..size]);
+
+        if size == space {
+            self.write_data_record()?;
+        }
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_data_record()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for IntelHexWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.write_data_record();
+        let _ = self.write_record(0x01, 0, &[]);
+        let _ = self.inner.flush();
+    }
+}
+
+impl<W: Write> SparseWrite for IntelHexWriter<W> {
+    fn skip(&mut self, size: usize) -> std::io::Result<()> {
+        self.write_data_record()?;
+        self.address += size;
+        Ok(())
+    }
+}
+
+/// Writer that formats written binary data as Motorola S-records.
+///
+/// `S1` records are used for 16-bit addresses and `S2` records for larger ones.
+/// Incomplete last record is written when the writer is flushed, termination record is written
+/// when the writer is dropped.
+pub struct SrecWriter<W: Write> {
+    inner: W,
+    address: usize,
+    hex_case: HexCase,
+    record: Vec<u8>,
+    header_written: bool,
+}
+
+impl<W: Write> SrecWriter<W> {
+    /// Creates a writer formatting data into `inner` with addresses starting at `start_address`.
+    pub fn new(inner: W, start_address: usize, hex_case: HexCase) -> Self {
+        Self {
+            inner,
+            address: start_address,
+            hex_case,
+            record: Vec::with_capacity(HEX_RECORD_SIZE),
+            header_written: false,
+        }
+    }
+
+    fn write_record(&mut self, record_type: u8, address: usize, data: &[u8]) -> std::io::Result<()> {
+        let address_bytes = &address.to_be_bytes()[size_of::<usize>() - srec_address_size(record_type)..];
+        let count = (address_bytes.len() + data.len() + 1) as u8;
+        let mut sum = count;
+
+        let hex_case = self.hex_case;
+        write!(self.inner, "S{}{}", record_type, hex_case.digits(count, 2))?;
+
+        for &b in address_bytes.iter().chain(data) {
+            sum = sum.wrapping_add(b);
+            write!(self.inner, "{}", hex_case.digits(b, 2))?;
+        }
+
+        writeln!(self.inner, "{}", hex_case.digits(!sum, 2))
+    }
+
+    fn write_data_record(&mut self) -> std::io::Result<()> {
+        if !self.header_written {
+            self.write_record(0, 0, b"romectl")?;
+            self.header_written = true;
+        }
+
+        if self.record.is_empty() {
+            return Ok(());
+        }
+
+        let record_type = if self.address + self.record.len() <= 0x10000 { 1 } else { 2 };
+        let record = std::mem::take(&mut self.record);
+        self.write_record(record_type, self.address, record.as_slice())?;
+
+        self.address += record.len();
+        self.record = record;
+        self.record.clear();
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for SrecWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let size = buf.len().min(HEX_RECORD_SIZE - self.record.len());
+        self.record.extend_from_slice(&buf[..size]);
+
+        if self.record.len() == HEX_RECORD_SIZE {
+            self.write_data_record()?;
+        }
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_data_record()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> SparseWrite for SrecWriter<W> {
+    fn skip(&mut self, size: usize) -> std::io::Result<()> {
+        self.write_data_record()?;
+        self.address += size;
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for SrecWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.write_data_record();
+        let _ = self.write_record(9, 0, &[]);
+        let _ = self.inner.flush();
+    }
+}
+
+/// Number of address bytes in S-record of given type.
+fn srec_address_size(record_type: u8) -> usize {
+    match record_type {
+        2 | 8 => 3,
+        3 | 7 => 4,
+        _ => 2,
+    }
+}
+
+/// Parses list of addresses, one hexadecimal address (with optional `0x` prefix) per line.
+///
+/// Empty lines and lines starting with `#` are ignored.
+pub fn parse_address_list(text: &str) -> Result<Vec<u16>> {
+    text.lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_number, line)| {
+            let digits = line.strip_prefix("0x").or_else(|| line.strip_prefix("0X")).unwrap_or(line);
+
+            u16::from_str_radix(digits, 16)
+                .with_context(|| format!("Line {}: invalid address '{}'", line_number, line))
+        })
+        .collect()
+}
+
+/// Parses Intel HEX file content into list of data chunks with absolute addresses.
+pub fn parse_intel_hex(text: &str) -> Result<Vec<(usize, Vec<u8>)>> {
+    let mut chunks = vec![];
+    let mut base_address = 0;
+
+    for (line_number, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() {
+            continue;
+        }
+
+        let record = line.strip_prefix(':')
+            .ok_or_else(|| anyhow!("Line {}: Intel HEX record must start with ':'", line_number))
+            .and_then(|digits| parse_hex_bytes(digits).with_context(|| format!("Line {}", line_number)))?;
+
+        if record.len() < 5 || record.len() != 5 + record[0] as usize {
+            return Err(anyhow!("Line {}: invalid Intel HEX record length", line_number));
+        }
+
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(anyhow!("Line {}: Intel HEX record checksum mismatch", line_number));
+        }
+
+        let address = u16::from_be_bytes([record[1], record[2]]) as usize;
+        let data = &record[4..record.len() - 1];
+
+        match record[3] {
+            0x00 => chunks.push((base_address + address, data.to_vec())),
+            0x01 => break,
+            0x02 if data.len() == 2 => base_address = (u16::from_be_bytes([data[0], data[1]]) as usize) << 4,
+            0x04 if data.len() == 2 => base_address = (u16::from_be_bytes([data[0], data[1]]) as usize) << 16,
+            0x03 | 0x05 => {}
+            record_type => {
+                return Err(anyhow!("Line {}: unsupported Intel HEX record type {:02X}", line_number, record_type));
+            }
+        }
+    }
+
+    Ok(chunks)
+}
+
+/// Parses Motorola S-record file content into list of data chunks with absolute addresses.
+pub fn parse_srec(text: &str) -> Result<Vec<(usize, Vec<u8>)>> {
+    let mut chunks = vec![];
+
+    for (line_number, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() {
+            continue;
+        }
+
+        let record_type = line.strip_prefix('S')
+            .and_then(|rest| rest.chars().next())
+            .and_then(|c| c.to_digit(10))
+            .ok_or_else(|| anyhow!("Line {}: S-record must start with 'S' followed by record type", line_number))?
+            as u8;
+        let record = parse_hex_bytes(&line[2..]).with_context(|| format!("Line {}", line_number))?;
+        let address_size = srec_address_size(record_type);
+
+        if record.len() < 2 + address_size || record.len() != 1 + record[0] as usize {
+            return Err(anyhow!("Line {}: invalid S-record length", line_number));
+        }
+
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xFF {
+            return Err(anyhow!("Line {}: S-record checksum mismatch", line_number));
+        }
+
+        if (1..=3).contains(&record_type) {
+            let address = record[1..1 + address_size].iter().fold(0usize, |a, b| (a << 8) | *b as usize);
+            chunks.push((address, record[1 + address_size..record.len() - 1].to_vec()));
+        }
+    }
+
+    Ok(chunks)
+}
+
+fn parse_hex_bytes(digits: &str) -> Result<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+        return Err(anyhow!("odd number of hexadecimal digits"));
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|e| anyhow!("invalid hexadecimal number: {}", e)))
+        .collect()
+}
+
+/// Combines chunks of data into a single contiguous image starting at the lowest chunk address.
+///
+/// Gaps between chunks are filled with `fill_byte`. Returns `None` if there is no data.
+pub fn flatten_chunks(chunks: &[(usize, Vec<u8>)], fill_byte: u8) -> Option<(usize, Vec<u8>)> {
+    // Zero-length records carry no data and don't affect bounds of the image
+    let chunks = || chunks.iter().filter(|(_, data)| !data.is_empty());
+    let start = chunks().map(|(address, _)| *address).min()?;
+    let end = chunks().map(|(address, data)| address + data.len()).max()?;
+    let mut image = vec![fill_byte; end - start];
+
+    for (address, data) in chunks() {
+        image[address - start..address - start + data.len()].copy_from_slice(data);
+    }
+
+    Some((start, image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flatten_chunks_ignores_empty_records() {
+        let chunks = parse_intel_hex(":0000000000\n:01010000AA54\n:00100000F0\n:00000001FF\n").unwrap();
+
+        assert_eq!(flatten_chunks(&chunks, 0xFF), Some((0x0100, vec![0xAA])));
+    }
+
+    #[test]
+    fn flatten_chunks_fills_gaps() {
+        let chunks = vec![(0x12, vec![0xBB]), (0x10, vec![0xAA])];
+
+        assert_eq!(flatten_chunks(&chunks, 0xFF), Some((0x10, vec![0xAA, 0xFF, 0xBB])));
+        assert_eq!(flatten_chunks(&[(0, vec![])], 0xFF), None);
+    }
+
+    #[test]
+    fn output_only_formats_are_not_detected_for_input() {
+        for (name, output_format) in [("notes.txt", DataFormat::Hexdump), ("rom.asm", DataFormat::AsmHex), ("rom.h", DataFormat::CArray)] {
+            let path = Some(Path::new(name));
+
+            assert_eq!(DataFormat::Auto.resolve(path), output_format);
+            assert_eq!(DataFormat::Auto.resolve_input(path), DataFormat::Raw);
+            assert_eq!(output_format.resolve_input(path), output_format);
+        }
+
+        assert_eq!(DataFormat::Auto.resolve_input(Some(Path::new("rom.hex"))), DataFormat::IntelHex);
+    }
+}
@@ -0,0 +1,14 @@
+pub mod args;
+pub mod checksum;
+pub mod chip_ids;
+pub mod cli;
+pub mod device;
+pub mod device_detector;
+pub mod data_ops;
+pub mod file_io;
+pub mod formats;
+pub mod metrics;
+pub mod mock;
+pub mod protocol;
+pub mod repl;
+pub mod signals;
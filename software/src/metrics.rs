@@ -0,0 +1,28 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+static DATA_BYTES: AtomicUsize = AtomicUsize::new(0);
+static RETRIES: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts bytes of memory content successfully read from or written to the device.
+pub fn record_data_bytes(bytes: usize) {
+    DATA_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Counts a retry of a failed transfer.
+pub fn record_retry() {
+    RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Formats a single `key=value` line summarizing a finished command, e.g.
+/// `rome: command=data.read bytes=65536 duration_ms=4210 retries=2 result=ok`.
+pub fn summary_line(command: &str, duration: Duration, result: &str) -> String {
+    format!(
+        "rome: command={} bytes={} duration_ms={} retries={} result={}",
+        command,
+        DATA_BYTES.load(Ordering::Relaxed),
+        duration.as_millis(),
+        RETRIES.load(Ordering::Relaxed),
+        result,
+    )
+}
@@ -0,0 +1,471 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
+use anyhow::Result;
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use crate::checksum::crc8;
+use crate::device::{Device, DeviceSettings};
+
+/// Name of the simulated device port.
+pub const MOCK_PORT_NAME: &str = "mock";
+
+/// Version reported by the simulated device.
+const MOCK_VERSION: &str = "ROME-0.0.1a crc binary id";
+
+/// Chip ID reported by the simulated device, SST39SF010A.
+const MOCK_CHIP_ID: &str = "BFB5";
+
+const MOCK_MEMORY_SIZE: usize = 0x10000;
+
+/// Creates a [Device] connected to an in-process simulation of ROME firmware with 64KiB of memory.
+pub fn open_mock_device(settings: &DeviceSettings) -> Result<Device> {
+    Ok(Device::with_port(MOCK_PORT_NAME, Box::new(MockPort::new()), settings))
+}
+
+/// Serial port connected to a simulated device.
+///
+/// The simulation follows behavior of the firmware: it responds to `W`, `R` (including checked
+/// reads), binary `w` and `r`, `E`, `T`, `V`, `P` and, if enabled, `S` commands, reports unknown commands and malformed arguments with error lines and sends info
+/// lines on start and during self-test.
+pub struct MockPort {
+    memory: Vec<u8>,
+    input: Vec<u8>,
+    output: RefCell<VecDeque<u8>>,
+    timeout: Duration,
+    baud_rate: u32,
+    empty_reads: bool,
+    read_delay: Duration,
+    failing_flushes: usize,
+    readable_size: usize,
+    version: String,
+    interrupted_reads: usize,
+    reported_memory_size: Option<usize>,
+}
+
+impl Default for MockPort {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockPort {
+    pub fn new() -> Self {
+        let mut port = Self {
+            memory: vec![0; MOCK_MEMORY_SIZE],
+            input: vec![],
+            output: RefCell::new(VecDeque::new()),
+            timeout: Duration::from_secs(1),
+            baud_rate: 250_000,
+            empty_reads: false,
+            read_delay: Duration::ZERO,
+            failing_flushes: 0,
+            readable_size: MOCK_MEMORY_SIZE,
+            version: MOCK_VERSION.to_string(),
+            interrupted_reads: 0,
+            reported_memory_size: None,
+        };
+        port.respond("# Started");
+        port
+    }
+
+    /// Makes reads return no data instead of timeout error when there is nothing to read, as some
+    /// serial port drivers do.
+    pub fn set_empty_reads(&mut self, empty_reads: bool) {
+        self.empty_reads = empty_reads;
+    }
+
+    /// Makes each read returning data wait for `delay` first, simulating a slow device.
+    pub fn set_read_delay(&mut self, delay: Duration) {
+        self.read_delay = delay;
+    }
+
+    /// Makes next `count` flushes fail after the data is written, as intermittently happens with
+    /// some USB-serial drivers.
+    pub fn fail_next_flushes(&mut self, count: usize) {
+        self.failing_flushes = count;
+    }
+
+    /// Makes read commands return only data below `size`, as some firmware does at the end of
+    /// addressable memory.
+    pub fn set_readable_size(&mut self, size: usize) {
+        self.readable_size = size;
+    }
+
+    /// Sets version banner reported by the device, e.g. to simulate other firmware builds.
+    pub fn set_version(&mut self, version: &str) {
+        self.version = version.to_string();
+    }
+
+    /// Makes next `count` reads fail with [ErrorKind::Interrupted], as reads of real ports do when
+    /// the process receives a signal.
+    pub fn interrupt_next_reads(&mut self, count: usize) {
+        self.interrupted_reads = count;
+    }
+
+    /// Makes the device support memory size query and report memory of `size` bytes.
+    ///
+    /// Data beyond `size` can't be read, like data at the end of addressable memory.
+    pub fn set_memory_size(&mut self, size: usize) {
+        self.reported_memory_size = Some(size);
+        self.readable_size = size;
+    }
+
+    fn respond(&mut self, line: &str) {
+        let output = self.output.get_mut();
+        output.extend(line.as_bytes());
+        output.push_back(b'\n');
+    }
+
+    fn run_command(&mut self, line: &[u8]) {
+        let (command, args) = match line.split_first() {
+            None => { return; }
+            Some((command, args)) => (*command as char, args),
+        };
+
+        match command {
+            'W' => self.run_write(args),
+            'R' => self.run_read(args),
+            'w' => self.run_binary_write(args),
+            'r' => self.run_binary_read(args),
+            'E' => self.respond("EOK"),
+            'I' => self.respond(format!("I{}", MOCK_CHIP_ID).as_str()),
+            'T' => self.run_test(),
+            'V' => match self.reported_memory_size {
+                Some(_) => self.respond(format!("V{} size", self.version).as_str()),
+                None => self.respond(format!("V{}", self.version).as_str()),
+            },
+            'S' => match self.reported_memory_size {
+                Some(size) => self.respond(format!("S{:X}", size).as_str()),
+                None => self.respond("!BADCMD S"),
+            },
+            'P' => self.respond(format!("p{}", String::from_utf8_lossy(args)).as_str()),
+            c => self.respond(format!("!BADCMD {}", c).as_str()),
+        }
+    }
+
+    fn run_write(&mut self, args: &[u8]) {
+        let start_address = match parse_hex(args.get(..4)) {
+            None => { return self.respond("!BADARG ADDRESS"); }
+            Some(address) => address as u16,
+        };
+        let mut address = start_address;
+
+        for digits in args[4..].chunks(2) {
+            match parse_hex(Some(digits)) {
+                Some(value) if digits.len() == 2 => {
+                    self.memory[address as usize] = value as u8;
+                    address = address.wrapping_add(1);
+                }
+                _ => {
+                    return self.respond(format!("!BADARG DATA {:04X}", address).as_str());
+                }
+            }
+        }
+
+        self.respond(format!("W{:04X}{:04X}", start_address, address).as_str());
+    }
+
+    fn run_read(&mut self, args: &[u8]) {
+        let mut address = match parse_hex(args.get(..4)) {
+            None => { return self.respond("!BADARG ADDRESS"); }
+            Some(address) => address as u16,
+        };
+        let size = match parse_hex(args.get(4..6)) {
+            None => { return self.respond("!BADARG SIZE"); }
+            Some(size) => size.min(self.readable_size.saturating_sub(address as usize) as u32),
+        };
+        let checked = match &args[6..] {
+            b"" => false,
+            b"C" => true,
+            _ => { return self.respond("!BADARG"); }
+        };
+        let mut response = String::with_capacity(3 + 2 * size as usize);
+        let mut data = Vec::with_capacity(size as usize);
+        response.push('R');
+
+        for _ in 0..size {
+            data.push(self.memory[address as usize]);
+            response.push_str(format!("{:02X}", self.memory[address as usize]).as_str());
+            address = address.wrapping_add(1);
+        }
+
+        if checked {
+            response.push_str(format!("{:02X}", crc8(data.as_slice())).as_str());
+        }
+
+        self.respond(response.as_str());
+    }
+
+    fn run_binary_write(&mut self, args: &[u8]) {
+        let start_address = match parse_hex(args.get(..4)) {
+            None => { return self.respond("!BADARG ADDRESS"); }
+            Some(address) => address as u16,
+        };
+        let size = match args.get(4) {
+            None => { return self.respond("!BADARG SIZE"); }
+            Some(size) => *size as usize,
+        };
+        let data = match args.get(5..) {
+            Some(data) if data.len() == size => data,
+            _ => { return self.respond("!BADARG DATA"); }
+        };
+        let mut address = start_address;
+
+        for value in data.iter().copied() {
+            self.memory[address as usize] = value;
+            address = address.wrapping_add(1);
+        }
+
+        self.respond(format!("W{:04X}{:04X}", start_address, address).as_str());
+    }
+
+    fn run_binary_read(&mut self, args: &[u8]) {
+        let mut address = match parse_hex(args.get(..4)) {
+            None => { return self.respond("!BADARG ADDRESS"); }
+            Some(address) => address as u16,
+        };
+        let size = match parse_hex(args.get(4..6)) {
+            None => { return self.respond("!BADARG SIZE"); }
+            Some(size) => size.min(self.readable_size.saturating_sub(address as usize) as u32) as u8,
+        };
+        let checked = match &args[6..] {
+            b"" => false,
+            b"C" => true,
+            _ => { return self.respond("!BADARG"); }
+        };
+        let mut data = Vec::with_capacity(size as usize);
+
+        for _ in 0..size {
+            data.push(self.memory[address as usize]);
+            address = address.wrapping_add(1);
+        }
+
+        let output = self.output.get_mut();
+        output.push_back(b'r');
+        output.push_back(size);
+        output.extend(data.iter());
+        if checked {
+            output.push_back(crc8(data.as_slice()));
+        }
+        output.push_back(b'\n');
+    }
+
+    fn run_test(&mut self) {
+        // Same patterns as the firmware uses, the memory is left filled with the last one
+        for (i, mask) in [0u8, 0xFF].into_iter().enumerate() {
+            self.respond(format!("# Testing pattern {}...", i + 1).as_str());
+
+            for (address, value) in self.memory.iter_mut().enumerate() {
+                *value = mask ^ (address as u8) ^ ((address >> 8) as u8);
+            }
+        }
+
+        self.respond("TOK");
+    }
+}
+
+fn parse_hex(digits: Option<&[u8]>) -> Option<u32> {
+    u32::from_str_radix(std::str::from_utf8(digits?).ok()?, 16).ok()
+}
+
+impl Read for MockPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.interrupted_reads > 0 {
+            self.interrupted_reads -= 1;
+            return Err(std::io::Error::new(ErrorKind::Interrupted, "Interrupted system call"));
+        }
+
+        let output = self.output.get_mut();
+
+        if output.is_empty() && self.empty_reads {
+            return Ok(0);
+        }
+        if output.is_empty() {
+            return Err(std::io::Error::new(ErrorKind::TimedOut, "Operation timed out"));
+        }
+
+        if !self.read_delay.is_zero() {
+            std::thread::sleep(self.read_delay);
+        }
+
+        let size = buf.len().min(output.len());
+        for (dst, src) in buf.iter_mut().zip(output.drain(..size)) {
+            *dst = src;
+        }
+
+        Ok(size)
+    }
+}
+
+impl Write for MockPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for b in buf {
+            // Binary write command has the data size after the address, so new line characters in
+            // its data don't end the command
+            let binary_write_size = match self.input.as_slice() {
+                [b'w', _, _, _, _, size, ..] => Some(6 + *size as usize),
+                _ => None,
+            };
+
+            if *b == b'\n' && binary_write_size.is_none_or(|size| self.input.len() >= size) {
+                let line = std::mem::take(&mut self.input);
+                self.run_command(line.as_slice());
+            } else {
+                self.input.push(*b);
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.failing_flushes > 0 {
+            self.failing_flushes -= 1;
+            return Err(std::io::Error::other("Simulated flush failure"));
+        }
+
+        Ok(())
+    }
+}
+
+impl SerialPort for MockPort {
+    fn name(&self) -> Option<String> {
+        Some(MOCK_PORT_NAME.to_string())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.output.borrow().len() as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        // Data sent by the device is the input from the host's point of view
+        if matches!(buffer_to_clear, ClearBuffer::Input | ClearBuffer::All) {
+            self.output.borrow_mut().clear();
+        }
+
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Err(serialport::Error::new(serialport::ErrorKind::Unknown, "Simulated port cannot be cloned"))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(port: &mut MockPort, command: &[u8]) -> String {
+        port.output.get_mut().clear();
+        port.write_all(command).unwrap();
+        let mut response = vec![];
+        let _ = port.read_to_end(&mut response);
+        String::from_utf8_lossy(response.as_slice()).into_owned()
+    }
+
+    #[test]
+    fn binary_write_reports_missing_size() {
+        let mut port = MockPort::new();
+        assert_eq!(run(&mut port, b"w0000\n"), "!BADARG SIZE\n");
+    }
+
+    #[test]
+    fn binary_write_reports_size_mismatch() {
+        let mut port = MockPort::new();
+        assert_eq!(run(&mut port, b"w0000\x01\xAA\xBB\n"), "!BADARG DATA\n");
+    }
+
+    #[test]
+    fn binary_write_stores_data() {
+        let mut port = MockPort::new();
+        assert_eq!(run(&mut port, b"w0010\x02\xAA\x0A\n"), "W00100012\n");
+        assert_eq!(&port.memory[0x10..0x12], &[0xAA, 0x0A]);
+    }
+}
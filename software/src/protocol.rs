@@ -0,0 +1,633 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::fs::read_to_string;
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+
+/// Characters used to mark commands, responses and messages of the protocol.
+///
+/// Standard firmware uses the characters described in `protocol.md`.
+/// Forks of the firmware may use different ones.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ProtocolDialect {
+    pub read: char,
+    pub write: char,
+    pub version: char,
+    pub external_control: char,
+    pub test: char,
+    pub memory_size: char,
+    pub ping: char,
+    pub ping_response: char,
+    pub info: char,
+    pub error: char,
+    pub binary_read: char,
+    pub binary_write: char,
+    pub chip_id: char,
+    /// Character starting responses to read commands, same as `read` if not set.
+    pub read_response: Option<char>,
+    /// Character starting responses to write commands, same as `write` if not set.
+    pub write_response: Option<char>,
+    /// Case of hexadecimal digits in read and write commands.
+    pub hex_case: HexCase,
+}
+
+impl Default for ProtocolDialect {
+    fn default() -> Self {
+        Self {
+            read: 'R',
+            write: 'W',
+            version: 'V',
+            external_control: 'E',
+            test: 'T',
+            memory_size: 'S',
+            ping: 'P',
+            ping_response: 'p',
+            info: '#',
+            error: '!',
+            binary_read: 'r',
+            binary_write: 'w',
+            chip_id: 'I',
+            read_response: None,
+            write_response: None,
+            hex_case: HexCase::Upper,
+        }
+    }
+}
+
+impl ProtocolDialect {
+    /// Parses dialect description consisting of `key = character` lines.
+    ///
+    /// Keys not mentioned in the description keep their standard values.
+    /// Empty lines and lines starting with `;` are ignored.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut dialect = Self::default();
+
+        for (line_index, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let (key, value) = line.split_once('=')
+                .ok_or_else(|| anyhow!("Line {}: expected 'key = character'", line_index + 1))?;
+            let mut chars = value.trim().chars();
+            let value = match (chars.next(), chars.next()) {
+                (Some(c), None) if c.is_ascii_graphic() => c,
+                _ => {
+                    return Err(anyhow!("Line {}: value must be a single printable ASCII character", line_index + 1));
+                }
+            };
+
+            let field = match key.trim() {
+                "read_response" => {
+                    dialect.read_response = Some(value);
+                    continue;
+                }
+                "write_response" => {
+                    dialect.write_response = Some(value);
+                    continue;
+                }
+                "read" => &mut dialect.read,
+                "write" => &mut dialect.write,
+                "version" => &mut dialect.version,
+                "external_control" => &mut dialect.external_control,
+                "test" => &mut dialect.test,
+                "memory_size" => &mut dialect.memory_size,
+                "ping" => &mut dialect.ping,
+                "ping_response" => &mut dialect.ping_response,
+                "info" => &mut dialect.info,
+                "error" => &mut dialect.error,
+                "binary_read" => &mut dialect.binary_read,
+                "binary_write" => &mut dialect.binary_write,
+                "chip_id" => &mut dialect.chip_id,
+                other => {
+                    return Err(anyhow!("Line {}: unknown key '{}'", line_index + 1, other));
+                }
+            };
+
+            *field = value;
+        }
+
+        Ok(dialect)
+    }
+
+    /// Returns character starting responses to read commands.
+    pub fn read_response(&self) -> char {
+        self.read_response.unwrap_or(self.read)
+    }
+
+    /// Returns character starting responses to write commands.
+    pub fn write_response(&self) -> char {
+        self.write_response.unwrap_or(self.write)
+    }
+
+    /// Loads dialect description from a file.
+    pub fn load(path: &str) -> Result<Self> {
+        // Errors are formatted into a single message, as clap shows only the outermost one
+        let text = read_to_string(path)
+            .map_err(|e| anyhow!("Error reading protocol dialect file {}: {}", path, e))?;
+
+        Self::parse(text.as_str())
+            .map_err(|e| anyhow!("Error parsing protocol dialect file {}: {:#}", path, e))
+    }
+}
+
+/// Case of hexadecimal digits in commands sent to the device and in output files.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum HexCase {
+    /// A-F, accepted by the standard firmware
+    #[default]
+    Upper,
+    /// a-f, for firmware variants with case-sensitive parsers
+    Lower,
+}
+
+impl HexCase {
+    /// Returns `value` formatted as `width` zero-padded hexadecimal digits of this case.
+    pub fn digits(self, value: impl Into<usize>, width: usize) -> HexDigits {
+        HexDigits { value: value.into(), width, case: self }
+    }
+}
+
+/// Number formatted by [HexCase::digits].
+pub struct HexDigits {
+    value: usize,
+    width: usize,
+    case: HexCase,
+}
+
+impl Display for HexDigits {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.case {
+            HexCase::Upper => write!(f, "{:0width$X}", self.value, width = self.width),
+            HexCase::Lower => write!(f, "{:0width$x}", self.value, width = self.width),
+        }
+    }
+}
+
+/// Encoding of data sent to and received from the device by read and write commands.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TransferMode {
+    /// Hexadecimal digits, supported by all firmware versions
+    #[default]
+    Hex,
+    /// Raw bytes, twice as fast; used only if the firmware supports it
+    Binary,
+}
+
+/// Number format used by firmware to report memory size.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MemorySizeFormat {
+    /// Hexadecimal number, like other numbers in the protocol
+    Hex,
+    /// Decimal number
+    Decimal,
+}
+
+/// Size of memory addressable by read and write commands, whose addresses are 4 hexadecimal digits.
+pub const ADDRESS_SPACE_SIZE: usize = 0x10000;
+
+/// Parses memory size reported by device in response to memory size query.
+///
+/// The number is interpreted according to `format` unless it has a `0x` prefix, which always means
+/// a hexadecimal number.
+/// A `K` suffix means that the size is given in KiB.
+/// Sizes above 64KiB are rejected, as read and write commands can't address more memory.
+pub fn parse_memory_size(text: &str, format: MemorySizeFormat) -> Result<usize> {
+    let text = text.trim();
+    let (text, multiplier) = match text.strip_suffix(['K', 'k']) {
+        Some(stripped) => (stripped.trim_end(), 1024),
+        None => (text, 1),
+    };
+    let (digits, radix) = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex_digits) => (hex_digits, 16),
+        None => match format {
+            MemorySizeFormat::Hex => (text, 16),
+            MemorySizeFormat::Decimal => (text, 10),
+        },
+    };
+
+    let size = usize::from_str_radix(digits, radix)
+        .with_context(|| format!("Invalid memory size: '{}'", text))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("Memory size is too large: '{}'", text))?;
+
+    if size == 0 {
+        return Err(anyhow!("Memory size is zero"));
+    }
+    if size > ADDRESS_SPACE_SIZE {
+        return Err(anyhow!("Memory size 0x{:X} exceeds 16-bit address space of read and write commands", size));
+    }
+
+    Ok(size)
+}
+
+/// Decodes pairs of hexadecimal digits into bytes appended to `data`.
+///
+/// Invalid characters are reported with their position in `digits`, so a single corrupted
+/// character can be located.
+pub fn decode_hex_into(digits: &[u8], data: &mut Vec<u8>) -> Result<()> {
+    if let Some(position) = digits.iter().position(|c| !c.is_ascii_hexdigit()) {
+        let c = digits[position];
+
+        return Err(anyhow!(
+            "Invalid hexadecimal digit {:?} (0x{:02X}) at position {} (byte {})",
+            c as char,
+            c,
+            position,
+            position / 2,
+        ));
+    }
+
+    if !digits.len().is_multiple_of(2) {
+        return Err(anyhow!("Odd number of hexadecimal digits"));
+    }
+
+    // All characters are valid digits, so the conversions can't fail
+    data.extend(digits.chunks(2).map(|pair| (hex_digit_value(pair[0]) << 4) | hex_digit_value(pair[1])));
+
+    Ok(())
+}
+
+fn hex_digit_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
+
+/// Returns hexadecimal digits following the read response character.
+pub fn read_response_payload<'a>(response: &'a [u8], dialect: &ProtocolDialect) -> Result<&'a [u8]> {
+    match response.split_first() {
+        Some((c, payload)) if *c == dialect.read_response() as u8 => Ok(payload),
+        _ => Err(anyhow!(
+            "Received unexpected response to '{}' command: '{}'",
+            dialect.read,
+            String::from_utf8_lossy(response),
+        )),
+    }
+}
+
+/// Response to a read command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReadResponse {
+    pub data: Vec<u8>,
+}
+
+impl ReadResponse {
+    pub fn parse(response: &[u8], dialect: &ProtocolDialect) -> Result<Self> {
+        let mut data = vec![];
+        decode_hex_into(read_response_payload(response, dialect)?, &mut data)
+            .context("Error parsing response payload")?;
+
+        Ok(Self { data })
+    }
+
+    pub fn encode(&self, dialect: &ProtocolDialect) -> String {
+        let mut response = String::with_capacity(1 + 2 * self.data.len());
+        response.push(dialect.read_response());
+
+        for b in self.data.iter() {
+            response.push_str(format!("{:02X}", b).as_str());
+        }
+
+        response
+    }
+}
+
+/// Response to a write command: address of the first written byte and address following the last one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WriteResponse {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl WriteResponse {
+    /// Parses a write response.
+    ///
+    /// Hexadecimal digits of any case are accepted, whitespace around and between the addresses is
+    /// ignored.
+    pub fn parse(response: &[u8], dialect: &ProtocolDialect) -> Result<Self> {
+        let invalid = || anyhow!("Invalid write command response: '{}'", String::from_utf8_lossy(response));
+        let (command, rest) = response.split_first().ok_or_else(invalid)?;
+
+        if *command != dialect.write_response() as u8 {
+            return Err(invalid());
+        }
+
+        let digits: Vec<u8> = rest.iter().copied().filter(|c| !c.is_ascii_whitespace()).collect();
+
+        if digits.len() != 8 {
+            return Err(invalid());
+        }
+
+        let parse = |digits: &[u8]| {
+            std::str::from_utf8(digits).ok()
+                .and_then(|digits| u16::from_str_radix(digits, 16).ok())
+                .ok_or_else(invalid)
+        };
+
+        Ok(Self { start: parse(&digits[..4])?, end: parse(&digits[4..])? })
+    }
+
+    pub fn encode(&self, dialect: &ProtocolDialect) -> String {
+        format!("{}{:04X}{:04X}", dialect.write_response(), self.start, self.end)
+    }
+}
+
+/// Response to a version request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionResponse {
+    /// Version banner, e.g. `ROME-0.1.0 size`.
+    pub version: String,
+}
+
+impl VersionResponse {
+    pub fn parse(response: &[u8], dialect: &ProtocolDialect) -> Result<Self> {
+        match response.split_first() {
+            Some((c, version)) if *c == dialect.version as u8 => Ok(Self {
+                version: String::from_utf8_lossy(version).to_string(),
+            }),
+            _ => Err(anyhow!("Received unexpected response: '{}'", String::from_utf8_lossy(response))),
+        }
+    }
+
+    pub fn encode(&self, dialect: &ProtocolDialect) -> String {
+        format!("{}{}", dialect.version, self.version)
+    }
+}
+
+/// Response to a test command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TestResponse {
+    Passed,
+    /// Test failed; newer firmware appends diagnostic detail, e.g. `TFAIL:ADDR=1234`.
+    Failed { detail: Option<String> },
+}
+
+impl TestResponse {
+    pub fn parse(response: &[u8], dialect: &ProtocolDialect) -> Result<Self> {
+        let status = match response.split_first() {
+            Some((c, status)) if *c == dialect.test as u8 => status,
+            _ => { return Err(anyhow!("Received unexpected response: '{}'", String::from_utf8_lossy(response))); }
+        };
+
+        match status {
+            b"OK" => Ok(Self::Passed),
+            b"FAIL" => Ok(Self::Failed { detail: None }),
+            _ => match status.strip_prefix(b"FAIL:") {
+                Some(detail) => {
+                    let detail = String::from_utf8_lossy(detail);
+                    let detail = detail.trim();
+
+                    Ok(Self::Failed { detail: (!detail.is_empty()).then(|| detail.to_string()) })
+                }
+                None => Err(anyhow!("Received unexpected response: '{}'", String::from_utf8_lossy(response))),
+            },
+        }
+    }
+
+    pub fn encode(&self, dialect: &ProtocolDialect) -> String {
+        match self {
+            Self::Passed => format!("{}OK", dialect.test),
+            Self::Failed { detail: None } => format!("{}FAIL", dialect.test),
+            Self::Failed { detail: Some(detail) } => format!("{}FAIL:{}", dialect.test, detail),
+        }
+    }
+}
+
+/// Response to a chip ID request: manufacturer code followed by device code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChipIdResponse {
+    pub id: Vec<u8>,
+}
+
+impl ChipIdResponse {
+    pub fn parse(response: &[u8], dialect: &ProtocolDialect) -> Result<Self> {
+        let digits = match response.split_first() {
+            Some((c, digits)) if *c == dialect.chip_id as u8 => digits,
+            _ => { return Err(anyhow!("Received unexpected response: '{}'", String::from_utf8_lossy(response))); }
+        };
+        let mut id = vec![];
+        decode_hex_into(digits, &mut id).context("Error parsing chip ID")?;
+
+        if id.is_empty() {
+            return Err(anyhow!("Received empty chip ID"));
+        }
+
+        Ok(Self { id })
+    }
+
+    pub fn encode(&self, dialect: &ProtocolDialect) -> String {
+        let mut response = String::with_capacity(1 + 2 * self.id.len());
+        response.push(dialect.chip_id);
+
+        for b in self.id.iter() {
+            response.push_str(format!("{:02X}", b).as_str());
+        }
+
+        response
+    }
+}
+
+// Responses in the standard dialect can be parsed from strings directly.
+
+impl FromStr for ReadResponse {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s.as_bytes(), &ProtocolDialect::default())
+    }
+}
+
+impl FromStr for WriteResponse {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s.as_bytes(), &ProtocolDialect::default())
+    }
+}
+
+impl FromStr for VersionResponse {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s.as_bytes(), &ProtocolDialect::default())
+    }
+}
+
+impl FromStr for TestResponse {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s.as_bytes(), &ProtocolDialect::default())
+    }
+}
+
+impl FromStr for ChipIdResponse {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s.as_bytes(), &ProtocolDialect::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn custom_dialect() -> ProtocolDialect {
+        ProtocolDialect::parse("read = a\nread_response = b\nwrite = c\nversion = d\ntest = e\nchip_id = f\n").unwrap()
+    }
+
+    #[test]
+    fn read_response_round_trip() {
+        for dialect in [ProtocolDialect::default(), custom_dialect()] {
+            let response = ReadResponse { data: vec![0x00, 0x7F, 0xA5, 0xFF] };
+            let encoded = response.encode(&dialect);
+
+            assert_eq!(ReadResponse::parse(encoded.as_bytes(), &dialect).unwrap(), response);
+        }
+
+        assert_eq!(ReadResponse { data: vec![0x12, 0xAB] }.encode(&custom_dialect()), "b12AB");
+        assert_eq!("R".parse::<ReadResponse>().unwrap(), ReadResponse { data: vec![] });
+    }
+
+    #[test]
+    fn write_response_round_trip() {
+        for dialect in [ProtocolDialect::default(), custom_dialect()] {
+            let response = WriteResponse { start: 0xFFF0, end: 0x0000 };
+            let encoded = response.encode(&dialect);
+
+            assert_eq!(WriteResponse::parse(encoded.as_bytes(), &dialect).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn version_response_round_trip() {
+        for dialect in [ProtocolDialect::default(), custom_dialect()] {
+            let response = VersionResponse { version: "ROME-0.1.0 size crc".to_string() };
+            let encoded = response.encode(&dialect);
+
+            assert_eq!(VersionResponse::parse(encoded.as_bytes(), &dialect).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let responses = [
+            TestResponse::Passed,
+            TestResponse::Failed { detail: None },
+            TestResponse::Failed { detail: Some("ADDR=1234".to_string()) },
+        ];
+
+        for dialect in [ProtocolDialect::default(), custom_dialect()] {
+            for response in responses.iter() {
+                let encoded = response.encode(&dialect);
+
+                assert_eq!(&TestResponse::parse(encoded.as_bytes(), &dialect).unwrap(), response);
+            }
+        }
+    }
+
+    #[test]
+    fn chip_id_response_round_trip() {
+        for dialect in [ProtocolDialect::default(), custom_dialect()] {
+            let response = ChipIdResponse { id: vec![0xBF, 0xB5] };
+            let encoded = response.encode(&dialect);
+
+            assert_eq!(ChipIdResponse::parse(encoded.as_bytes(), &dialect).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn hex_decoding() {
+        let mut data = vec![0x01];
+        decode_hex_into(b"00a5FF7f", &mut data).unwrap();
+
+        assert_eq!(data, vec![0x01, 0x00, 0xA5, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn bad_nibble_position_is_reported() {
+        let mut data = vec![];
+        let err = decode_hex_into(b"00A5F?7F", &mut data).unwrap_err();
+
+        assert_eq!(err.to_string(), "Invalid hexadecimal digit '?' (0x3F) at position 5 (byte 2)");
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn odd_number_of_digits_is_rejected() {
+        assert!(decode_hex_into(b"00A", &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn bare_test_failure() {
+        assert_eq!("TFAIL".parse::<TestResponse>().unwrap(), TestResponse::Failed { detail: None });
+        assert_eq!("TFAIL:".parse::<TestResponse>().unwrap(), TestResponse::Failed { detail: None });
+        assert_eq!("TOK".parse::<TestResponse>().unwrap(), TestResponse::Passed);
+    }
+
+    #[test]
+    fn detailed_test_failure() {
+        assert_eq!(
+            "TFAIL:ADDR=1234".parse::<TestResponse>().unwrap(),
+            TestResponse::Failed { detail: Some("ADDR=1234".to_string()) },
+        );
+        assert_eq!(
+            "TFAIL: ADDR=1234 ".parse::<TestResponse>().unwrap(),
+            TestResponse::Failed { detail: Some("ADDR=1234".to_string()) },
+        );
+    }
+
+    #[test]
+    fn test_statuses_starting_with_fail_are_rejected() {
+        assert!("TFAILED".parse::<TestResponse>().is_err());
+        assert!("TFAILURE".parse::<TestResponse>().is_err());
+        assert!("TOKAY".parse::<TestResponse>().is_err());
+    }
+
+    #[test]
+    fn write_response_with_lowercase_digits() {
+        assert_eq!("W00ff01a4".parse::<WriteResponse>().unwrap(), WriteResponse { start: 0x00FF, end: 0x01A4 });
+        assert_eq!("W00Ff01A4".parse::<WriteResponse>().unwrap(), WriteResponse { start: 0x00FF, end: 0x01A4 });
+    }
+
+    #[test]
+    fn write_response_with_spaces() {
+        assert_eq!("W 00FF 01A4".parse::<WriteResponse>().unwrap(), WriteResponse { start: 0x00FF, end: 0x01A4 });
+        assert_eq!("W00FF  01A4 \r".parse::<WriteResponse>().unwrap(), WriteResponse { start: 0x00FF, end: 0x01A4 });
+    }
+
+    #[test]
+    fn invalid_write_responses() {
+        assert!("W00FF01A".parse::<WriteResponse>().is_err());
+        assert!("W00FG01A4".parse::<WriteResponse>().is_err());
+        assert!("R00FF01A4".parse::<WriteResponse>().is_err());
+        assert!("".parse::<WriteResponse>().is_err());
+    }
+
+    #[test]
+    fn memory_size_forms() {
+        assert_eq!(parse_memory_size("8000", MemorySizeFormat::Hex).unwrap(), 0x8000);
+        assert_eq!(parse_memory_size("32768", MemorySizeFormat::Decimal).unwrap(), 0x8000);
+        assert_eq!(parse_memory_size("0x8000", MemorySizeFormat::Decimal).unwrap(), 0x8000);
+        assert_eq!(parse_memory_size("0X8000", MemorySizeFormat::Hex).unwrap(), 0x8000);
+        assert_eq!(parse_memory_size("32K", MemorySizeFormat::Decimal).unwrap(), 0x8000);
+        assert_eq!(parse_memory_size("20k", MemorySizeFormat::Hex).unwrap(), 0x8000);
+        assert_eq!(parse_memory_size(" 64 K ", MemorySizeFormat::Decimal).unwrap(), 0x10000);
+    }
+
+    #[test]
+    fn invalid_memory_sizes() {
+        assert!(parse_memory_size("", MemorySizeFormat::Hex).is_err());
+        assert!(parse_memory_size("0", MemorySizeFormat::Hex).is_err());
+        assert!(parse_memory_size("12AB", MemorySizeFormat::Decimal).is_err());
+        assert!(parse_memory_size("10001", MemorySizeFormat::Hex).is_err());
+        assert!(parse_memory_size("128K", MemorySizeFormat::Decimal).is_err());
+        assert!(parse_memory_size("FFFFFFFFFFFFFFFFK", MemorySizeFormat::Hex).is_err());
+    }
+}
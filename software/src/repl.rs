@@ -0,0 +1,253 @@
+use std::io::{BufRead, Write};
+use std::num::{NonZeroU8, NonZeroUsize};
+use anyhow::{anyhow, Result};
+use crate::args::parse_number;
+use crate::data_ops::{check_write_bounds, read_buffer_size_for_frame, read_data_into, write_buffer_size_for_frame, write_data, DataChunk, DataReadRequest, DataWriteRequest};
+use crate::device::Device;
+use crate::formats::HexDumpWriter;
+use crate::protocol::HexCase;
+use crate::signals;
+
+const PROMPT: &str = "rome> ";
+
+/// Maximal length of a response to a raw command, enough for a read of 255 bytes.
+const RAW_RESPONSE_LIMIT: usize = 2 + 2 * 255 + 2;
+
+const HELP: &str = "\
+Commands:
+  read <offset> <size>      print hexdump of device memory region
+  write <offset> <hex data> write bytes (e.g. `write 0x100 DEADBEEF` or `write 0x100 DE AD`)
+  version                   print firmware version
+  size                      print device memory size
+  raw <command>             send command as is and print the first line received back
+  help                      print this message
+  quit                      exit
+Numbers are decimal or hexadecimal with 0x prefix. Empty lines and lines starting with # are ignored.";
+
+/// A command accepted by [run_repl].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplCommand {
+    Read { offset: u16, size: NonZeroUsize },
+    Write { offset: u16, data: Vec<u8> },
+    Version,
+    Size,
+    Raw(String),
+    Help,
+    Quit,
+}
+
+fn parse_offset(value: Option<&str>) -> Result<u16> {
+    let value = value.ok_or_else(|| anyhow!("Missing offset"))?;
+
+    u16::try_from(parse_number(value).map_err(|e| anyhow!(e))?).map_err(|_| anyhow!("Offset is out of address space: {}", value))
+}
+
+fn parse_hex_data<'a>(words: impl Iterator<Item=&'a str>) -> Result<Vec<u8>> {
+    let digits: String = words.collect();
+
+    if digits.is_empty() {
+        return Err(anyhow!("Missing data"));
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err(anyhow!("Odd number of hexadecimal digits in data"));
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            digits.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| anyhow!("Invalid hexadecimal data: {}", digits))
+        })
+        .collect()
+}
+
+impl ReplCommand {
+    /// Parses a command line.
+    ///
+    /// Returns `None` for empty lines and comments.
+    pub fn parse(line: &str) -> Result<Option<Self>> {
+        let mut words = line.split_whitespace();
+
+        let command = match words.next() {
+            None => { return Ok(None); }
+            Some(word) if word.starts_with('#') => { return Ok(None); }
+            Some(word) => word,
+        };
+
+        let command = match command.to_lowercase().as_str() {
+            "read" => {
+                let offset = parse_offset(words.next())?;
+                let size = words.next().ok_or_else(|| anyhow!("Missing size"))?;
+                let size = NonZeroUsize::new(parse_number(size).map_err(|e| anyhow!(e))?)
+                    .ok_or_else(|| anyhow!("Size must not be zero"))?;
+
+                ReplCommand::Read { offset, size }
+            }
+            "write" => {
+                let offset = parse_offset(words.next())?;
+
+                return Ok(Some(ReplCommand::Write { offset, data: parse_hex_data(words)? }));
+            }
+            "raw" => {
+                let (_, command) = line.trim().split_once(char::is_whitespace)
+                    .ok_or_else(|| anyhow!("Missing command"))?;
+
+                return Ok(Some(ReplCommand::Raw(command.trim_start().to_string())));
+            }
+            "version" => ReplCommand::Version,
+            "size" => ReplCommand::Size,
+            "help" => ReplCommand::Help,
+            "quit" | "exit" => ReplCommand::Quit,
+            other => { return Err(anyhow!("Unknown command: {}. Type `help` for list of commands", other)); }
+        };
+
+        if let Some(extra) = words.next() {
+            return Err(anyhow!("Unexpected argument: {}", extra));
+        }
+
+        Ok(Some(command))
+    }
+
+    /// Executes the command printing its results to `out`, with hexadecimal digits in given case.
+    ///
+    /// Returns `false` if no more commands should be executed.
+    pub fn run(&self, device: &mut Device, out: &mut impl Write, hex_case: HexCase) -> Result<bool> {
+        match self {
+            ReplCommand::Read { offset, size } => {
+                let buffer_size = NonZeroU8::new(read_buffer_size_for_frame(device.serial_frame_size()))
+                    .ok_or_else(|| anyhow!("Serial frame size is too small"))?;
+                let mut dump = HexDumpWriter::new(&mut *out, *offset as usize, hex_case);
+
+                read_data_into(device, DataReadRequest {
+                    offset: *offset,
+                    size: *size,
+                    buffer_size,
+                    allow_short_read: false,
+                    reverse: false,
+                }, |_, _| {}, |chunk| {
+                    dump.write_all(chunk.data)?;
+                    Ok(())
+                })?;
+
+                dump.flush()?;
+            }
+            ReplCommand::Write { offset, data } => {
+                let buffer_size = NonZeroU8::new(write_buffer_size_for_frame(device.serial_frame_size()))
+                    .ok_or_else(|| anyhow!("Serial frame size is too small"))?;
+
+                check_write_bounds(device, *offset, data.len())?;
+                write_data(device, DataWriteRequest {
+                    data: &DataChunk { offset: *offset, data: data.as_slice() },
+                    buffer_size,
+                    page_size: None,
+                })?;
+            }
+            ReplCommand::Version => writeln!(out, "{}", device.version()?)?,
+            ReplCommand::Size => writeln!(out, "{}", device.memory_size()?)?,
+            ReplCommand::Raw(command) => {
+                device.send(format!("{}\n", command).as_bytes())?;
+                writeln!(out, "{}", String::from_utf8_lossy(device.receive_lenient(RAW_RESPONSE_LIMIT)?.as_slice()))?;
+            }
+            ReplCommand::Help => writeln!(out, "{}", HELP)?,
+            ReplCommand::Quit => { return Ok(false); }
+        }
+
+        out.flush()?;
+
+        Ok(true)
+    }
+}
+
+/// Executes commands read line by line from `input`.
+///
+/// In interactive mode a prompt is printed to standard error before each command and failed
+/// commands are reported without stopping. Ctrl+C interrupts the current command (after the device
+/// operation in progress completes or times out) and returns to the prompt; the device is
+/// re-synchronized, so a partially received response is not mistaken for a response to the next
+/// command. Ctrl+C while a command is being typed discards it.
+/// Otherwise, the first failed command stops execution and the returned error refers to its line
+/// number.
+pub fn run_repl(device: &mut Device, input: impl BufRead, out: &mut impl Write, interactive: bool, hex_case: HexCase) -> Result<()> {
+    let mut lines = input.lines().enumerate();
+
+    loop {
+        if interactive {
+            eprint!("{}", PROMPT);
+        }
+
+        let (index, line) = match lines.next() {
+            None => {
+                if interactive {
+                    // Move to a new line after the prompt when input ends with Ctrl+D
+                    eprintln!();
+                }
+                break;
+            }
+            Some((index, line)) => (index, line?),
+        };
+
+        if interactive && signals::take_interrupt() {
+            eprintln!("Interrupted, command discarded");
+            continue;
+        }
+
+        let result = ReplCommand::parse(line.as_str())
+            .and_then(|command| match command {
+                None => Ok(true),
+                Some(command) => command.run(device, out, hex_case),
+            });
+
+        match result {
+            Ok(true) => {
+                // Interrupt that came after the last device operation has nothing left to interrupt
+                if interactive {
+                    signals::take_interrupt();
+                }
+            }
+            Ok(false) => { break; }
+            Err(e) if interactive && signals::take_interrupt() => {
+                eprintln!("Interrupted: {:#}", e);
+
+                if let Err(e) = device.check() {
+                    eprintln!("Error re-synchronizing with device: {:#}", e);
+                }
+            }
+            Err(e) if interactive && signals::received_signal().is_none() => {
+                eprintln!("Error: {:#}", e);
+            }
+            Err(e) => {
+                return Err(e.context(format!("Line {}: {}", index + 1, line.trim())));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DeviceSettings;
+    use crate::mock::open_mock_device;
+
+    #[test]
+    fn numbers_are_decimal_or_hexadecimal() {
+        let read = ReplCommand::parse("read 0x10 32").unwrap();
+
+        assert_eq!(read, Some(ReplCommand::Read { offset: 0x10, size: NonZeroUsize::new(32).unwrap() }));
+        assert!(ReplCommand::parse("read 0x10000 1").is_err());
+        assert!(ReplCommand::parse("read 0x1G 1").is_err());
+    }
+
+    #[test]
+    fn read_prints_digits_in_given_case() {
+        let mut device = open_mock_device(&DeviceSettings::default()).unwrap();
+        let mut out = vec![];
+
+        run_repl(&mut device, "write 0x10 ABCD\nread 0x10 2\n".as_bytes(), &mut out, false, HexCase::Lower).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("0010  ab cd"), "{}", out);
+    }
+}
@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+
+/// Longest period [sleep_unless_interrupted] sleeps without checking for received signals.
+const SLEEP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+static RECEIVED_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Number of the signal sent by Ctrl+C.
+#[cfg(unix)]
+const SIGINT: i32 = libc::SIGINT;
+#[cfg(not(unix))]
+const SIGINT: i32 = 2;
+
+#[cfg(unix)]
+extern "C" fn handle_signal(signal: libc::c_int) {
+    if RECEIVED_SIGNAL.swap(signal, Ordering::SeqCst) != 0 {
+        // Second signal - the user doesn't want to wait for graceful shutdown
+        unsafe { libc::_exit(128 + signal) };
+    }
+}
+
+/// Installs SIGINT and SIGTERM handlers that request graceful shutdown instead of killing the process.
+///
+/// The handlers only set a flag, which is checked by [crate::device::Device] between commands, so a
+/// command being executed is never interrupted halfway.
+/// Second signal terminates the process immediately.
+#[cfg(unix)]
+pub fn install_handlers() -> Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let previous = unsafe { libc::signal(signal, handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t) };
+
+        if previous == libc::SIG_ERR {
+            return Err(anyhow!("Error installing handler for signal {}", signal));
+        }
+    }
+
+    Ok(())
+}
+
+/// Signals are not handled on other platforms, so Ctrl+C terminates the process immediately.
+#[cfg(not(unix))]
+pub fn install_handlers() -> Result<()> {
+    Ok(())
+}
+
+/// Returns number of termination signal received by the process, if any.
+pub fn received_signal() -> Option<i32> {
+    match RECEIVED_SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// Forgets received SIGINT, so an interactive program can continue after an interrupted operation.
+///
+/// Returns `true` if SIGINT was received. Other signals are kept.
+pub fn take_interrupt() -> bool {
+    RECEIVED_SIGNAL.compare_exchange(SIGINT, 0, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+}
+
+/// Returns an error if the process was asked to terminate.
+pub fn check_interrupted() -> Result<()> {
+    match received_signal() {
+        None => Ok(()),
+        Some(signal) => Err(anyhow!("Interrupted by signal {}", signal)),
+    }
+}
+
+/// Sleeps for given duration, returning early if the process is asked to terminate.
+///
+/// Returns `true` if the sleep was interrupted.
+pub fn sleep_unless_interrupted(duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+
+    while received_signal().is_none() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        if remaining.is_zero() {
+            return false;
+        }
+
+        sleep(remaining.min(SLEEP_CHECK_INTERVAL));
+    }
+
+    true
+}